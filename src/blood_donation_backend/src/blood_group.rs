@@ -0,0 +1,274 @@
+use crate::Error;
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+
+// ABO/Rh blood groups, `Unknown` is used for patients whose group has not been typed yet
+#[derive(
+    candid::CandidType,
+    Clone,
+    Copy,
+    Serialize,
+    Deserialize,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Debug,
+    Default,
+)]
+pub(crate) enum BloodGroup {
+    APositive,
+    ANegative,
+    BPositive,
+    BNegative,
+    ABPositive,
+    ABNegative,
+    OPositive,
+    ONegative,
+    #[default]
+    Unknown,
+}
+
+pub(crate) const TYPED_GROUPS: [BloodGroup; 8] = [
+    BloodGroup::APositive,
+    BloodGroup::ANegative,
+    BloodGroup::BPositive,
+    BloodGroup::BNegative,
+    BloodGroup::ABPositive,
+    BloodGroup::ABNegative,
+    BloodGroup::OPositive,
+    BloodGroup::ONegative,
+];
+
+// Stored as its position in `TYPED_GROUPS`, `Unknown` after them
+impl Storable for BloodGroup {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let code = TYPED_GROUPS
+            .iter()
+            .position(|group| group == self)
+            .unwrap_or(TYPED_GROUPS.len());
+        Cow::Owned(vec![code as u8])
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        TYPED_GROUPS
+            .get(usize::from(bytes[0]))
+            .copied()
+            .unwrap_or(BloodGroup::Unknown)
+    }
+}
+
+impl BoundedStorable for BloodGroup {
+    const MAX_SIZE: u32 = 1;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+impl BloodGroup {
+    // ABO antigens on the red cells as (A, B) and whether the RhD antigen is present
+    fn antigens(self) -> Option<(bool, bool, bool)> {
+        match self {
+            BloodGroup::APositive => Some((true, false, true)),
+            BloodGroup::ANegative => Some((true, false, false)),
+            BloodGroup::BPositive => Some((false, true, true)),
+            BloodGroup::BNegative => Some((false, true, false)),
+            BloodGroup::ABPositive => Some((true, true, true)),
+            BloodGroup::ABNegative => Some((true, true, false)),
+            BloodGroup::OPositive => Some((false, false, true)),
+            BloodGroup::ONegative => Some((false, false, false)),
+            BloodGroup::Unknown => None,
+        }
+    }
+
+    // Red cells from `self` can be given to `recipient` when they carry no antigen the
+    // recipient lacks. A recipient of unknown group can only receive O negative.
+    pub(crate) fn can_donate_to(self, recipient: BloodGroup) -> bool {
+        match (self.antigens(), recipient.antigens()) {
+            (Some((a, b, rh)), Some((ra, rb, rrh))) => (!a || ra) && (!b || rb) && (!rh || rrh),
+            (Some(_), None) => self == BloodGroup::ONegative,
+            (None, _) => false,
+        }
+    }
+
+    // Every ABO antigen of `self` is also carried by `other`, Rh does not matter. Unknown groups
+    // are only covered by AB, which carries both antigens.
+    pub(crate) fn abo_subset_of(self, other: BloodGroup) -> bool {
+        match (self.antigens(), other.antigens()) {
+            (Some((a, b, _)), Some((oa, ob, _))) => (!a || oa) && (!b || ob),
+            (None, Some((oa, ob, _))) => oa && ob,
+            (_, None) => false,
+        }
+    }
+
+    // Parse the free text stored before blood groups were typed, e.g. "O+", "o pos" or
+    // "AB negative". Anything that cannot be read as a group becomes `Unknown`.
+    pub(crate) fn from_legacy(text: &str) -> BloodGroup {
+        let normalized: String = text
+            .to_lowercase()
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect();
+        let (abo, rh) = match normalized.find(|c: char| !matches!(c, 'a' | 'b' | 'o' | '0')) {
+            Some(split) => normalized.split_at(split),
+            None => return BloodGroup::Unknown,
+        };
+        let positive = match rh {
+            "+" | "+ve" | "pos" | "positive" | "rh+" | "rhpositive" => true,
+            "-" | "-ve" | "neg" | "negative" | "rh-" | "rhnegative" => false,
+            _ => return BloodGroup::Unknown,
+        };
+        match (abo, positive) {
+            ("a", true) => BloodGroup::APositive,
+            ("a", false) => BloodGroup::ANegative,
+            ("b", true) => BloodGroup::BPositive,
+            ("b", false) => BloodGroup::BNegative,
+            ("ab", true) => BloodGroup::ABPositive,
+            ("ab", false) => BloodGroup::ABNegative,
+            ("o" | "0", true) => BloodGroup::OPositive,
+            ("o" | "0", false) => BloodGroup::ONegative,
+            _ => BloodGroup::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for BloodGroup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            BloodGroup::APositive => "A+",
+            BloodGroup::ANegative => "A-",
+            BloodGroup::BPositive => "B+",
+            BloodGroup::BNegative => "B-",
+            BloodGroup::ABPositive => "AB+",
+            BloodGroup::ABNegative => "AB-",
+            BloodGroup::OPositive => "O+",
+            BloodGroup::ONegative => "O-",
+            BloodGroup::Unknown => "unknown",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+// Strict parsing of payload blood groups: only "A+", "A-", "B+", "B-", "AB+", "AB-", "O+",
+// "O-" (in any case) and "unknown" are accepted
+impl FromStr for BloodGroup {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let trimmed = text.trim();
+        TYPED_GROUPS
+            .iter()
+            .chain([BloodGroup::Unknown].iter())
+            .find(|group| group.to_string().eq_ignore_ascii_case(trimmed))
+            .copied()
+            .ok_or(Error::InvalidPayload {
+                msg: format!(
+                    "invalid blood group: {}, expected one of A+, A-, B+, B-, AB+, AB-, O+, O- or unknown",
+                    text
+                ),
+            })
+    }
+}
+
+// blood groups whose red cells a recipient can receive
+#[ic_cdk::query]
+fn compatible_donor_groups(recipient: BloodGroup) -> Vec<BloodGroup> {
+    TYPED_GROUPS
+        .into_iter()
+        .filter(|donor| donor.can_donate_to(recipient))
+        .collect()
+}
+
+// blood groups that can receive red cells from a donor
+#[ic_cdk::query]
+fn compatible_recipient_groups(donor: BloodGroup) -> Vec<BloodGroup> {
+    TYPED_GROUPS
+        .into_iter()
+        .filter(|recipient| donor.can_donate_to(*recipient))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ids::DonorId;
+    use crate::Donor;
+    use candid::Encode;
+    use ic_stable_structures::Storable;
+    use std::borrow::Cow;
+
+    #[test]
+    fn parses_only_canonical_groups() {
+        assert_eq!("O+".parse::<BloodGroup>().unwrap(), BloodGroup::OPositive);
+        assert_eq!(
+            " ab- ".parse::<BloodGroup>().unwrap(),
+            BloodGroup::ABNegative
+        );
+        assert_eq!(
+            "Unknown".parse::<BloodGroup>().unwrap(),
+            BloodGroup::Unknown
+        );
+        assert!("o pos".parse::<BloodGroup>().is_err());
+        assert!("banana".parse::<BloodGroup>().is_err());
+    }
+
+    #[test]
+    fn migrates_legacy_text() {
+        assert_eq!(BloodGroup::from_legacy("o pos"), BloodGroup::OPositive);
+        assert_eq!(
+            BloodGroup::from_legacy("AB Negative"),
+            BloodGroup::ABNegative
+        );
+        assert_eq!(BloodGroup::from_legacy("B+ve"), BloodGroup::BPositive);
+        assert_eq!(BloodGroup::from_legacy("banana"), BloodGroup::Unknown);
+        assert_eq!(BloodGroup::from_legacy(""), BloodGroup::Unknown);
+    }
+
+    #[test]
+    fn red_cell_compatibility() {
+        assert_eq!(compatible_donor_groups(BloodGroup::ABPositive).len(), 8);
+        assert_eq!(
+            compatible_donor_groups(BloodGroup::ONegative),
+            vec![BloodGroup::ONegative]
+        );
+        assert_eq!(
+            compatible_donor_groups(BloodGroup::ANegative),
+            vec![BloodGroup::ANegative, BloodGroup::ONegative]
+        );
+        assert_eq!(
+            compatible_recipient_groups(BloodGroup::BPositive),
+            vec![BloodGroup::BPositive, BloodGroup::ABPositive]
+        );
+        assert_eq!(compatible_recipient_groups(BloodGroup::ONegative).len(), 8);
+        assert_eq!(
+            compatible_donor_groups(BloodGroup::Unknown),
+            vec![BloodGroup::ONegative]
+        );
+        assert!(compatible_recipient_groups(BloodGroup::Unknown).is_empty());
+    }
+
+    #[test]
+    fn decodes_donors_stored_with_free_text_groups() {
+        #[derive(candid::CandidType)]
+        struct StoredDonor {
+            id: u64,
+            name: String,
+            password: String,
+            blood_group: String,
+            beneficiaries: Vec<u64>,
+        }
+        let bytes = Encode!(&StoredDonor {
+            id: 7,
+            name: "John".to_string(),
+            password: String::new(),
+            blood_group: "o pos".to_string(),
+            beneficiaries: vec![1],
+        })
+        .unwrap();
+
+        let donor = Donor::from_bytes(Cow::Owned(bytes));
+        assert_eq!(donor.id, DonorId(7));
+        assert_eq!(donor.blood_group, BloodGroup::OPositive);
+        assert_eq!(donor.beneficiaries, Some(vec![1]));
+    }
+}
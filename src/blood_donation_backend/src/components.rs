@@ -0,0 +1,126 @@
+use crate::blood_group::BloodGroup;
+use crate::Error;
+
+// Blood components patients need and donors give. Packed red cells and cryoprecipitate are
+// prepared from a whole blood donation, old records without a component were whole blood.
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug, Default,
+)]
+pub(crate) enum Component {
+    #[default]
+    WholeBlood,
+    Plasma,
+    Platelets,
+    PackedRedCells,
+    Cryoprecipitate,
+}
+
+// Components that are collected from donors, each with its own donation interval
+pub(crate) const COLLECTED_COMPONENTS: [Component; 3] = [
+    Component::WholeBlood,
+    Component::Plasma,
+    Component::Platelets,
+];
+
+impl Component {
+    // Stable map key of the component
+    pub(crate) fn key(self) -> u8 {
+        match self {
+            Component::WholeBlood => 0,
+            Component::Plasma => 1,
+            Component::Platelets => 2,
+            Component::PackedRedCells => 3,
+            Component::Cryoprecipitate => 4,
+        }
+    }
+
+    // The collection a component comes from
+    pub(crate) fn collected_as(self) -> Component {
+        match self {
+            Component::PackedRedCells | Component::Cryoprecipitate => Component::WholeBlood,
+            component => component,
+        }
+    }
+
+    // Red cells must carry no antigen the recipient lacks, plasma must carry no antibody
+    // against the recipient's ABO antigens
+    pub(crate) fn compatible(self, donor: BloodGroup, recipient: BloodGroup) -> bool {
+        match self {
+            Component::Plasma | Component::Cryoprecipitate => recipient.abo_subset_of(donor),
+            _ => donor.can_donate_to(recipient),
+        }
+    }
+}
+
+// Pints of a component needed by a patient and donated so far
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) struct ComponentNeed {
+    pub(crate) component: Component,
+    pub(crate) needed_pints: u32,
+    pub(crate) donations: u32,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) struct ComponentPints {
+    pub(crate) component: Component,
+    pub(crate) pints: u32,
+}
+
+// Most pints of a component a patient can need
+pub(crate) const MAX_NEEDED_PINTS: u32 = 100;
+
+// Check a list of needs names each component once with 1 to `MAX_NEEDED_PINTS` pints
+pub(crate) fn validate_needs(needs: &[ComponentPints]) -> Result<(), Error> {
+    if needs.is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: "at least one component need is required".to_string(),
+        });
+    }
+    for (i, need) in needs.iter().enumerate() {
+        if need.pints == 0 || need.pints > MAX_NEEDED_PINTS {
+            return Err(Error::InvalidPayload {
+                msg: format!(
+                    "needed pints of {:?} must be 1 to {}",
+                    need.component, MAX_NEEDED_PINTS
+                ),
+            });
+        }
+        if needs[..i]
+            .iter()
+            .any(|other| other.component == need.component)
+        {
+            return Err(Error::InvalidPayload {
+                msg: format!("{:?} is listed more than once", need.component),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plasma_compatibility_is_reversed() {
+        assert!(Component::PackedRedCells.compatible(BloodGroup::ONegative, BloodGroup::ABPositive));
+        assert!(!Component::Plasma.compatible(BloodGroup::ONegative, BloodGroup::ABPositive));
+        assert!(Component::Plasma.compatible(BloodGroup::ABNegative, BloodGroup::OPositive));
+        assert!(Component::Cryoprecipitate.compatible(BloodGroup::APositive, BloodGroup::ANegative));
+        assert!(!Component::Plasma.compatible(BloodGroup::Unknown, BloodGroup::OPositive));
+    }
+
+    #[test]
+    fn needs_are_bounded() {
+        let needs = |pints| {
+            [ComponentPints {
+                component: Component::Plasma,
+                pints,
+            }]
+        };
+        assert!(validate_needs(&needs(MAX_NEEDED_PINTS)).is_ok());
+        assert!(validate_needs(&needs(0)).is_err());
+        assert!(validate_needs(&needs(MAX_NEEDED_PINTS + 1)).is_err());
+        assert!(validate_needs(&needs(u32::MAX)).is_err());
+    }
+}
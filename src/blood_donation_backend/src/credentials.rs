@@ -0,0 +1,103 @@
+use crate::Error;
+use candid::{Decode, Encode};
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_stable_structures::{BoundedStorable, Storable};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+
+// Account kinds used to key legacy credentials in stable memory
+pub(crate) const HOSPITAL_ACCOUNT: u8 = 0;
+pub(crate) const PATIENT_ACCOUNT: u8 = 1;
+pub(crate) const DONOR_ACCOUNT: u8 = 2;
+
+// Number of hashing rounds applied to every legacy password
+const HASH_ITERATIONS: u32 = 10_000;
+const SALT_LEN: usize = 16;
+
+// Salted, iterated hash of a legacy account password
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct Credential {
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+    iterations: u32,
+}
+
+impl Credential {
+    // Hash a password with the given salt
+    pub(crate) fn new(password: &str, salt: &[u8]) -> Self {
+        Credential {
+            salt: salt.to_vec(),
+            hash: derive_hash(password, salt, HASH_ITERATIONS),
+            iterations: HASH_ITERATIONS,
+        }
+    }
+
+    // Check a password against the stored hash
+    pub(crate) fn verify(&self, password: &str) -> bool {
+        let candidate = derive_hash(password, &self.salt, self.iterations);
+        // compare in constant time so the hash does not leak through timing
+        candidate.len() == self.hash.len()
+            && candidate
+                .iter()
+                .zip(self.hash.iter())
+                .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+}
+
+impl Storable for Credential {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Credential {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+fn derive_hash(password: &str, salt: &[u8], iterations: u32) -> Vec<u8> {
+    let mut digest = Sha256::new()
+        .chain_update(salt)
+        .chain_update(password.as_bytes())
+        .finalize();
+    for _ in 1..iterations {
+        digest = Sha256::new()
+            .chain_update(digest)
+            .chain_update(salt)
+            .chain_update(password.as_bytes())
+            .finalize();
+    }
+    digest.to_vec()
+}
+
+// Fetch a fresh salt from the management canister
+pub(crate) async fn random_salt() -> Result<Vec<u8>, Error> {
+    let seed = random_seed().await?;
+    Ok(seed[..SALT_LEN].to_vec())
+}
+
+// Fetch 32 random bytes from the management canister
+pub(crate) async fn random_seed() -> Result<Vec<u8>, Error> {
+    match raw_rand().await {
+        Ok((bytes,)) => Ok(bytes),
+        Err((code, msg)) => Err(Error::InvalidPayload {
+            msg: format!("Could not get randomness: {:?} {}", code, msg),
+        }),
+    }
+}
+
+// Derive a distinct salt for one account from a random seed shared by a migration batch
+pub(crate) fn derive_salt(seed: &[u8], kind: u8, id: u64) -> Vec<u8> {
+    let digest = Sha256::new()
+        .chain_update(seed)
+        .chain_update([kind])
+        .chain_update(id.to_le_bytes())
+        .finalize();
+    digest[..SALT_LEN].to_vec()
+}
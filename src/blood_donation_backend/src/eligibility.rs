@@ -0,0 +1,602 @@
+use crate::components::{Component, COLLECTED_COMPONENTS};
+use crate::ids::{self, DonorId, HospitalId};
+use crate::roles::{self, caller_is_super_admin, caller_is_verified_hospital_staff, Role};
+use crate::staff::{self, StaffAction, StaffPermission};
+use crate::{
+    caller_principal, now, pledges, Error, DEFERRAL_ID_SEQUENCE, DEFERRAL_STORAGE,
+    DONATION_RULE_STORAGE, DONOR_STORAGE, HOSPITAL_STORAGE,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+use validator::Validate;
+
+const DAY: u64 = 86_400_000_000_000;
+const YEAR: u64 = 365 * DAY;
+// Longest temporary deferral and donation interval, about ten years
+pub(crate) const MAX_DEFERRAL_DAYS: u32 = 3650;
+
+// Minimum days between two collections of a component and most collections in a year
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) struct DonationRule {
+    component: Component,
+    min_interval_days: u32,
+    annual_cap: u32,
+}
+
+impl Storable for DonationRule {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DonationRule {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) enum DeferralKind {
+    Temporary { until: u64 },
+    Permanent,
+}
+
+// A donor kept from donating by hospital staff or by their screening answers, with the reason.
+// `hospital_id` is None when a donor's own screening answers deferred them.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Deferral {
+    id: u64,
+    donor_id: DonorId,
+    kind: DeferralKind,
+    reason: String,
+    hospital_id: Option<HospitalId>,
+    recorded_by: Principal,
+    recorded_at: u64,
+}
+
+impl Storable for Deferral {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Deferral {
+    // a reason of 200 characters takes up to 800 bytes
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// `days` of None records a permanent deferral
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct DeferDonorPayload {
+    donor_id: DonorId,
+    // hospital the deferral is recorded for, where the caller confirms donations
+    hospital_id: HospitalId,
+    #[validate(range(min = 1, max = "MAX_DEFERRAL_DAYS"))]
+    days: Option<u32>,
+    #[validate(length(min = 3, max = 200))]
+    reason: String,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct ComponentEligibility {
+    component: Component,
+    last_collected_at: Option<u64>,
+    collections_past_year: u32,
+    next_eligible_at: u64,
+}
+
+// When a donor can give each component again, a deferral pushes back every component
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct DonorEligibility {
+    donor_id: DonorId,
+    eligible_now: bool,
+    deferral: Option<Deferral>,
+    components: Vec<ComponentEligibility>,
+}
+
+fn default_rule(component: Component) -> DonationRule {
+    let (min_interval_days, annual_cap) = match component {
+        Component::Plasma => (28, 13),
+        Component::Platelets => (7, 24),
+        _ => (56, 6),
+    };
+    DonationRule {
+        component,
+        min_interval_days,
+        annual_cap,
+    }
+}
+
+// Rules apply to the collection a component comes from
+fn rule_for(component: Component) -> DonationRule {
+    let component = component.collected_as();
+    DONATION_RULE_STORAGE
+        .with(|s| s.borrow().get(&component.key()))
+        .unwrap_or_else(|| default_rule(component))
+}
+
+// The deferral in force at `at`, a permanent one wins over the longest temporary one
+fn active_deferral(donor_id: DonorId, at: u64) -> Option<Deferral> {
+    DEFERRAL_STORAGE.with(|s| {
+        s.borrow()
+            .range((donor_id, 0)..)
+            .take_while(|((id, _), _)| *id == donor_id)
+            .map(|(_, deferral)| deferral)
+            .filter(|deferral| match deferral.kind {
+                DeferralKind::Temporary { until } => until > at,
+                DeferralKind::Permanent => true,
+            })
+            .max_by_key(|deferral| match deferral.kind {
+                DeferralKind::Temporary { until } => until,
+                DeferralKind::Permanent => u64::MAX,
+            })
+    })
+}
+
+// Eligibility for a component from the donor's collections, as (collection, collected at)
+fn component_eligibility(
+    collections: &[(Component, u64)],
+    component: Component,
+    at: u64,
+) -> ComponentEligibility {
+    let component = component.collected_as();
+    let rule = rule_for(component);
+    let mut collected: Vec<u64> = collections
+        .iter()
+        .copied()
+        .filter(|(collected_component, _)| *collected_component == component)
+        .map(|(_, collected_at)| collected_at)
+        .collect();
+    collected.sort_unstable();
+    let last_collected_at = collected.last().copied();
+    let past_year: Vec<u64> = collected
+        .into_iter()
+        .filter(|collected_at| collected_at.saturating_add(YEAR) > at)
+        .collect();
+
+    let mut next_eligible_at = last_collected_at
+        .map(|last| last.saturating_add(u64::from(rule.min_interval_days).saturating_mul(DAY)))
+        .unwrap_or(0);
+    // once the cap is reached the donor waits for enough collections to leave the past year
+    if past_year.len() >= rule.annual_cap as usize {
+        let leaving = past_year.len() - rule.annual_cap as usize;
+        next_eligible_at = next_eligible_at.max(past_year[leaving].saturating_add(YEAR));
+    }
+    ComponentEligibility {
+        component,
+        last_collected_at,
+        collections_past_year: past_year.len() as u32,
+        next_eligible_at,
+    }
+}
+
+fn donor_eligibility(donor_id: DonorId, at: u64) -> DonorEligibility {
+    let collections = pledges::collections_of_donor(donor_id);
+    let deferral = active_deferral(donor_id, at);
+    let deferred_until = match deferral.as_ref().map(|deferral| deferral.kind) {
+        Some(DeferralKind::Temporary { until }) => until,
+        Some(DeferralKind::Permanent) => u64::MAX,
+        None => 0,
+    };
+    let components: Vec<ComponentEligibility> = COLLECTED_COMPONENTS
+        .iter()
+        .map(|component| {
+            let eligibility = component_eligibility(&collections, *component, at);
+            ComponentEligibility {
+                next_eligible_at: eligibility.next_eligible_at.max(deferred_until),
+                ..eligibility
+            }
+        })
+        .collect();
+    DonorEligibility {
+        donor_id,
+        eligible_now: deferral.is_none() && components.iter().any(|c| c.next_eligible_at <= at),
+        deferral,
+        components,
+    }
+}
+
+fn check_not_deferred(donor_id: DonorId, at: u64) -> Result<(), Error> {
+    match active_deferral(donor_id, at) {
+        Some(deferral) => Err(Error::IneligibleDonor {
+            msg: format!(
+                "donor of id: {} is deferred from donating: {}",
+                donor_id, deferral.reason
+            ),
+        }),
+        None => Ok(()),
+    }
+}
+
+fn check_interval(
+    donor_id: DonorId,
+    collections: &[(Component, u64)],
+    component: Component,
+    at: u64,
+) -> Result<(), Error> {
+    let eligibility = component_eligibility(collections, component, at);
+    if eligibility.next_eligible_at > at {
+        return Err(Error::IneligibleDonor {
+            msg: format!(
+                "donor of id: {} cannot give {:?} again before {}",
+                donor_id, component, eligibility.next_eligible_at
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Refuse a donation of `component` from a deferred donor or one that gave it too recently
+pub(crate) fn check_donor_eligible(
+    donor_id: DonorId,
+    component: Component,
+    at: u64,
+) -> Result<(), Error> {
+    check_not_deferred(donor_id, at)?;
+    check_interval(
+        donor_id,
+        &pledges::collections_of_donor(donor_id),
+        component,
+        at,
+    )
+}
+
+// Collections a donor may give at `at` given their past collections, already read by the
+// caller when checking many donors. Refused with the reason when there is none.
+pub(crate) fn eligible_collections(
+    donor_id: DonorId,
+    collections: &[(Component, u64)],
+    at: u64,
+) -> Result<Vec<Component>, Error> {
+    check_not_deferred(donor_id, at)?;
+    let mut eligible = vec![];
+    let mut refusal = None;
+    for component in COLLECTED_COMPONENTS {
+        match check_interval(donor_id, collections, component, at) {
+            Ok(()) => eligible.push(component),
+            Err(e) => {
+                refusal.get_or_insert(e);
+            }
+        }
+    }
+    match refusal {
+        Some(refusal) if eligible.is_empty() => Err(refusal),
+        _ => Ok(eligible),
+    }
+}
+
+fn defer(caller: Principal, payload: DeferDonorPayload) -> Result<Deferral, Error> {
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    let hospital_id = payload.hospital_id;
+    match HOSPITAL_STORAGE.with(|s| s.borrow().get(&hospital_id)) {
+        Some(hospital) if hospital.verified_by.is_some() => {}
+        Some(_) => {
+            return Err(Error::Unauthorized {
+                msg: format!("hospital of id: {} is not verified", hospital_id),
+            })
+        }
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("hospital of id: {} not found", hospital_id),
+            })
+        }
+    }
+    staff::require_permission(&caller, hospital_id, StaffPermission::ConfirmDonations)?;
+    if !DONOR_STORAGE.with(|s| s.borrow().contains_key(&payload.donor_id)) {
+        return Err(Error::NotFound {
+            msg: format!("Donor of id: {} not found", payload.donor_id),
+        });
+    }
+
+    Ok(record_deferral(
+        payload.donor_id,
+        payload.days,
+        payload.reason,
+        Some(hospital_id),
+        caller,
+    ))
+}
+
+// Store a deferral of `days`, or a permanent one, attributing it to the hospital when there is one
+pub(crate) fn record_deferral(
+    donor_id: DonorId,
+    days: Option<u32>,
+    reason: String,
+    hospital_id: Option<HospitalId>,
+    recorded_by: Principal,
+) -> Deferral {
+    let id = ids::next_in(&DEFERRAL_ID_SEQUENCE);
+
+    let kind = match days {
+        Some(days) => DeferralKind::Temporary {
+            until: now().saturating_add(u64::from(days).saturating_mul(DAY)),
+        },
+        None => DeferralKind::Permanent,
+    };
+    let deferral = Deferral {
+        id,
+        donor_id,
+        kind,
+        reason,
+        hospital_id,
+        recorded_by,
+        recorded_at: now(),
+    };
+    DEFERRAL_STORAGE.with(|s| s.borrow_mut().insert((donor_id, id), deferral.clone()));
+    if let Some(hospital_id) = hospital_id {
+        staff::record_activity(
+            hospital_id,
+            recorded_by,
+            StaffAction::DeferredDonor { donor_id },
+        );
+    }
+    deferral
+}
+
+// record a temporary or permanent deferral of a donor on behalf of a hospital of the caller
+#[ic_cdk::update(guard = "caller_is_verified_hospital_staff")]
+fn defer_donor(payload: DeferDonorPayload) -> Result<Deferral, Error> {
+    let caller = caller_principal()?;
+    defer(caller, payload)
+}
+
+// change the interval and annual cap of a component, only for canister administrators
+#[ic_cdk::update(guard = "caller_is_super_admin")]
+fn set_donation_rule(rule: DonationRule) -> Result<DonationRule, Error> {
+    if rule.component.collected_as() != rule.component {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "{:?} is prepared from {:?}, set the rule of the collection instead",
+                rule.component,
+                rule.component.collected_as()
+            ),
+        });
+    }
+    if rule.min_interval_days == 0 || rule.annual_cap == 0 {
+        return Err(Error::InvalidPayload {
+            msg: "donation interval and annual cap must be at least 1".to_string(),
+        });
+    }
+    if rule.min_interval_days > MAX_DEFERRAL_DAYS {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "donation interval must be at most {} days",
+                MAX_DEFERRAL_DAYS
+            ),
+        });
+    }
+    DONATION_RULE_STORAGE.with(|s| s.borrow_mut().insert(rule.component.key(), rule));
+    Ok(rule)
+}
+
+// list the donation interval and annual cap in force for every component
+#[ic_cdk::query]
+fn get_donation_rules() -> Vec<DonationRule> {
+    COLLECTED_COMPONENTS
+        .iter()
+        .map(|component| rule_for(*component))
+        .collect()
+}
+
+// The donor, or staff of a hospital the donor pledged to or was deferred by
+fn authorize_eligibility_viewer(caller: &Principal, donor_id: DonorId) -> Result<(), Error> {
+    if roles::require_role(caller, &[Role::Donor], Some(donor_id.0)).is_ok() {
+        return Ok(());
+    }
+    let mut hospitals = pledges::hospitals_of_donor(donor_id);
+    DEFERRAL_STORAGE.with(|s| {
+        hospitals.extend(
+            s.borrow()
+                .range((donor_id, 0)..)
+                .take_while(|((id, _), _)| *id == donor_id)
+                .filter_map(|(_, deferral)| deferral.hospital_id),
+        )
+    });
+    if hospitals.into_iter().any(|hospital_id| {
+        roles::require_role(
+            caller,
+            &[Role::HospitalAdmin, Role::HospitalStaff],
+            Some(hospital_id.0),
+        )
+        .is_ok()
+    }) {
+        return Ok(());
+    }
+    Err(Error::Unauthorized {
+        msg: format!(
+            "Unauthorized, caller is not donor of id: {} or staff of a hospital they dealt with",
+            donor_id
+        ),
+    })
+}
+
+fn eligibility_of(
+    caller: &Principal,
+    donor_id: DonorId,
+    at: u64,
+) -> Result<DonorEligibility, Error> {
+    if !DONOR_STORAGE.with(|s| s.borrow().contains_key(&donor_id)) {
+        return Err(Error::NotFound {
+            msg: format!("Donor of id: {} not found", donor_id),
+        });
+    }
+    authorize_eligibility_viewer(caller, donor_id)?;
+    Ok(donor_eligibility(donor_id, at))
+}
+
+// when a donor can donate each component again, for the donor and staff of the hospitals the
+// donor pledged to or was deferred by
+#[ic_cdk::query]
+fn get_donor_eligibility(donor_id: DonorId) -> Result<DonorEligibility, Error> {
+    eligibility_of(&ic_cdk::caller(), donor_id, now())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_intervals_and_caps() {
+        let at = 1_000 * DAY;
+        assert!(check_donor_eligible(DonorId(1), Component::WholeBlood, at).is_ok());
+        assert_eq!(rule_for(Component::WholeBlood).min_interval_days, 56);
+
+        let eligibility = donor_eligibility(DonorId(1), at);
+        assert!(eligibility.eligible_now);
+        assert!(eligibility
+            .components
+            .iter()
+            .all(|component| component.next_eligible_at == 0));
+    }
+
+    #[test]
+    fn deferral_blocks_every_component() {
+        let at = 1_000 * DAY;
+        DEFERRAL_STORAGE.with(|s| {
+            s.borrow_mut().insert(
+                (DonorId(1), 10),
+                Deferral {
+                    id: 10,
+                    donor_id: DonorId(1),
+                    kind: DeferralKind::Temporary { until: at + DAY },
+                    reason: "recent tattoo".to_string(),
+                    hospital_id: Some(HospitalId(2)),
+                    recorded_by: Principal::anonymous(),
+                    recorded_at: at,
+                },
+            )
+        });
+        assert!(matches!(
+            check_donor_eligible(DonorId(1), Component::Platelets, at),
+            Err(Error::IneligibleDonor { .. })
+        ));
+        assert!(check_donor_eligible(DonorId(1), Component::Platelets, at + DAY).is_ok());
+        let eligibility = donor_eligibility(DonorId(1), at);
+        assert!(!eligibility.eligible_now);
+        assert!(eligibility
+            .components
+            .iter()
+            .all(|component| component.next_eligible_at == at + DAY));
+    }
+
+    #[test]
+    fn deferrals_and_intervals_are_bounded() {
+        let payload = |days| DeferDonorPayload {
+            donor_id: DonorId(1),
+            hospital_id: HospitalId(2),
+            days: Some(days),
+            reason: "recent tattoo".to_string(),
+        };
+        assert!(payload(MAX_DEFERRAL_DAYS).validate().is_ok());
+        assert!(payload(MAX_DEFERRAL_DAYS + 1).validate().is_err());
+        assert!(payload(u32::MAX).validate().is_err());
+
+        let rule = |min_interval_days| DonationRule {
+            component: Component::WholeBlood,
+            min_interval_days,
+            annual_cap: 6,
+        };
+        assert!(set_donation_rule(rule(MAX_DEFERRAL_DAYS + 1)).is_err());
+        assert!(set_donation_rule(rule(MAX_DEFERRAL_DAYS)).is_ok());
+        // the next eligible time of a collection near the end of time saturates
+        let eligibility = component_eligibility(
+            &[(Component::WholeBlood, u64::MAX - DAY)],
+            Component::WholeBlood,
+            u64::MAX - DAY,
+        );
+        assert_eq!(eligibility.next_eligible_at, u64::MAX);
+    }
+
+    #[test]
+    fn deferrals_are_recorded_for_the_named_hospital() {
+        let caller = Principal::from_slice(&[3; 10]);
+        let payload = DeferDonorPayload {
+            donor_id: DonorId(1),
+            hospital_id: HospitalId(2),
+            days: Some(30),
+            reason: "low iron".to_string(),
+        };
+        DONOR_STORAGE.with(|s| {
+            s.borrow_mut().insert(
+                DonorId(1),
+                crate::Donor {
+                    id: DonorId(1),
+                    ..Default::default()
+                },
+            )
+        });
+        assert!(matches!(
+            defer(caller, payload.clone()),
+            Err(Error::NotFound { .. })
+        ));
+        let hospital = crate::Hospital {
+            id: HospitalId(2),
+            ..Default::default()
+        };
+        HOSPITAL_STORAGE.with(|s| s.borrow_mut().insert(HospitalId(2), hospital.clone()));
+        assert!(matches!(
+            defer(caller, payload.clone()),
+            Err(Error::Unauthorized { .. })
+        ));
+        let hospital = crate::Hospital {
+            verified_by: Some(caller),
+            ..hospital
+        };
+        HOSPITAL_STORAGE.with(|s| s.borrow_mut().insert(HospitalId(2), hospital));
+        // staff of another hospital cannot defer in its name
+        roles::grant(
+            caller,
+            roles::RoleGrant {
+                role: Role::HospitalAdmin,
+                scope_id: Some(5),
+            },
+        );
+        assert!(matches!(
+            defer(caller, payload.clone()),
+            Err(Error::Unauthorized { .. })
+        ));
+        roles::grant(
+            caller,
+            roles::RoleGrant {
+                role: Role::HospitalAdmin,
+                scope_id: Some(2),
+            },
+        );
+        // only the donor and staff of hospitals the donor dealt with see the eligibility
+        let at = now();
+        assert!(matches!(
+            eligibility_of(&caller, DonorId(1), at),
+            Err(Error::Unauthorized { .. })
+        ));
+        let deferral = defer(caller, payload).unwrap();
+        assert_eq!(deferral.hospital_id, Some(HospitalId(2)));
+        assert!(
+            !eligibility_of(&caller, DonorId(1), at)
+                .unwrap()
+                .eligible_now
+        );
+        let donor = Principal::from_slice(&[1; 10]);
+        assert!(eligibility_of(&donor, DonorId(1), at).is_err());
+        roles::grant(
+            donor,
+            roles::RoleGrant {
+                role: Role::Donor,
+                scope_id: Some(1),
+            },
+        );
+        assert!(eligibility_of(&donor, DonorId(1), at).is_ok());
+    }
+}
@@ -0,0 +1,401 @@
+use crate::ids::{self, HospitalId, PatientId, PledgeId};
+use crate::pagination::{self, ListOptions, Listed, Page};
+use crate::pledges::{self, PledgeRecipient};
+use crate::roles::{self, caller_is_super_admin, Role};
+use crate::{
+    inventory, now, Error, EXPIRY_EVENT_ID_SEQUENCE, EXPIRY_EVENT_STORAGE, EXPIRY_SETTINGS,
+    HOSPITAL_EXPIRY_EVENT_INDEX, PATIENT_STORAGE,
+};
+use candid::{Decode, Encode};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+use std::time::Duration;
+
+const HOUR: u64 = 3_600_000_000_000;
+const DAY: u64 = 24 * HOUR;
+
+// How often expired units, stale pledges and stale reservations are swept
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// Units or pledges read per timer tick by a sweep
+const SWEEP_BATCH: usize = 200;
+// Longest pledge window and reservation hold that can be set
+const MAX_PLEDGE_WINDOW_DAYS: u32 = 365;
+const MAX_RESERVATION_HOLD_HOURS: u32 = 720;
+
+// Days a pledge has to be collected and hours a unit stays reserved before the sweep
+// expires the pledge or puts the unit back in stock
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) struct ExpirySettings {
+    pledge_window_days: u32,
+    reservation_hold_hours: u32,
+}
+
+impl Default for ExpirySettings {
+    fn default() -> Self {
+        ExpirySettings {
+            pledge_window_days: 14,
+            reservation_hold_hours: 48,
+        }
+    }
+}
+
+impl Storable for ExpirySettings {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Transitions made by the sweep rather than by a caller
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) enum ExpiryEventKind {
+    UnitExpired {
+        unit_id: u64,
+    },
+    ReservationReleased {
+        unit_id: u64,
+        patient_id: Option<PatientId>,
+    },
+    PledgeExpired {
+        pledge_id: PledgeId,
+        recipient: PledgeRecipient,
+    },
+}
+
+// `hospital_id` is None for pledges made to a patient not linked to a hospital
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ExpiryEvent {
+    id: u64,
+    hospital_id: Option<HospitalId>,
+    kind: ExpiryEventKind,
+    at: u64,
+}
+
+impl Listed for ExpiryEvent {
+    type Cursor = u64;
+    const NAME: &'static str = "expiry events";
+
+    fn cursor(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Storable for ExpiryEvent {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ExpiryEvent {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+fn settings() -> ExpirySettings {
+    EXPIRY_SETTINGS.with(|cell| *cell.borrow().get())
+}
+
+fn record_event(hospital_id: Option<HospitalId>, kind: ExpiryEventKind, at: u64) {
+    let id = ids::next_in(&EXPIRY_EVENT_ID_SEQUENCE);
+    let event = ExpiryEvent {
+        id,
+        hospital_id,
+        kind,
+        at,
+    };
+    EXPIRY_EVENT_STORAGE.with(|s| s.borrow_mut().insert(id, event));
+    if let Some(hospital_id) = hospital_id {
+        HOSPITAL_EXPIRY_EVENT_INDEX.with(|s| s.borrow_mut().insert((hospital_id, id), ()));
+    }
+}
+
+// A page of the events at a hospital, read through the hospital index, or of every event
+fn event_page(
+    hospital_id: Option<HospitalId>,
+    options: &ListOptions<u64>,
+) -> Result<Page<ExpiryEvent, u64>, Error> {
+    EXPIRY_EVENT_STORAGE.with(|s| {
+        let events = s.borrow();
+        match hospital_id {
+            Some(hospital_id) => HOSPITAL_EXPIRY_EVENT_INDEX.with(|index| {
+                pagination::paginate(
+                    index
+                        .borrow()
+                        .range((hospital_id, 0)..)
+                        .take_while(|((id, _), _)| *id == hospital_id)
+                        .filter_map(|((_, id), _)| events.get(&id)),
+                    options,
+                    |id| events.get(&id),
+                )
+            }),
+            None => pagination::paginate(events.iter().map(|(_, event)| event), options, |id| {
+                events.get(&id)
+            }),
+        }
+    })
+}
+
+// Where a sweep carries on: after a unit of the inventory, then after a pledge
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum SweepCursor {
+    Units(Option<(HospitalId, u64)>),
+    Pledges(Option<PledgeId>),
+}
+
+// Sweep up to `limit` units or pledges from `cursor`, returns where the next batch starts or
+// None once the inventory and then the pledges were read through
+fn sweep_batch(at: u64, cursor: SweepCursor, limit: usize) -> Option<SweepCursor> {
+    let settings = settings();
+    let after = match cursor {
+        SweepCursor::Units(after) => after,
+        SweepCursor::Pledges(after) => return sweep_pledges(at, settings, after, limit),
+    };
+    let reserved_before =
+        at.saturating_sub(u64::from(settings.reservation_hold_hours).saturating_mul(HOUR));
+    let units = inventory::sweep_units(at, reserved_before, after, limit);
+    for (hospital_id, unit_id) in units.expired {
+        record_event(
+            Some(hospital_id),
+            ExpiryEventKind::UnitExpired { unit_id },
+            at,
+        );
+    }
+    for (hospital_id, unit_id, patient_id) in units.released {
+        record_event(
+            Some(hospital_id),
+            ExpiryEventKind::ReservationReleased {
+                unit_id,
+                patient_id,
+            },
+            at,
+        );
+    }
+    Some(match units.next {
+        Some(next) => SweepCursor::Units(Some(next)),
+        None => SweepCursor::Pledges(None),
+    })
+}
+
+fn sweep_pledges(
+    at: u64,
+    settings: ExpirySettings,
+    after: Option<PledgeId>,
+    limit: usize,
+) -> Option<SweepCursor> {
+    let pledged_before =
+        at.saturating_sub(u64::from(settings.pledge_window_days).saturating_mul(DAY));
+    let (expired, next) = pledges::expire_stale_pledges(pledged_before, after, limit);
+    for (pledge_id, recipient) in expired {
+        let hospital_id = match recipient {
+            PledgeRecipient::Hospital(id) => Some(id),
+            PledgeRecipient::Patient(id) => PATIENT_STORAGE
+                .with(|s| s.borrow().get(&id))
+                .and_then(|patient| patient.hospital_id),
+        };
+        record_event(
+            hospital_id,
+            ExpiryEventKind::PledgeExpired {
+                pledge_id,
+                recipient,
+            },
+            at,
+        );
+    }
+    next.map(|next| SweepCursor::Pledges(Some(next)))
+}
+
+// Each batch runs in a timer tick of its own, keeping a sweep under the instruction limit
+// however large the inventory and pledges grow
+fn run_sweep(at: u64, cursor: SweepCursor) {
+    if let Some(next) = sweep_batch(at, cursor, SWEEP_BATCH) {
+        ic_cdk_timers::set_timer(Duration::ZERO, move || run_sweep(at, next));
+    }
+}
+
+// Timers do not survive upgrades, so this runs from both init and post_upgrade
+pub(crate) fn schedule_sweeps() {
+    ic_cdk_timers::set_timer_interval(SWEEP_INTERVAL, || {
+        run_sweep(now(), SweepCursor::Units(None))
+    });
+}
+
+// change the pledge collection window and reservation hold, only for canister administrators
+#[ic_cdk::update(guard = "caller_is_super_admin")]
+fn set_expiry_settings(settings: ExpirySettings) -> Result<ExpirySettings, Error> {
+    if !(1..=MAX_PLEDGE_WINDOW_DAYS).contains(&settings.pledge_window_days)
+        || !(1..=MAX_RESERVATION_HOLD_HOURS).contains(&settings.reservation_hold_hours)
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "pledge window must be 1 to {} days and reservation hold 1 to {} hours",
+                MAX_PLEDGE_WINDOW_DAYS, MAX_RESERVATION_HOLD_HOURS
+            ),
+        });
+    }
+    EXPIRY_SETTINGS
+        .with(|cell| cell.borrow_mut().set(settings))
+        .expect("Cannot store the expiry settings");
+    Ok(settings)
+}
+
+#[ic_cdk::query]
+fn get_expiry_settings() -> ExpirySettings {
+    settings()
+}
+
+// list a page of the automatic transitions at a hospital, or of every transition for canister
+// administrators
+#[ic_cdk::query]
+fn get_expiry_events(
+    hospital_id: Option<HospitalId>,
+    options: ListOptions<u64>,
+) -> Result<Page<ExpiryEvent, u64>, Error> {
+    let caller = ic_cdk::caller();
+    match hospital_id {
+        Some(id) => roles::require_role(
+            &caller,
+            &[Role::HospitalAdmin, Role::HospitalStaff],
+            Some(id.0),
+        )?,
+        None => roles::require_role(&caller, &[], None)?,
+    }
+    event_page(hospital_id, &options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blood_group::BloodGroup;
+    use crate::components::Component;
+    use crate::inventory::UnitStatus;
+    use crate::INVENTORY_STORAGE;
+
+    fn events() -> Vec<ExpiryEventKind> {
+        EXPIRY_EVENT_STORAGE.with(|s| s.borrow().iter().map(|(_, event)| event.kind).collect())
+    }
+
+    // run every batch of a sweep, two records at a time
+    fn sweep(at: u64) {
+        let mut cursor = Some(SweepCursor::Units(None));
+        while let Some(next) = cursor {
+            cursor = sweep_batch(at, next, 2);
+        }
+    }
+
+    fn reserved_unit(hospital_id: u64, label: &str, collected_at: u64, reserved_at: u64) -> u64 {
+        let mut unit = inventory::add_unit(
+            HospitalId(hospital_id),
+            label.to_string(),
+            Component::WholeBlood,
+            BloodGroup::ONegative,
+            collected_at,
+            None,
+        );
+        unit.status = UnitStatus::Reserved;
+        unit.updated_at = reserved_at;
+        INVENTORY_STORAGE.with(|s| {
+            s.borrow_mut()
+                .insert((unit.hospital_id, unit.id), unit.clone())
+        });
+        unit.id
+    }
+
+    #[test]
+    fn sweep_records_each_transition() {
+        let at = now();
+        inventory::add_unit(
+            HospitalId(1),
+            "BAG-1".to_string(),
+            Component::Platelets,
+            BloodGroup::ONegative,
+            at - 6 * DAY,
+            None,
+        );
+        inventory::add_unit(
+            HospitalId(1),
+            "BAG-2".to_string(),
+            Component::Plasma,
+            BloodGroup::ONegative,
+            at - 6 * DAY,
+            None,
+        );
+        sweep(at);
+        assert!(matches!(
+            events()[..],
+            [ExpiryEventKind::UnitExpired { .. }]
+        ));
+        // a second sweep finds nothing left to do
+        sweep(at);
+        assert_eq!(events().len(), 1);
+    }
+
+    #[test]
+    fn stale_reservations_go_back_in_stock() {
+        let at = now();
+        let stale = reserved_unit(1, "BAG-1", at - DAY, at - 49 * HOUR);
+        let fresh = reserved_unit(1, "BAG-2", at - DAY, at - HOUR);
+        // expired while reserved, discarded rather than put back in stock
+        let expired = reserved_unit(2, "BAG-3", at - 40 * DAY, at - 49 * HOUR);
+        sweep(at);
+
+        assert_eq!(
+            events(),
+            [
+                ExpiryEventKind::ReservationReleased {
+                    unit_id: stale,
+                    patient_id: None
+                },
+                ExpiryEventKind::UnitExpired { unit_id: expired },
+            ]
+        );
+        let status = |hospital_id, unit_id| {
+            INVENTORY_STORAGE
+                .with(|s| s.borrow().get(&(HospitalId(hospital_id), unit_id)))
+                .unwrap()
+                .status
+        };
+        assert_eq!(status(1, stale), UnitStatus::Available);
+        assert_eq!(status(1, fresh), UnitStatus::Reserved);
+        assert_eq!(status(2, expired), UnitStatus::Discarded);
+        // each hospital lists its own events
+        let listed = |hospital_id| -> Vec<ExpiryEventKind> {
+            event_page(hospital_id, &ListOptions::default())
+                .unwrap()
+                .items
+                .iter()
+                .map(|event| event.kind)
+                .collect()
+        };
+        assert_eq!(
+            listed(Some(HospitalId(2))),
+            [ExpiryEventKind::UnitExpired { unit_id: expired }]
+        );
+        assert_eq!(listed(Some(HospitalId(1))).len(), 1);
+        assert_eq!(listed(None), events());
+    }
+
+    #[test]
+    fn settings_are_bounded() {
+        for (pledge_window_days, reservation_hold_hours) in [(0, 48), (366, 48), (14, 721)] {
+            assert!(set_expiry_settings(ExpirySettings {
+                pledge_window_days,
+                reservation_hold_hours,
+            })
+            .is_err());
+        }
+        let longest = ExpirySettings {
+            pledge_window_days: MAX_PLEDGE_WINDOW_DAYS,
+            reservation_hold_hours: MAX_RESERVATION_HOLD_HOURS,
+        };
+        assert_eq!(set_expiry_settings(longest).ok(), Some(longest));
+    }
+}
@@ -0,0 +1,301 @@
+use crate::blood_group::{BloodGroup, TYPED_GROUPS};
+use crate::ids::{HospitalId, PatientId};
+use crate::indexes::{self, IndexText};
+use crate::schema;
+use crate::{
+    Error, Hospital, HOSPITAL_CITY_INDEX, HOSPITAL_GEO_INDEX, HOSPITAL_STORAGE,
+    PATIENT_GROUP_INDEX, PATIENT_STORAGE,
+};
+use std::collections::BTreeSet;
+
+// Bits of the geohashes kept in the index, half for the longitude and half for the latitude,
+// giving cells of about 5 by 5 metres at the equator
+const GEOHASH_BITS: u32 = 52;
+const EARTH_RADIUS_KM: f64 = 6371.0088;
+// Length of a degree of latitude
+const KM_PER_DEGREE: f64 = 111.32;
+// Widest search, half the circumference of the earth
+const MAX_RADIUS_KM: f64 = 20_000.0;
+// Most hospitals returned by a single search
+const MAX_NEARBY_HOSPITALS: usize = 100;
+
+// Position of a hospital in decimal degrees
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Debug, Default)]
+pub(crate) struct GeoPoint {
+    pub(crate) latitude: f64,
+    pub(crate) longitude: f64,
+}
+
+impl GeoPoint {
+    pub(crate) fn check(self) -> Result<(), Error> {
+        if !(-90.0..=90.0).contains(&self.latitude) || !(-180.0..=180.0).contains(&self.longitude) {
+            return Err(Error::InvalidPayload {
+                msg: format!(
+                    "latitude {} and longitude {} must be within ±90 and ±180 degrees",
+                    self.latitude, self.longitude
+                ),
+            });
+        }
+        Ok(())
+    }
+
+    // Great-circle distance in kilometres
+    pub(crate) fn distance_km(self, other: GeoPoint) -> f64 {
+        let (lat1, lat2) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let dlat = lat2 - lat1;
+        let dlon = (other.longitude - self.longitude).to_radians();
+        let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_KM * a.sqrt().min(1.0).asin()
+    }
+}
+
+// A hospital found near a point with its distance from it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct NearbyHospital {
+    hospital: Hospital,
+    distance_km: f64,
+}
+
+// Geohash of a point: the halves of the longitude and latitude ranges holding it, alternating
+// and starting with the longitude, one bit per halving
+pub(crate) fn geohash(point: GeoPoint) -> u64 {
+    let (mut longitude, mut latitude) = ((-180.0, 180.0), (-90.0, 90.0));
+    let mut hash = 0;
+    for bit in 0..GEOHASH_BITS {
+        let (range, value): (&mut (f64, f64), f64) = match bit % 2 {
+            0 => (&mut longitude, point.longitude),
+            _ => (&mut latitude, point.latitude),
+        };
+        let middle = (range.0 + range.1) / 2.0;
+        hash <<= 1;
+        if value >= middle {
+            hash |= 1;
+            range.0 = middle;
+        } else {
+            range.1 = middle;
+        }
+    }
+    hash
+}
+
+// Most geohash bits, counted in pairs, whose cells are at least `radius_km` high and wide
+// everywhere within the radius, so a circle is covered by the cell of its centre and the eight
+// cells around it
+fn cell_bits(center: GeoPoint, radius_km: f64) -> u32 {
+    let farthest_latitude = (center.latitude.abs() + radius_km / KM_PER_DEGREE).min(90.0);
+    let narrowest = farthest_latitude.to_radians().cos();
+    (0..=GEOHASH_BITS / 2)
+        .rev()
+        .map(|pairs| pairs * 2)
+        .find(|bits| {
+            let cells = f64::from(1u32 << (bits / 2));
+            let height = 180.0 / cells * KM_PER_DEGREE;
+            let width = 360.0 / cells * KM_PER_DEGREE * narrowest;
+            height >= radius_km && width >= radius_km
+        })
+        .unwrap_or(0)
+}
+
+// Ranges of indexed geohashes covering a circle
+fn covering_ranges(center: GeoPoint, radius_km: f64) -> Vec<(u64, u64)> {
+    let bits = cell_bits(center, radius_km);
+    let shift = GEOHASH_BITS - bits;
+    let cells = f64::from(1u32 << (bits / 2));
+    let (height, width) = (180.0 / cells, 360.0 / cells);
+    let mut prefixes = BTreeSet::new();
+    for dlat in [-1.0, 0.0, 1.0] {
+        let latitude = center.latitude + dlat * height;
+        if !(-90.0..=90.0).contains(&latitude) {
+            continue;
+        }
+        for dlon in [-1.0, 0.0, 1.0] {
+            // wrap around the antimeridian
+            let longitude = (center.longitude + dlon * width + 540.0).rem_euclid(360.0) - 180.0;
+            prefixes.insert(
+                geohash(GeoPoint {
+                    latitude,
+                    longitude,
+                }) >> shift,
+            );
+        }
+    }
+    prefixes
+        .into_iter()
+        .map(|prefix| (prefix << shift, (prefix + 1) << shift))
+        .collect()
+}
+
+// Hospitals within `radius_km` of a point, nearest first
+pub(crate) fn hospitals_near(center: GeoPoint, radius_km: f64) -> Vec<(Hospital, f64)> {
+    let mut found: Vec<(Hospital, f64)> = HOSPITAL_GEO_INDEX.with(|s| {
+        let index = s.borrow();
+        covering_ranges(center, radius_km)
+            .into_iter()
+            .flat_map(|(start, end)| {
+                index
+                    .range((start, HospitalId::default())..)
+                    .take_while(move |((hash, _), _)| *hash < end)
+                    .map(|((_, id), _)| id)
+                    .collect::<Vec<_>>()
+            })
+            .filter_map(|id| HOSPITAL_STORAGE.with(|s| s.borrow().get(&id)))
+            .filter_map(|hospital| {
+                let distance = center.distance_km(hospital.location?);
+                (distance <= radius_km).then_some((hospital, distance))
+            })
+            .collect()
+    });
+    found.sort_by(|(a, a_distance), (b, b_distance)| {
+        a_distance.total_cmp(b_distance).then(a.id.cmp(&b.id))
+    });
+    found
+}
+
+// Centre of the located hospitals of a city, standing in for places known only by their city
+pub(crate) fn city_location(city: &IndexText) -> Option<GeoPoint> {
+    if city.as_str().is_empty() {
+        return None;
+    }
+    let points: Vec<GeoPoint> = indexes::scan(&HOSPITAL_CITY_INDEX, city, |ids| {
+        ids.filter_map(|id| HOSPITAL_STORAGE.with(|s| s.borrow().get(&id)))
+            .filter_map(|hospital| hospital.location)
+            .collect()
+    });
+    if points.is_empty() {
+        return None;
+    }
+    let count = points.len() as f64;
+    Some(GeoPoint {
+        latitude: points.iter().map(|point| point.latitude).sum::<f64>() / count,
+        longitude: points.iter().map(|point| point.longitude).sum::<f64>() / count,
+    })
+}
+
+// Hospitals with an open patient who can receive red cells from a donor of the group
+fn hospitals_needing(group: BloodGroup) -> BTreeSet<HospitalId> {
+    let mut hospitals = BTreeSet::new();
+    for recipient in TYPED_GROUPS.into_iter().chain([BloodGroup::Unknown]) {
+        if !group.can_donate_to(recipient) {
+            continue;
+        }
+        let patients: Vec<PatientId> = indexes::scan(&PATIENT_GROUP_INDEX, &recipient, |ids| {
+            ids.filter(|id| indexes::is_open(*id)).collect()
+        });
+        hospitals.extend(patients.into_iter().filter_map(|id| {
+            PATIENT_STORAGE
+                .with(|s| s.borrow().get(&id))
+                .and_then(|patient| patient.hospital_id)
+        }));
+    }
+    hospitals
+}
+
+// list the hospitals within `radius_km` of a point, nearest first, optionally only those with
+// open patients who can receive blood of a group
+#[ic_cdk::query]
+fn find_hospitals_near(
+    latitude: f64,
+    longitude: f64,
+    radius_km: f64,
+    blood_group: Option<String>,
+) -> Result<Vec<NearbyHospital>, Error> {
+    schema::check_migrated()?;
+    let center = GeoPoint {
+        latitude,
+        longitude,
+    };
+    center.check()?;
+    if !(radius_km > 0.0 && radius_km <= MAX_RADIUS_KM) {
+        return Err(Error::InvalidPayload {
+            msg: format!("radius must be above 0 and at most {} km", MAX_RADIUS_KM),
+        });
+    }
+    let needing = match blood_group {
+        Some(group) => Some(hospitals_needing(group.parse()?)),
+        None => None,
+    };
+    Ok(hospitals_near(center, radius_km)
+        .into_iter()
+        .filter(|(hospital, _)| {
+            needing
+                .as_ref()
+                .is_none_or(|needing| needing.contains(&hospital.id))
+        })
+        .take(MAX_NEARBY_HOSPITALS)
+        .map(|(hospital, distance_km)| NearbyHospital {
+            hospital: Hospital {
+                password: "******".to_string(),
+                ..hospital
+            },
+            distance_km,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAIROBI: GeoPoint = GeoPoint {
+        latitude: -1.2864,
+        longitude: 36.8172,
+    };
+
+    fn hospital(id: u64, latitude: f64, longitude: f64) {
+        indexes::store_hospital(Hospital {
+            id: HospitalId(id),
+            location: Some(GeoPoint {
+                latitude,
+                longitude,
+            }),
+            ..Default::default()
+        });
+    }
+
+    fn near(center: GeoPoint, radius_km: f64) -> Vec<u64> {
+        hospitals_near(center, radius_km)
+            .into_iter()
+            .map(|(hospital, _)| hospital.id.0)
+            .collect()
+    }
+
+    #[test]
+    fn distances_follow_the_great_circle() {
+        let mombasa = GeoPoint {
+            latitude: -4.0435,
+            longitude: 39.6682,
+        };
+        let distance = NAIROBI.distance_km(mombasa);
+        assert!((distance - 440.0).abs() < 5.0, "{}", distance);
+        assert_eq!(NAIROBI.distance_km(NAIROBI), 0.0);
+    }
+
+    #[test]
+    fn nearest_hospitals_come_first() {
+        // Kenyatta, about 3 km from the centre, and Thika, about 40 km away
+        hospital(1, -1.3013, 36.8070);
+        hospital(2, -1.0388, 37.0834);
+        hospital(3, -4.0435, 39.6682);
+        // across the antimeridian from each other
+        hospital(4, 0.0, 179.99);
+        hospital(5, 0.0, -179.99);
+
+        assert_eq!(near(NAIROBI, 10.0), [1]);
+        assert_eq!(near(NAIROBI, 50.0), [1, 2]);
+        assert_eq!(near(NAIROBI, 1000.0), [1, 2, 3]);
+        assert_eq!(
+            near(
+                GeoPoint {
+                    latitude: 0.0,
+                    longitude: 179.995
+                },
+                5.0
+            ),
+            [4, 5]
+        );
+
+        // moving a hospital moves its index entry
+        hospital(2, -4.05, 39.67);
+        assert_eq!(near(NAIROBI, 50.0), [1]);
+    }
+}
@@ -0,0 +1,149 @@
+use crate::{
+    IdCell, DEFERRAL_ID_SEQUENCE, DONOR_ID_SEQUENCE, EXPIRY_EVENT_ID_SEQUENCE,
+    HOSPITAL_ID_SEQUENCE, ID_COUNTER, PATIENT_ID_SEQUENCE, PLEDGE_ID_SEQUENCE, UNIT_ID_SEQUENCE,
+};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::fmt;
+use std::thread::LocalKey;
+
+// Ids of hospitals, patients, donors and pledges, each handed out by a sequence of its own. They
+// travel as nat64 in candid and are stored as u64, so records and keys written with plain ids
+// read back unchanged.
+macro_rules! record_id {
+    ($name:ident, $sequence:ident) => {
+        #[derive(
+            candid::CandidType,
+            Clone,
+            Copy,
+            Serialize,
+            Deserialize,
+            PartialEq,
+            Eq,
+            PartialOrd,
+            Ord,
+            Hash,
+            Debug,
+            Default,
+        )]
+        pub(crate) struct $name(pub(crate) u64);
+
+        impl $name {
+            // Take the next id of the sequence
+            pub(crate) fn next() -> Self {
+                $name(next_in(&$sequence))
+            }
+        }
+
+        impl RecordId for $name {
+            fn successor(self) -> Option<Self> {
+                self.0.checked_add(1).map($name)
+            }
+        }
+
+        // Migrations go through every storage with the plain u64 of the id as their cursor
+        impl From<u64> for $name {
+            fn from(id: u64) -> Self {
+                $name(id)
+            }
+        }
+
+        impl From<$name> for u64 {
+            fn from(id: $name) -> Self {
+                id.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+
+        impl Storable for $name {
+            // Conversion to bytes, the same as the u64 it wraps
+            fn to_bytes(&self) -> Cow<'_, [u8]> {
+                Cow::Owned(self.0.to_be_bytes().to_vec())
+            }
+            // Conversion from bytes
+            fn from_bytes(bytes: Cow<[u8]>) -> Self {
+                $name(u64::from_bytes(bytes))
+            }
+        }
+
+        impl BoundedStorable for $name {
+            const MAX_SIZE: u32 = u64::MAX_SIZE;
+            const IS_FIXED_SIZE: bool = u64::IS_FIXED_SIZE;
+        }
+    };
+}
+
+// Ids usable as keys of range scans, the default id sorts before every other
+pub(crate) trait RecordId: BoundedStorable + Ord + Copy + Default {
+    fn successor(self) -> Option<Self>;
+}
+
+record_id!(HospitalId, HOSPITAL_ID_SEQUENCE);
+record_id!(PatientId, PATIENT_ID_SEQUENCE);
+record_id!(DonorId, DONOR_ID_SEQUENCE);
+record_id!(PledgeId, PLEDGE_ID_SEQUENCE);
+
+type Sequence = LocalKey<RefCell<IdCell>>;
+
+const SEQUENCES: [&Sequence; 7] = [
+    &HOSPITAL_ID_SEQUENCE,
+    &PATIENT_ID_SEQUENCE,
+    &DONOR_ID_SEQUENCE,
+    &PLEDGE_ID_SEQUENCE,
+    &UNIT_ID_SEQUENCE,
+    &DEFERRAL_ID_SEQUENCE,
+    &EXPIRY_EVENT_ID_SEQUENCE,
+];
+
+// Take the next id of a sequence, for records whose ids are plain u64
+pub(crate) fn next_in(sequence: &'static Sequence) -> u64 {
+    sequence
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids")
+}
+
+// Start every sequence where the counter shared by all records before the split stopped, so the
+// ids already handed out are kept and none of them is given out again
+pub(crate) fn split_shared_sequence() {
+    let shared = ID_COUNTER.with(|counter| *counter.borrow().get());
+    for sequence in SEQUENCES {
+        sequence.with(|counter| {
+            let current_id = *counter.borrow().get();
+            if current_id < shared {
+                counter
+                    .borrow_mut()
+                    .set(shared)
+                    .expect("Cannot store the id sequence");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequences_continue_after_the_shared_counter() {
+        ID_COUNTER.with(|counter| counter.borrow_mut().set(12).unwrap());
+        split_shared_sequence();
+        assert_eq!(HospitalId::next(), HospitalId(12));
+        assert_eq!(PatientId::next(), PatientId(12));
+        assert_eq!(PatientId::next(), PatientId(13));
+        assert_eq!(DonorId::next(), DonorId(12));
+        assert_eq!(next_in(&UNIT_ID_SEQUENCE), 12);
+        assert_eq!(next_in(&EXPIRY_EVENT_ID_SEQUENCE), 12);
+        // the split only ever moves a sequence forward
+        split_shared_sequence();
+        assert_eq!(PatientId::next(), PatientId(14));
+    }
+}
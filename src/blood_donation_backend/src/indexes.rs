@@ -0,0 +1,297 @@
+use crate::geo;
+use crate::ids::{PatientId, RecordId};
+use crate::schema;
+use crate::search;
+use crate::{
+    normalize_name, Donor, Hospital, Memory, Patient, DONOR_GROUP_INDEX, DONOR_STORAGE,
+    HOSPITAL_CITY_INDEX, HOSPITAL_GEO_INDEX, HOSPITAL_STORAGE, PATIENT_GROUP_INDEX,
+    PATIENT_HOSPITAL_INDEX, PATIENT_STATUS_INDEX, PATIENT_STORAGE,
+};
+use ic_stable_structures::{BoundedStorable, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
+// Longest text kept in an index key, longer texts are cut and told apart by their records
+const MAX_INDEX_TEXT: usize = 64;
+
+// Completion status of a patient in the status index
+pub(crate) const OPEN: u8 = 0;
+pub(crate) const COMPLETE: u8 = 1;
+
+// Records keyed by (indexed value, record id)
+pub(crate) type SecondaryIndex<K, I> = LocalKey<RefCell<StableBTreeMap<(K, I), (), Memory>>>;
+
+// Values records can be indexed by
+pub(crate) trait IndexKey: BoundedStorable + Default + Ord + Clone {}
+
+impl<K: BoundedStorable + Default + Ord + Clone> IndexKey for K {}
+
+// Normalized text used as an index key: lowercase words without punctuation
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub(crate) struct IndexText(String);
+
+impl IndexText {
+    pub(crate) fn new(text: &str) -> Self {
+        IndexText::cut(normalize_name(text))
+    }
+
+    // Keep text already normalized, cut to the longest text an index key holds
+    pub(crate) fn cut(mut text: String) -> Self {
+        if text.len() > MAX_INDEX_TEXT {
+            let end = (0..=MAX_INDEX_TEXT)
+                .rev()
+                .find(|end| text.is_char_boundary(*end))
+                .unwrap_or(0);
+            text.truncate(end);
+        }
+        IndexText(text)
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Storable for IndexText {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        IndexText(String::from_utf8(bytes.into_owned()).expect("index text is utf-8"))
+    }
+}
+
+impl BoundedStorable for IndexText {
+    const MAX_SIZE: u32 = MAX_INDEX_TEXT as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+fn insert<K: IndexKey, I: RecordId>(index: &'static SecondaryIndex<K, I>, key: K, id: I) {
+    index.with(|s| s.borrow_mut().insert((key, id), ()));
+}
+
+// Move a record from the key it was indexed under to its current one, None when it is not
+// indexed
+fn reindex<K: IndexKey, I: RecordId>(
+    index: &'static SecondaryIndex<K, I>,
+    previous: Option<K>,
+    current: Option<K>,
+    id: I,
+) {
+    if previous == current {
+        return;
+    }
+    if let Some(previous) = previous {
+        index.with(|s| s.borrow_mut().remove(&(previous, id)));
+    }
+    if let Some(current) = current {
+        insert(index, current, id);
+    }
+}
+
+// Read the ids of the records indexed under a key, in ascending order
+pub(crate) fn scan<K, I, R>(
+    index: &'static SecondaryIndex<K, I>,
+    key: &K,
+    read: impl FnOnce(&mut dyn Iterator<Item = I>) -> R,
+) -> R
+where
+    K: IndexKey,
+    I: RecordId,
+{
+    index.with(|s| {
+        let index = s.borrow();
+        let mut ids = index
+            .range((key.clone(), I::default())..)
+            .take_while(|((indexed, _), _)| indexed == key)
+            .map(|((_, id), _)| id);
+        read(&mut ids)
+    })
+}
+
+fn completion(patient: &Patient) -> u8 {
+    match patient.is_complete {
+        true => COMPLETE,
+        false => OPEN,
+    }
+}
+
+// Store a hospital and keep its index entries in step, returns the hospital it replaced
+pub(crate) fn store_hospital(hospital: Hospital) -> Option<Hospital> {
+    let city = IndexText::new(&hospital.city);
+    let cell = hospital.location.map(geo::geohash);
+    let postings = search::hospital_postings(&hospital);
+    let id = hospital.id;
+    let previous = HOSPITAL_STORAGE.with(|s| s.borrow_mut().insert(id, hospital));
+    let previous_city = previous.as_ref().map(|h| IndexText::new(&h.city));
+    reindex(&HOSPITAL_CITY_INDEX, previous_city, Some(city), id);
+    let previous_cell = previous.as_ref().and_then(|h| h.location).map(geo::geohash);
+    reindex(&HOSPITAL_GEO_INDEX, previous_cell, cell, id);
+    let previous_postings = previous.as_ref().map(search::hospital_postings);
+    search::update_postings(previous_postings.unwrap_or_default(), postings);
+    previous
+}
+
+// Store a patient and keep its index entries in step, returns the patient it replaced
+pub(crate) fn store_patient(patient: Patient) -> Option<Patient> {
+    let (group, status) = (patient.blood_group, completion(&patient));
+    let hospital = patient.hospital_id;
+    let postings = search::patient_postings(&patient);
+    let id = patient.id;
+    let previous = PATIENT_STORAGE.with(|s| s.borrow_mut().insert(id, patient));
+    let previous_group = previous.as_ref().map(|p| p.blood_group);
+    let previous_status = previous.as_ref().map(completion);
+    reindex(&PATIENT_GROUP_INDEX, previous_group, Some(group), id);
+    reindex(&PATIENT_STATUS_INDEX, previous_status, Some(status), id);
+    let previous_hospital = previous.as_ref().and_then(|p| p.hospital_id);
+    reindex(&PATIENT_HOSPITAL_INDEX, previous_hospital, hospital, id);
+    let previous_postings = previous.as_ref().map(search::patient_postings);
+    search::update_postings(previous_postings.unwrap_or_default(), postings);
+    previous
+}
+
+// Store a donor and keep its index entries in step, returns the donor it replaced
+pub(crate) fn store_donor(donor: Donor) -> Option<Donor> {
+    let group = donor.blood_group;
+    let id = donor.id;
+    let previous = DONOR_STORAGE.with(|s| s.borrow_mut().insert(id, donor));
+    let previous_group = previous.as_ref().map(|d| d.blood_group);
+    reindex(&DONOR_GROUP_INDEX, previous_group, Some(group), id);
+    previous
+}
+
+// Index a batch of stored hospitals by city
+pub(crate) fn index_hospitals(after: Option<u64>, limit: usize) -> Option<u64> {
+    let (hospitals, next) = schema::batch(&HOSPITAL_STORAGE, after, limit);
+    for hospital in hospitals {
+        insert(
+            &HOSPITAL_CITY_INDEX,
+            IndexText::new(&hospital.city),
+            hospital.id,
+        );
+    }
+    next
+}
+
+// Index a batch of stored patients by blood group, completion and hospital
+pub(crate) fn index_patients(after: Option<u64>, limit: usize) -> Option<u64> {
+    let (patients, next) = schema::batch(&PATIENT_STORAGE, after, limit);
+    for patient in patients {
+        insert(&PATIENT_GROUP_INDEX, patient.blood_group, patient.id);
+        insert(&PATIENT_STATUS_INDEX, completion(&patient), patient.id);
+        if let Some(hospital_id) = patient.hospital_id {
+            insert(&PATIENT_HOSPITAL_INDEX, hospital_id, patient.id);
+        }
+    }
+    next
+}
+
+// Index a batch of stored donors by blood group
+pub(crate) fn index_donors(after: Option<u64>, limit: usize) -> Option<u64> {
+    let (donors, next) = schema::batch(&DONOR_STORAGE, after, limit);
+    for donor in donors {
+        insert(&DONOR_GROUP_INDEX, donor.blood_group, donor.id);
+    }
+    next
+}
+
+// Whether a patient is indexed as still needing donations
+pub(crate) fn is_open(id: PatientId) -> bool {
+    PATIENT_STATUS_INDEX.with(|s| s.borrow().contains_key(&(OPEN, id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blood_group::BloodGroup;
+    use crate::ids::{DonorId, HospitalId};
+
+    fn ids<K: IndexKey, I: RecordId>(index: &'static SecondaryIndex<K, I>, key: K) -> Vec<I> {
+        scan(index, &key, |ids| ids.collect())
+    }
+
+    #[test]
+    fn indexes_follow_every_store() {
+        for (id, city) in [(1, "Nairobi"), (2, " NAIROBI, "), (3, "Mombasa")] {
+            store_hospital(Hospital {
+                id: HospitalId(id),
+                city: city.to_string(),
+                ..Default::default()
+            });
+        }
+        let nairobi = IndexText::new("nairobi");
+        assert_eq!(
+            ids(&HOSPITAL_CITY_INDEX, nairobi.clone()),
+            [HospitalId(1), HospitalId(2)]
+        );
+        store_hospital(Hospital {
+            id: HospitalId(2),
+            city: "Kisumu".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(ids(&HOSPITAL_CITY_INDEX, nairobi), [HospitalId(1)]);
+
+        let mut patient = Patient {
+            id: PatientId(4),
+            blood_group: BloodGroup::APositive,
+            needed_pints: 1,
+            ..Default::default()
+        };
+        store_patient(patient.clone());
+        assert!(is_open(PatientId(4)));
+        assert!(ids(&PATIENT_HOSPITAL_INDEX, HospitalId(1)).is_empty());
+        patient.hospital_id = Some(HospitalId(1));
+        store_patient(patient.clone());
+        assert_eq!(ids(&PATIENT_HOSPITAL_INDEX, HospitalId(1)), [PatientId(4)]);
+        patient.is_complete = true;
+        patient.blood_group = BloodGroup::ONegative;
+        patient.hospital_id = Some(HospitalId(3));
+        store_patient(patient);
+        assert!(ids(&PATIENT_HOSPITAL_INDEX, HospitalId(1)).is_empty());
+        assert_eq!(ids(&PATIENT_HOSPITAL_INDEX, HospitalId(3)), [PatientId(4)]);
+        assert!(!is_open(PatientId(4)));
+        assert_eq!(ids(&PATIENT_STATUS_INDEX, COMPLETE), [PatientId(4)]);
+        assert!(ids(&PATIENT_GROUP_INDEX, BloodGroup::APositive).is_empty());
+        assert_eq!(
+            ids(&PATIENT_GROUP_INDEX, BloodGroup::ONegative),
+            [PatientId(4)]
+        );
+    }
+
+    #[test]
+    fn stored_records_are_indexed_on_upgrade() {
+        PATIENT_STORAGE.with(|s| {
+            s.borrow_mut().insert(
+                PatientId(1),
+                Patient {
+                    id: PatientId(1),
+                    blood_group: BloodGroup::BNegative,
+                    hospital_id: Some(HospitalId(5)),
+                    ..Default::default()
+                },
+            )
+        });
+        DONOR_STORAGE.with(|s| {
+            s.borrow_mut().insert(
+                DonorId(2),
+                Donor {
+                    id: DonorId(2),
+                    blood_group: BloodGroup::Unknown,
+                    ..Default::default()
+                },
+            )
+        });
+        index_hospitals(None, 10);
+        index_patients(None, 10);
+        index_donors(None, 10);
+        assert!(is_open(PatientId(1)));
+        assert_eq!(
+            ids(&PATIENT_GROUP_INDEX, BloodGroup::BNegative),
+            [PatientId(1)]
+        );
+        assert_eq!(ids(&PATIENT_HOSPITAL_INDEX, HospitalId(5)), [PatientId(1)]);
+        assert_eq!(ids(&DONOR_GROUP_INDEX, BloodGroup::Unknown), [DonorId(2)]);
+    }
+}
@@ -0,0 +1,659 @@
+use crate::blood_group::{BloodGroup, TYPED_GROUPS};
+use crate::components::Component;
+use crate::ids::{self, HospitalId, PatientId, PledgeId};
+use crate::pagination::{self, ListOptions, Listed, Page};
+use crate::roles::{self, caller_is_verified_hospital_staff, Role};
+use crate::staff::{self, StaffAction, StaffPermission};
+use crate::{
+    caller_principal, now, Error, HOSPITAL_STORAGE, INVENTORY_STORAGE, PATIENT_STORAGE,
+    UNIT_ID_SEQUENCE,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+use std::ops::Bound;
+use validator::Validate;
+
+const DAY: u64 = 86_400_000_000_000;
+const MAX_LABEL_LENGTH: u64 = 32;
+
+// New units wait in quarantine until their screening tests come back
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) enum UnitStatus {
+    Quarantined,
+    Available,
+    Reserved,
+    Issued,
+    Discarded,
+}
+
+// A unit of a component in the blood bank of a hospital. `label` is the bag label written by
+// the staff, `pledge_id` links units received from a confirmed donation.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct BloodUnit {
+    pub(crate) id: u64,
+    pub(crate) hospital_id: HospitalId,
+    label: String,
+    component: Component,
+    blood_group: BloodGroup,
+    collected_at: u64,
+    expires_at: u64,
+    pub(crate) status: UnitStatus,
+    pub(crate) pledge_id: Option<PledgeId>,
+    reserved_for: Option<PatientId>,
+    pub(crate) discard_reason: Option<String>,
+    pub(crate) updated_at: u64,
+}
+
+impl Listed for BloodUnit {
+    type Cursor = u64;
+    const NAME: &'static str = "units";
+
+    fn cursor(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Storable for BloodUnit {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for BloodUnit {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// `expires_at` defaults to the shelf life of the component counted from `collected_at`
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct ReceiveUnitPayload {
+    hospital_id: HospitalId,
+    #[validate(length(min = 1, max = "MAX_LABEL_LENGTH"))]
+    label: String,
+    component: Component,
+    blood_group: String,
+    collected_at: u64,
+    expires_at: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct DiscardUnitPayload {
+    hospital_id: HospitalId,
+    unit_id: u64,
+    #[validate(length(min = 3, max = 200))]
+    reason: String,
+}
+
+// Units of a group and component in stock, `expired` counts units past their expiry that
+// were neither issued nor discarded
+#[derive(candid::CandidType, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) struct StockLevel {
+    blood_group: BloodGroup,
+    component: Component,
+    quarantined: u32,
+    available: u32,
+    reserved: u32,
+    expired: u32,
+}
+
+// Days a unit of a component keeps once collected
+fn shelf_life_days(component: Component) -> u64 {
+    match component {
+        Component::WholeBlood => 35,
+        Component::PackedRedCells => 42,
+        Component::Platelets => 5,
+        Component::Plasma | Component::Cryoprecipitate => 365,
+    }
+}
+
+fn get_unit(hospital_id: HospitalId, unit_id: u64) -> Result<BloodUnit, Error> {
+    INVENTORY_STORAGE
+        .with(|s| s.borrow().get(&(hospital_id, unit_id)))
+        .ok_or(Error::NotFound {
+            msg: format!(
+                "unit of id: {} not found at hospital id: {}",
+                unit_id, hospital_id
+            ),
+        })
+}
+
+fn units_of(hospital_id: HospitalId) -> Vec<BloodUnit> {
+    INVENTORY_STORAGE.with(|s| {
+        s.borrow()
+            .range((hospital_id, 0)..)
+            .take_while(|((id, _), _)| *id == hospital_id)
+            .map(|(_, unit)| unit)
+            .collect()
+    })
+}
+
+// Refuse repeated labels and labels already used by a unit of the hospital
+pub(crate) fn check_new_labels(hospital_id: HospitalId, labels: &[String]) -> Result<(), Error> {
+    if let Some(label) = labels
+        .iter()
+        .enumerate()
+        .find_map(|(i, label)| labels[..i].contains(label).then_some(label))
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!("unit label {} is listed more than once", label),
+        });
+    }
+    match units_of(hospital_id)
+        .into_iter()
+        .find(|unit| labels.contains(&unit.label))
+    {
+        Some(unit) => Err(Error::AlreadyInit {
+            msg: format!(
+                "a unit labelled {} is already in the inventory of hospital id: {}",
+                unit.label, hospital_id
+            ),
+        }),
+        None => Ok(()),
+    }
+}
+
+// Store a new quarantined unit, the caller has checked the label is free
+pub(crate) fn add_unit(
+    hospital_id: HospitalId,
+    label: String,
+    component: Component,
+    blood_group: BloodGroup,
+    collected_at: u64,
+    pledge_id: Option<PledgeId>,
+) -> BloodUnit {
+    let id = ids::next_in(&UNIT_ID_SEQUENCE);
+    let unit = BloodUnit {
+        id,
+        hospital_id,
+        label,
+        component,
+        blood_group,
+        collected_at,
+        expires_at: collected_at + shelf_life_days(component) * DAY,
+        status: UnitStatus::Quarantined,
+        pledge_id,
+        reserved_for: None,
+        discard_reason: None,
+        updated_at: collected_at,
+    };
+    INVENTORY_STORAGE.with(|s| s.borrow_mut().insert((hospital_id, id), unit.clone()));
+    unit
+}
+
+fn save_unit(unit: BloodUnit, status: UnitStatus) -> BloodUnit {
+    let unit = BloodUnit {
+        status,
+        updated_at: now(),
+        ..unit
+    };
+    INVENTORY_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert((unit.hospital_id, unit.id), unit.clone())
+    });
+    unit
+}
+
+fn set_unit_status(caller: Principal, unit: BloodUnit, status: UnitStatus) -> BloodUnit {
+    let unit = save_unit(unit, status);
+    staff::record_activity(
+        unit.hospital_id,
+        caller,
+        StaffAction::ChangedUnit {
+            unit_id: unit.id,
+            status,
+        },
+    );
+    unit
+}
+
+fn check_unit_status(unit: &BloodUnit, allowed: &[UnitStatus]) -> Result<(), Error> {
+    if allowed.contains(&unit.status) {
+        return Ok(());
+    }
+    Err(Error::InvalidPayload {
+        msg: format!(
+            "unit of id: {} is {:?}, expected one of {:?}",
+            unit.id, unit.status, allowed
+        ),
+    })
+}
+
+fn check_not_expired(unit: &BloodUnit) -> Result<(), Error> {
+    if unit.expires_at <= now() {
+        return Err(Error::InvalidPayload {
+            msg: format!("unit of id: {} expired at {}", unit.id, unit.expires_at),
+        });
+    }
+    Ok(())
+}
+
+fn receive(caller: Principal, payload: ReceiveUnitPayload) -> Result<BloodUnit, Error> {
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    staff::require_permission(
+        &caller,
+        payload.hospital_id,
+        StaffPermission::ManageInventory,
+    )?;
+    let blood_group: BloodGroup = payload.blood_group.parse()?;
+    if blood_group == BloodGroup::Unknown {
+        return Err(Error::InvalidPayload {
+            msg: "units must be typed before they are received".to_string(),
+        });
+    }
+    if payload.collected_at > now() {
+        return Err(Error::InvalidPayload {
+            msg: "collection date cannot be in the future".to_string(),
+        });
+    }
+    if payload
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= payload.collected_at)
+    {
+        return Err(Error::InvalidPayload {
+            msg: "expiry date must be after the collection date".to_string(),
+        });
+    }
+    check_new_labels(payload.hospital_id, std::slice::from_ref(&payload.label))?;
+
+    let unit = add_unit(
+        payload.hospital_id,
+        payload.label,
+        payload.component,
+        blood_group,
+        payload.collected_at,
+        None,
+    );
+    let unit = BloodUnit {
+        expires_at: payload.expires_at.unwrap_or(unit.expires_at),
+        ..unit
+    };
+    Ok(set_unit_status(caller, unit, UnitStatus::Quarantined))
+}
+
+fn release(caller: Principal, hospital_id: HospitalId, unit_id: u64) -> Result<BloodUnit, Error> {
+    staff::require_permission(&caller, hospital_id, StaffPermission::ManageInventory)?;
+    let unit = get_unit(hospital_id, unit_id)?;
+    check_unit_status(&unit, &[UnitStatus::Quarantined])?;
+    check_not_expired(&unit)?;
+    Ok(set_unit_status(caller, unit, UnitStatus::Available))
+}
+
+// Hold an available unit, for a patient when one is given
+fn reserve(
+    caller: Principal,
+    hospital_id: HospitalId,
+    unit_id: u64,
+    patient_id: Option<PatientId>,
+) -> Result<BloodUnit, Error> {
+    staff::require_permission(&caller, hospital_id, StaffPermission::ManageInventory)?;
+    let unit = get_unit(hospital_id, unit_id)?;
+    check_unit_status(&unit, &[UnitStatus::Available])?;
+    check_not_expired(&unit)?;
+    if let Some(patient_id) = patient_id {
+        let patient = PATIENT_STORAGE
+            .with(|s| s.borrow().get(&patient_id))
+            .ok_or(Error::NotFound {
+                msg: format!("patient of id: {} not found", patient_id),
+            })?;
+        if !unit
+            .component
+            .compatible(unit.blood_group, patient.blood_group)
+        {
+            return Err(Error::IncompatibleBloodGroup {
+                msg: format!(
+                    "{:?} of group {} cannot be given to patient of id: {} with group {}",
+                    unit.component, unit.blood_group, patient_id, patient.blood_group
+                ),
+            });
+        }
+    }
+    let unit = BloodUnit {
+        reserved_for: patient_id,
+        ..unit
+    };
+    Ok(set_unit_status(caller, unit, UnitStatus::Reserved))
+}
+
+fn issue(caller: Principal, hospital_id: HospitalId, unit_id: u64) -> Result<BloodUnit, Error> {
+    staff::require_permission(&caller, hospital_id, StaffPermission::ManageInventory)?;
+    let unit = get_unit(hospital_id, unit_id)?;
+    check_unit_status(&unit, &[UnitStatus::Available, UnitStatus::Reserved])?;
+    check_not_expired(&unit)?;
+    Ok(set_unit_status(caller, unit, UnitStatus::Issued))
+}
+
+fn discard(caller: Principal, payload: DiscardUnitPayload) -> Result<BloodUnit, Error> {
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    staff::require_permission(
+        &caller,
+        payload.hospital_id,
+        StaffPermission::ManageInventory,
+    )?;
+    let unit = get_unit(payload.hospital_id, payload.unit_id)?;
+    check_unit_status(
+        &unit,
+        &[
+            UnitStatus::Quarantined,
+            UnitStatus::Available,
+            UnitStatus::Reserved,
+        ],
+    )?;
+    let unit = BloodUnit {
+        discard_reason: Some(payload.reason),
+        ..unit
+    };
+    Ok(set_unit_status(caller, unit, UnitStatus::Discarded))
+}
+
+// Discard the units of a collected pledge still in stock beyond the first `keep`, latest first,
+// when the collection is cancelled or amended down. Issued units have left the bank and are kept.
+pub(crate) fn discard_pledge_units(
+    caller: Principal,
+    hospital_id: HospitalId,
+    pledge_id: PledgeId,
+    keep: usize,
+    reason: &str,
+) {
+    let units: Vec<BloodUnit> = units_of(hospital_id)
+        .into_iter()
+        .filter(|unit| unit.pledge_id == Some(pledge_id) && unit.status != UnitStatus::Discarded)
+        .collect();
+    let mut excess = units.len().saturating_sub(keep);
+    for unit in units.into_iter().rev() {
+        if excess == 0 {
+            break;
+        }
+        if unit.status != UnitStatus::Issued {
+            let unit = BloodUnit {
+                discard_reason: Some(reason.to_string()),
+                reserved_for: None,
+                ..unit
+            };
+            set_unit_status(caller, unit, UnitStatus::Discarded);
+            excess -= 1;
+        }
+    }
+}
+
+// Units changed by a batch of the sweep and the key of the last unit it read, None once the
+// whole inventory was read
+#[derive(Default)]
+pub(crate) struct UnitSweep {
+    pub(crate) expired: Vec<(HospitalId, u64)>,
+    // (hospital id, unit id, patient id)
+    pub(crate) released: Vec<(HospitalId, u64, Option<PatientId>)>,
+    pub(crate) next: Option<(HospitalId, u64)>,
+}
+
+// Read up to `limit` units after `after`, discard those expired at `at` and put those reserved
+// before `reserved_before` back in stock. An expired unit is discarded rather than released, so
+// an expired reserved unit never goes back in stock. A unit's last change is its reservation,
+// so `updated_at` is when it was reserved.
+pub(crate) fn sweep_units(
+    at: u64,
+    reserved_before: u64,
+    after: Option<(HospitalId, u64)>,
+    limit: usize,
+) -> UnitSweep {
+    let start = match after {
+        Some(key) => Bound::Excluded(key),
+        None => Bound::Unbounded,
+    };
+    let units: Vec<BloodUnit> = INVENTORY_STORAGE.with(|s| {
+        s.borrow()
+            .range((start, Bound::Unbounded))
+            .take(limit)
+            .map(|(_, unit)| unit)
+            .collect()
+    });
+    let mut sweep = UnitSweep {
+        next: match units.len() {
+            len if len == limit => units.last().map(|unit| (unit.hospital_id, unit.id)),
+            _ => None,
+        },
+        ..Default::default()
+    };
+    for unit in units {
+        let in_stock = matches!(
+            unit.status,
+            UnitStatus::Quarantined | UnitStatus::Available | UnitStatus::Reserved
+        );
+        if in_stock && unit.expires_at <= at {
+            let unit = BloodUnit {
+                discard_reason: Some("expired".to_string()),
+                ..unit
+            };
+            let unit = save_unit(unit, UnitStatus::Discarded);
+            sweep.expired.push((unit.hospital_id, unit.id));
+        } else if unit.status == UnitStatus::Reserved && unit.updated_at < reserved_before {
+            let patient_id = unit.reserved_for;
+            let unit = save_unit(
+                BloodUnit {
+                    reserved_for: None,
+                    ..unit
+                },
+                UnitStatus::Available,
+            );
+            sweep.released.push((unit.hospital_id, unit.id, patient_id));
+        }
+    }
+    sweep
+}
+
+// Count the units in stock per group and component, leaving out groups and components
+// the hospital has no stock of
+fn stock_summary(hospital_id: HospitalId, at: u64) -> Vec<StockLevel> {
+    let mut levels: Vec<StockLevel> = Vec::new();
+    for unit in units_of(hospital_id) {
+        if matches!(unit.status, UnitStatus::Issued | UnitStatus::Discarded) {
+            continue;
+        }
+        let position = match levels.iter().position(|level| {
+            level.blood_group == unit.blood_group && level.component == unit.component
+        }) {
+            Some(position) => position,
+            None => {
+                levels.push(StockLevel {
+                    blood_group: unit.blood_group,
+                    component: unit.component,
+                    quarantined: 0,
+                    available: 0,
+                    reserved: 0,
+                    expired: 0,
+                });
+                levels.len() - 1
+            }
+        };
+        let level = &mut levels[position];
+        match unit.status {
+            _ if unit.expires_at <= at => level.expired += 1,
+            UnitStatus::Quarantined => level.quarantined += 1,
+            UnitStatus::Available => level.available += 1,
+            _ => level.reserved += 1,
+        }
+    }
+    let group_order = |group: BloodGroup| TYPED_GROUPS.iter().position(|typed| *typed == group);
+    levels.sort_by_key(|level| (group_order(level.blood_group), level.component.key()));
+    levels
+}
+
+fn authorize_inventory_viewer(caller: &Principal, hospital_id: HospitalId) -> Result<(), Error> {
+    if !HOSPITAL_STORAGE.with(|s| s.borrow().contains_key(&hospital_id)) {
+        return Err(Error::NotFound {
+            msg: format!("hospital of id: {} not found", hospital_id),
+        });
+    }
+    roles::require_role(
+        caller,
+        &[Role::HospitalAdmin, Role::HospitalStaff],
+        Some(hospital_id.0),
+    )
+}
+
+// add a quarantined unit to the inventory of a hospital
+#[ic_cdk::update(guard = "caller_is_verified_hospital_staff")]
+fn receive_unit(payload: ReceiveUnitPayload) -> Result<BloodUnit, Error> {
+    let caller = caller_principal()?;
+    receive(caller, payload)
+}
+
+// make a quarantined unit available once its tests have cleared
+#[ic_cdk::update(guard = "caller_is_verified_hospital_staff")]
+fn release_unit(hospital_id: HospitalId, unit_id: u64) -> Result<BloodUnit, Error> {
+    let caller = caller_principal()?;
+    release(caller, hospital_id, unit_id)
+}
+
+// hold an available unit, checking it suits the patient when one is given
+#[ic_cdk::update(guard = "caller_is_verified_hospital_staff")]
+fn reserve_unit(
+    hospital_id: HospitalId,
+    unit_id: u64,
+    patient_id: Option<PatientId>,
+) -> Result<BloodUnit, Error> {
+    let caller = caller_principal()?;
+    reserve(caller, hospital_id, unit_id, patient_id)
+}
+
+// hand out an available or reserved unit for transfusion
+#[ic_cdk::update(guard = "caller_is_verified_hospital_staff")]
+fn issue_unit(hospital_id: HospitalId, unit_id: u64) -> Result<BloodUnit, Error> {
+    let caller = caller_principal()?;
+    issue(caller, hospital_id, unit_id)
+}
+
+// take a unit out of stock with the reason it cannot be used
+#[ic_cdk::update(guard = "caller_is_verified_hospital_staff")]
+fn discard_unit(payload: DiscardUnitPayload) -> Result<BloodUnit, Error> {
+    let caller = caller_principal()?;
+    discard(caller, payload)
+}
+
+// list a page of the units of a hospital, optionally only those with a status
+#[ic_cdk::query]
+fn get_hospital_units(
+    hospital_id: HospitalId,
+    status: Option<UnitStatus>,
+    options: ListOptions<u64>,
+) -> Result<Page<BloodUnit, u64>, Error> {
+    authorize_inventory_viewer(&ic_cdk::caller(), hospital_id)?;
+    INVENTORY_STORAGE.with(|s| {
+        let units = s.borrow();
+        pagination::paginate(
+            units
+                .range((hospital_id, 0)..)
+                .take_while(|((id, _), _)| *id == hospital_id)
+                .map(|(_, unit)| unit)
+                .filter(|unit| status.is_none_or(|status| unit.status == status)),
+            &options,
+            |id| units.get(&(hospital_id, id)),
+        )
+    })
+}
+
+// summarise the stock of a hospital per blood group and component
+#[ic_cdk::query]
+fn get_stock_summary(hospital_id: HospitalId) -> Result<Vec<StockLevel>, Error> {
+    authorize_inventory_viewer(&ic_cdk::caller(), hospital_id)?;
+    Ok(stock_summary(hospital_id, now()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roles::RoleGrant;
+    use crate::Hospital;
+
+    fn setup() -> Principal {
+        let admin = Principal::from_slice(&[1; 10]);
+        HOSPITAL_STORAGE.with(|s| {
+            s.borrow_mut().insert(
+                HospitalId(1),
+                Hospital {
+                    id: HospitalId(1),
+                    verified_by: Some(Principal::from_slice(&[9; 10])),
+                    ..Default::default()
+                },
+            )
+        });
+        roles::grant(
+            admin,
+            RoleGrant {
+                role: Role::HospitalAdmin,
+                scope_id: Some(1),
+            },
+        );
+        admin
+    }
+
+    fn payload(label: &str, component: Component) -> ReceiveUnitPayload {
+        ReceiveUnitPayload {
+            hospital_id: HospitalId(1),
+            label: label.to_string(),
+            component,
+            blood_group: "O-".to_string(),
+            collected_at: now(),
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn unit_lifecycle() {
+        let admin = setup();
+        let unit = receive(admin, payload("BAG-1", Component::WholeBlood)).unwrap();
+        assert_eq!(unit.status, UnitStatus::Quarantined);
+        assert_eq!(unit.expires_at, unit.collected_at + 35 * DAY);
+        assert!(matches!(
+            receive(admin, payload("BAG-1", Component::Plasma)),
+            Err(Error::AlreadyInit { .. })
+        ));
+        assert!(reserve(admin, HospitalId(1), unit.id, None).is_err());
+
+        release(admin, HospitalId(1), unit.id).unwrap();
+        let unit = reserve(admin, HospitalId(1), unit.id, None).unwrap();
+        assert_eq!(unit.status, UnitStatus::Reserved);
+        let unit = issue(admin, HospitalId(1), unit.id).unwrap();
+        assert_eq!(unit.status, UnitStatus::Issued);
+        assert!(discard(
+            admin,
+            DiscardUnitPayload {
+                hospital_id: HospitalId(1),
+                unit_id: unit.id,
+                reason: "broken bag".to_string(),
+            },
+        )
+        .is_err());
+
+        let stranger = Principal::from_slice(&[2; 10]);
+        assert!(matches!(
+            receive(stranger, payload("BAG-2", Component::WholeBlood)),
+            Err(Error::Unauthorized { .. })
+        ));
+    }
+
+    #[test]
+    fn summary_counts_stock_per_group_and_component() {
+        let admin = setup();
+        let first = receive(admin, payload("BAG-1", Component::Plasma)).unwrap();
+        receive(admin, payload("BAG-2", Component::Plasma)).unwrap();
+        receive(admin, payload("BAG-3", Component::Platelets)).unwrap();
+        release(admin, HospitalId(1), first.id).unwrap();
+
+        let summary = stock_summary(HospitalId(1), now());
+        assert_eq!(summary.len(), 2);
+        assert_eq!((summary[0].quarantined, summary[0].available), (1, 1));
+        assert_eq!(summary[1].component, Component::Platelets);
+        let later = stock_summary(HospitalId(1), now() + 6 * DAY);
+        assert_eq!((later[0].available, later[1].expired), (1, 1));
+    }
+}
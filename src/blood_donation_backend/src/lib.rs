@@ -1,81 +1,362 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
+use blood_group::BloodGroup;
+use candid::{Decode, Encode, Principal};
+use components::{Component, ComponentNeed, ComponentPints};
+use credentials::{Credential, DONOR_ACCOUNT, HOSPITAL_ACCOUNT, PATIENT_ACCOUNT};
+use eligibility::{DeferDonorPayload, Deferral, DonationRule, DonorEligibility};
+use expiry::{ExpiryEvent, ExpirySettings};
+use geo::{GeoPoint, NearbyHospital};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
+use ids::{DonorId, HospitalId, PatientId, PledgeId};
+use indexes::IndexText;
+use inventory::{BloodUnit, DiscardUnitPayload, ReceiveUnitPayload, StockLevel, UnitStatus};
+use matching::{DonorMatch, PatientMatch};
+use pagination::{ListOptions, Listed, Page, SortBy, SortKey};
+use pledges::{
+    AmendPledgePayload, CancelPledgePayload, ConfirmDonationPayload, NoShowPayload, Pledge,
+    PledgeChange, PledgeEligibility, PledgePayload, PledgeReceipt,
+};
+use relations::IdPage;
+use roles::{
+    caller_is_authenticated, caller_is_super_admin, caller_is_verified_hospital_staff,
+    require_role, Role, RoleGrant, StorablePrincipal,
+};
+use screening::{Screening, ScreeningPayload, ScreeningRule, ScreeningRules};
+use search::{Match, Posting, SearchField, SearchQuery, SearchResults};
+use staff::{
+    RemoveStaffPayload, StaffAction, StaffActivity, StaffMember, StaffPayload, StaffPermission,
+};
+use std::cmp::Reverse;
+use std::time::Duration;
 use std::{borrow::Cow, cell::RefCell};
 use validator::Validate;
 
+mod blood_group;
+mod components;
+mod credentials;
+mod eligibility;
+mod expiry;
+mod geo;
+mod ids;
+mod indexes;
+mod inventory;
+mod matching;
+mod pagination;
+mod pledges;
+mod relations;
+mod roles;
+mod schema;
+mod screening;
+mod search;
+mod staff;
+
 // Define type aliases for convenience
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct Patient {
-    id: u64,
+    id: PatientId,
+    owner: Option<Principal>,
     name: String,
-    blood_group: String,
+    blood_group: BloodGroup,
+    // hospital name given before patients were linked by id, kept for records the upgrade
+    // could not link and empty otherwise
     hospital: String,
+    hospital_id: Option<HospitalId>,
     description: String,
     needed_pints: u32,
     donations: u32,
     password: String,
     is_complete: bool,
-    donors_ids: Vec<u64>,
+    // donors stored in the record by earlier versions, moved to an index on upgrade
+    donors_ids: Option<Vec<DonorId>>,
+    // needs per component, `needed_pints` and `donations` are their totals. Patients stored
+    // before components were tracked have None and need whole blood.
+    needs: Option<Vec<ComponentNeed>>,
+    // routine for patients stored before urgency was recorded
+    urgency: Option<Urgency>,
+    // None for patients stored before registration times were recorded
+    registered_at: Option<u64>,
+}
+
+// How soon a patient needs blood, from least to most urgent
+#[derive(
+    candid::CandidType,
+    Clone,
+    Copy,
+    Serialize,
+    Deserialize,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Debug,
+    Default,
+)]
+enum Urgency {
+    #[default]
+    Routine,
+    Urgent,
+    Critical,
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct Hospital {
-    id: u64,
+    id: HospitalId,
+    owner: Option<Principal>,
+    verified_by: Option<Principal>,
     name: String,
     address: String,
     password: String,
     city: String,
     donations: u32,
-    donors_ids: Vec<u64>,
+    // donors stored in the record by earlier versions, moved to an index on upgrade
+    donors_ids: Option<Vec<DonorId>>,
+    location: Option<GeoPoint>,
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
 struct Donor {
-    id: u64,
+    id: DonorId,
+    owner: Option<Principal>,
+    name: String,
+    password: String,
+    blood_group: BloodGroup,
+    // hospitals and patients stored in the record by earlier versions, when they shared one id
+    // sequence, moved to indexes on upgrade
+    beneficiaries: Option<Vec<u64>>,
+    // home city, used to find patients nearby
+    city: Option<String>,
+}
+
+// Layout of patients stored before blood groups were typed
+#[derive(candid::CandidType, Deserialize)]
+struct LegacyPatient {
+    id: PatientId,
+    owner: Option<Principal>,
+    name: String,
+    blood_group: String,
+    hospital: String,
+    description: String,
+    needed_pints: u32,
+    donations: u32,
+    password: String,
+    is_complete: bool,
+    donors_ids: Vec<DonorId>,
+}
+
+// Layout of donors stored before blood groups were typed
+#[derive(candid::CandidType, Deserialize)]
+struct LegacyDonor {
+    id: DonorId,
+    owner: Option<Principal>,
     name: String,
     password: String,
     blood_group: String,
     beneficiaries: Vec<u64>,
 }
 
+impl Patient {
+    fn needs(&self) -> Vec<ComponentNeed> {
+        self.needs.clone().unwrap_or_else(|| {
+            vec![ComponentNeed {
+                component: Component::WholeBlood,
+                needed_pints: self.needed_pints,
+                donations: self.donations,
+            }]
+        })
+    }
+
+    // Replace the needs and bring the totals and completion in line with them
+    fn set_needs(&mut self, needs: Vec<ComponentNeed>) -> Result<(), Error> {
+        let total = |pints: fn(&ComponentNeed) -> u32| {
+            needs
+                .iter()
+                .try_fold(0u32, |total, need| total.checked_add(pints(need)))
+                .ok_or(Error::InvalidPayload {
+                    msg: format!("pints of patient of id: {} overflow", self.id),
+                })
+        };
+        let (needed_pints, donations) = (
+            total(|need| need.needed_pints)?,
+            total(|need| need.donations)?,
+        );
+        self.needed_pints = needed_pints;
+        self.donations = donations;
+        self.is_complete = needs.iter().all(|need| need.donations >= need.needed_pints);
+        self.needs = Some(needs);
+        Ok(())
+    }
+
+    fn urgency(&self) -> Urgency {
+        self.urgency.unwrap_or_default()
+    }
+
+    // City of the hospital the patient is registered at, empty when it is not linked
+    fn city(&self) -> String {
+        self.hospital_id
+            .and_then(|id| HOSPITAL_STORAGE.with(|s| s.borrow().get(&id)))
+            .map(|hospital| hospital.city)
+            .unwrap_or_default()
+    }
+}
+
+impl Listed for Patient {
+    type Cursor = PatientId;
+    const NAME: &'static str = "patients";
+    const SORTS: &'static [SortBy] = &[SortBy::Name, SortBy::City, SortBy::Urgency];
+
+    fn cursor(&self) -> PatientId {
+        self.id
+    }
+
+    fn sort_key(&self, sort_by: SortBy) -> SortKey {
+        match sort_by {
+            SortBy::Name => pagination::text_key(&self.name),
+            SortBy::City => pagination::text_key(&self.city()),
+            // most urgent first, then the largest remaining need
+            SortBy::Urgency => {
+                let remaining = self.needed_pints.saturating_sub(self.donations);
+                SortKey::Rank(Reverse(
+                    (self.urgency() as u64) << 32 | u64::from(remaining),
+                ))
+            }
+            SortBy::Created => SortKey::Unsorted,
+        }
+    }
+}
+
+impl Listed for Hospital {
+    type Cursor = HospitalId;
+    const NAME: &'static str = "hospitals";
+    const SORTS: &'static [SortBy] = &[SortBy::Name, SortBy::City];
+
+    fn cursor(&self) -> HospitalId {
+        self.id
+    }
+
+    fn sort_key(&self, sort_by: SortBy) -> SortKey {
+        match sort_by {
+            SortBy::Name => pagination::text_key(&self.name),
+            SortBy::City => pagination::text_key(&self.city),
+            _ => SortKey::Unsorted,
+        }
+    }
+}
+
+impl Listed for Donor {
+    type Cursor = DonorId;
+    const NAME: &'static str = "donors";
+    const SORTS: &'static [SortBy] = &[SortBy::Name];
+
+    fn cursor(&self) -> DonorId {
+        self.id
+    }
+
+    fn sort_key(&self, sort_by: SortBy) -> SortKey {
+        match sort_by {
+            SortBy::Name => pagination::text_key(&self.name),
+            _ => SortKey::Unsorted,
+        }
+    }
+}
+
+impl From<LegacyPatient> for Patient {
+    fn from(patient: LegacyPatient) -> Self {
+        Patient {
+            id: patient.id,
+            owner: patient.owner,
+            name: patient.name,
+            blood_group: BloodGroup::from_legacy(&patient.blood_group),
+            hospital: patient.hospital,
+            hospital_id: None,
+            description: patient.description,
+            needed_pints: patient.needed_pints,
+            donations: patient.donations,
+            password: patient.password,
+            is_complete: patient.is_complete,
+            donors_ids: Some(patient.donors_ids),
+            needs: None,
+            urgency: None,
+            registered_at: None,
+        }
+    }
+}
+
+impl From<LegacyDonor> for Donor {
+    fn from(donor: LegacyDonor) -> Self {
+        Donor {
+            id: donor.id,
+            owner: donor.owner,
+            name: donor.name,
+            password: donor.password,
+            blood_group: BloodGroup::from_legacy(&donor.blood_group),
+            beneficiaries: Some(donor.beneficiaries),
+            city: None,
+        }
+    }
+}
+
 // Implement the 'Storable' trait for 'Hospital', 'Patient' and 'CommunityHospital'
 
 impl Storable for Patient {
-    // Conversion to bytes
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+    // Conversion to bytes, enveloped with the layout version
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(schema::seal(
+            schema::PATIENT_VERSION,
+            Encode!(self).unwrap(),
+        ))
     }
-    // Conversion from bytes
+    // Conversion from bytes. Records stored before envelopes fall back to the layout with a
+    // free text blood group.
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        match schema::open(&bytes) {
+            (schema::PATIENT_VERSION, candid) => Decode!(candid, Self).unwrap(),
+            (0, candid) => match Decode!(candid, Self) {
+                Ok(patient) => patient,
+                Err(_) => Decode!(candid, LegacyPatient).unwrap().into(),
+            },
+            (version, _) => panic!("unknown patient layout version {}", version),
+        }
     }
 }
 
 impl Storable for Hospital {
-    // Conversion to bytes
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+    // Conversion to bytes, enveloped with the layout version
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(schema::seal(
+            schema::HOSPITAL_VERSION,
+            Encode!(self).unwrap(),
+        ))
     }
-    // Conversion from bytes
+    // Conversion from bytes, every hospital layout so far decodes into the current one
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        match schema::open(&bytes) {
+            (0 | schema::HOSPITAL_VERSION, candid) => Decode!(candid, Self).unwrap(),
+            (version, _) => panic!("unknown hospital layout version {}", version),
+        }
     }
 }
 
 impl Storable for Donor {
-    // Conversion to bytes
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+    // Conversion to bytes, enveloped with the layout version
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(schema::seal(schema::DONOR_VERSION, Encode!(self).unwrap()))
     }
-    // Conversion from bytes
+    // Conversion from bytes. Records stored before envelopes fall back to the layout with a
+    // free text blood group.
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        match schema::open(&bytes) {
+            (schema::DONOR_VERSION, candid) => Decode!(candid, Self).unwrap(),
+            (0, candid) => match Decode!(candid, Self) {
+                Ok(donor) => donor,
+                Err(_) => Decode!(candid, LegacyDonor).unwrap().into(),
+            },
+            (version, _) => panic!("unknown donor layout version {}", version),
+        }
     }
 }
 
@@ -101,145 +382,897 @@ thread_local! {
         MemoryManager::init(DefaultMemoryImpl::default())
     );
 
+    // memory 1 is reserved for the schema version of the stable memory
+    static SCHEMA_VERSION_CELL: RefCell<Cell<u32, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))), 0)
+            .expect("Cannot create the schema version")
+    );
+
     static ID_COUNTER: RefCell<IdCell> = RefCell::new(
         IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
             .expect("Cannot create a counter")
     );
 
-    static PATIENT_STORAGE: RefCell<StableBTreeMap<u64, Patient, Memory>> =
+    static PATIENT_STORAGE: RefCell<StableBTreeMap<PatientId, Patient, Memory>> =
         RefCell::new(StableBTreeMap::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
     ));
 
-    static HOSPITAL_STORAGE: RefCell<StableBTreeMap<u64, Hospital, Memory>> =
+    static HOSPITAL_STORAGE: RefCell<StableBTreeMap<HospitalId, Hospital, Memory>> =
         RefCell::new(StableBTreeMap::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
     ));
 
-    static DONOR_STORAGE: RefCell<StableBTreeMap<u64, Donor, Memory>> =
+    static DONOR_STORAGE: RefCell<StableBTreeMap<DonorId, Donor, Memory>> =
         RefCell::new(StableBTreeMap::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
     ));
+
+    // Hashed passwords of accounts created before principal ownership, keyed by account kind and id
+    static CREDENTIAL_STORAGE: RefCell<StableBTreeMap<(u8, u64), Credential, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+    ));
+
+    // one entry per role granted to a principal
+    static ROLE_STORAGE: RefCell<StableBTreeMap<(StorablePrincipal, RoleGrant), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+    ));
+
+    // Staff memberships keyed by hospital id and staff principal
+    static STAFF_STORAGE: RefCell<StableBTreeMap<(HospitalId, StorablePrincipal), StaffMember, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+    ));
+
+    static ACTIVITY_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    // Hospital-side mutations keyed by hospital id and activity id
+    static ACTIVITY_STORAGE: RefCell<StableBTreeMap<(HospitalId, u64), StaffActivity, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+    ));
+
+    static PLEDGE_STORAGE: RefCell<StableBTreeMap<PledgeId, Pledge, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+    ));
+
+    // Cancellations and amendments keyed by pledge id and sequence number
+    static PLEDGE_CHANGE_STORAGE: RefCell<StableBTreeMap<(PledgeId, u64), PledgeChange, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+    ));
+
+    // Donor deferrals keyed by donor id and deferral id
+    static DEFERRAL_STORAGE: RefCell<StableBTreeMap<(DonorId, u64), Deferral, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+    ));
+
+    // Donation rules changed by administrators keyed by component, defaults apply otherwise
+    static DONATION_RULE_STORAGE: RefCell<StableBTreeMap<u8, DonationRule, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+    ));
+
+    // Versions of the screening rules keyed by version number
+    static SCREENING_RULE_STORAGE: RefCell<StableBTreeMap<u32, ScreeningRules, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+    ));
+
+    // Screening questionnaires keyed by the pledge they were taken for
+    static SCREENING_STORAGE: RefCell<StableBTreeMap<PledgeId, Screening, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+    ));
+
+    // blood bank units keyed by (hospital id, unit id)
+    static INVENTORY_STORAGE: RefCell<StableBTreeMap<(HospitalId, u64), BloodUnit, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+    ));
+
+    static EXPIRY_SETTINGS: RefCell<Cell<ExpirySettings, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))),
+            ExpirySettings::default(),
+        )
+        .expect("Cannot create the expiry settings")
+    );
+
+    // transitions made by the expiry sweep keyed by event id
+    static EXPIRY_EVENT_STORAGE: RefCell<StableBTreeMap<u64, ExpiryEvent, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+    ));
+
+    // donors of each hospital and patient and the hospitals and patients of each donor keyed by
+    // (owner id, related id), valued by the number of collections behind the relation
+    static HOSPITAL_DONOR_INDEX: RefCell<StableBTreeMap<(HospitalId, DonorId), u32, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+    ));
+
+    static PATIENT_DONOR_INDEX: RefCell<StableBTreeMap<(PatientId, DonorId), u32, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+    ));
+
+    static DONOR_PATIENT_INDEX: RefCell<StableBTreeMap<(DonorId, PatientId), u32, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+    ));
+
+    // ids of hospitals, patients, donors and pledges each come from their own sequence, as do
+    // those of blood units, deferrals and expiry events further down. ID_COUNTER only holds where
+    // the counter they all shared before stopped.
+    static HOSPITAL_ID_SEQUENCE: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static PATIENT_ID_SEQUENCE: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static DONOR_ID_SEQUENCE: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static PLEDGE_ID_SEQUENCE: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static DONOR_HOSPITAL_INDEX: RefCell<StableBTreeMap<(DonorId, HospitalId), u32, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
+    ));
+
+    // secondary indexes keyed by (indexed value, record id), kept in step with the records by
+    // the store functions of `indexes`
+    static HOSPITAL_CITY_INDEX: RefCell<StableBTreeMap<(IndexText, HospitalId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29)))
+    ));
+
+    static PATIENT_GROUP_INDEX: RefCell<StableBTreeMap<(BloodGroup, PatientId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30)))
+    ));
+
+    // patients by completion, `indexes::OPEN` or `indexes::COMPLETE`
+    static PATIENT_STATUS_INDEX: RefCell<StableBTreeMap<(u8, PatientId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31)))
+    ));
+
+    static DONOR_GROUP_INDEX: RefCell<StableBTreeMap<(BloodGroup, DonorId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32)))
+    ));
+
+    // words of hospitals and patients keyed by (word, field and record id), valued by how often
+    // the word appears in the field
+    static SEARCH_INDEX: RefCell<StableBTreeMap<(IndexText, Posting), u32, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33)))
+    ));
+
+    // hospitals by the geohash of their location, see `geo::geohash`
+    static HOSPITAL_GEO_INDEX: RefCell<StableBTreeMap<(u64, HospitalId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34)))
+    ));
+
+    // patients by the hospital they are registered at, unlinked patients are not indexed
+    static PATIENT_HOSPITAL_INDEX: RefCell<StableBTreeMap<(HospitalId, PatientId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35)))
+    ));
+
+    static UNIT_ID_SEQUENCE: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static DEFERRAL_ID_SEQUENCE: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static EXPIRY_EVENT_ID_SEQUENCE: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    // pledges by the patient they were made to, pledges to hospitals are not indexed
+    static PATIENT_PLEDGE_INDEX: RefCell<StableBTreeMap<(PatientId, PledgeId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39)))
+    ));
+
+    // pledges by the donor who made them
+    static DONOR_PLEDGE_INDEX: RefCell<StableBTreeMap<(DonorId, PledgeId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40)))
+    ));
+
+    // pledges by the hospital they were made to, pledges to patients are not indexed
+    static HOSPITAL_PLEDGE_INDEX: RefCell<StableBTreeMap<(HospitalId, PledgeId), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41)))
+    ));
+
+    // expiry events by the hospital they happened at, events without a hospital are not indexed
+    static HOSPITAL_EXPIRY_EVENT_INDEX: RefCell<StableBTreeMap<(HospitalId, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42)))
+    ));
 }
 
+// Number of legacy passwords hashed per timer tick by the upgrade migration
+const PASSWORD_MIGRATION_BATCH: usize = 50;
+
+// Longest texts of the payloads, in characters. At four bytes a character the texts of a record
+// still fit its 1024 bytes with the other fields.
+const MAX_NAME_LENGTH: u64 = 48;
+const MAX_ADDRESS_LENGTH: u64 = 64;
+const MAX_CITY_LENGTH: u64 = 64;
+const MAX_DESCRIPTION_LENGTH: u64 = 100;
+
 // Struct for payload date used in update functions
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
 struct HospitalPayload {
-    #[validate(length(min = 3))]
+    #[validate(length(min = 3, max = "MAX_NAME_LENGTH"))]
     name: String,
-    #[validate(length(min = 3))]
+    #[validate(length(min = 3, max = "MAX_ADDRESS_LENGTH"))]
     address: String,
-    password: String,
+    #[validate(length(min = 2, max = "MAX_CITY_LENGTH"))]
     city: String,
+    location: Option<GeoPoint>,
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
 struct PatientPayload {
-    #[validate(length(min = 3))]
+    #[validate(length(min = 3, max = "MAX_NAME_LENGTH"))]
     name: String,
     blood_group: String,
-    #[validate(length(min = 6))]
+    #[validate(length(min = 6, max = "MAX_DESCRIPTION_LENGTH"))]
     description: String,
-    password: String,
-    hospital: String,
+    hospital_id: HospitalId,
+    // whole blood pints, used when `needs` is not given
+    needed_pints: u32,
+    needs: Option<Vec<ComponentPints>>,
+    urgency: Option<Urgency>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct EditPatientPayload {
+    patient_id: PatientId,
+    // used when `needs` is not given and the patient needs a single component
     needed_pints: u32,
+    // true closes the patient before every need is met, otherwise completion follows the needs
+    is_complete: Option<bool>,
+    needs: Option<Vec<ComponentPints>>,
+    // kept when None
+    urgency: Option<Urgency>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+struct DonorPayload {
+    #[validate(length(min = 3, max = "MAX_NAME_LENGTH"))]
+    name: String,
+    blood_group: String,
+    #[validate(length(max = "MAX_CITY_LENGTH"))]
+    city: Option<String>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+struct EditDonorPayload {
+    donor_id: DonorId,
+    // an empty city clears it
+    #[validate(length(max = "MAX_CITY_LENGTH"))]
+    city: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+struct EditHospitalPayload {
+    hospital_id: HospitalId,
+    #[validate(length(min = 3, max = "MAX_NAME_LENGTH"))]
+    name: String,
+    // kept when None
+    location: Option<GeoPoint>,
+}
+
+// Payload used to grant or revoke a role, `scope_id` is empty only for SuperAdmin
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RolePayload {
+    principal: Principal,
+    role: Role,
+    scope_id: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RoleAssignment {
+    principal: Principal,
+    role: Role,
+    scope_id: Option<u64>,
+}
+
+// Payload used by owners of records created before principal ownership to claim them
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ClaimPayload {
+    id: u64,
+    password: String,
+}
+
+// Payload used to rotate the password of an account that has not been claimed yet
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+struct ChangePasswordPayload {
+    id: u64,
+    old_password: String,
+    #[validate(length(min = 4))]
+    new_password: String,
+}
+
+// Current time in nanoseconds, unit tests run outside a canister and use the system clock
+fn now() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        ic_cdk::api::time()
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .expect("system clock before unix epoch")
+            .as_nanos() as u64
+    }
+}
+
+// Returns the principal of the caller, rejecting anonymous callers
+fn caller_principal() -> Result<Principal, Error> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "Unauthorized, anonymous principals cannot perform updates".to_string(),
+        });
+    }
+    Ok(caller)
+}
+
+// Checks a password against the hashed credential of a legacy account, falling back to the
+// plaintext copy for records the upgrade migration has not reached yet
+fn verify_legacy_password(kind: u8, id: u64, stored_password: &str, password: &str) -> bool {
+    match CREDENTIAL_STORAGE.with(|s| s.borrow().get(&(kind, id))) {
+        Some(credential) => credential.verify(password),
+        None => !stored_password.is_empty() && stored_password == password,
+    }
+}
+
+// Checks the legacy password of a record that was created before principal ownership
+fn authorize_legacy_account(
+    owner: &Option<Principal>,
+    kind: u8,
+    id: u64,
+    stored_password: &str,
+    password: &str,
+) -> Result<(), Error> {
+    if owner.is_some() {
+        return Err(Error::AlreadyInit {
+            msg: "record has already been claimed by a principal".to_string(),
+        });
+    }
+    if !verify_legacy_password(kind, id, stored_password, password) {
+        return Err(Error::Unauthorized {
+            msg: "Unauthorized, password does not match, try again".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Replace the credential of a legacy account after checking its current password
+fn rotate_legacy_password(
+    owner: &Option<Principal>,
+    kind: u8,
+    id: u64,
+    stored_password: &str,
+    payload: &ChangePasswordPayload,
+    salt: &[u8],
+) -> Result<(), Error> {
+    authorize_legacy_account(owner, kind, id, stored_password, &payload.old_password)?;
+    CREDENTIAL_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert((kind, id), Credential::new(&payload.new_password, salt))
+    });
+    Ok(())
+}
+
+// Set up the first canister administrator, defaults to the principal installing the canister
+#[ic_cdk::init]
+fn init(admin: Option<Principal>) {
+    bootstrap_super_admin(admin.unwrap_or_else(ic_cdk::caller));
+    schema::init_version();
+    expiry::schedule_sweeps();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade(admin: Option<Principal>) {
+    // canisters installed before roles existed get their administrator on the first upgrade
+    if roles::super_admin_count() == 0 {
+        bootstrap_super_admin(admin.unwrap_or_else(ic_cdk::caller));
+    }
+    schema::migrate();
+    // hash the plaintext passwords left in records created before hashing was introduced
+    schedule_password_migration();
+    expiry::schedule_sweeps();
+}
+
+// Store a batch of patients again in the current layout
+fn rewrite_patients(after: Option<u64>, limit: usize) -> Option<u64> {
+    let (patients, next) = schema::batch(&PATIENT_STORAGE, after, limit);
+    for patient in patients {
+        PATIENT_STORAGE.with(|s| s.borrow_mut().insert(patient.id, patient));
+    }
+    next
+}
+
+// Store a batch of hospitals again in the current layout
+fn rewrite_hospitals(after: Option<u64>, limit: usize) -> Option<u64> {
+    let (hospitals, next) = schema::batch(&HOSPITAL_STORAGE, after, limit);
+    for hospital in hospitals {
+        HOSPITAL_STORAGE.with(|s| s.borrow_mut().insert(hospital.id, hospital));
+    }
+    next
+}
+
+// Store a batch of donors again in the current layout
+fn rewrite_donors(after: Option<u64>, limit: usize) -> Option<u64> {
+    let (donors, next) = schema::batch(&DONOR_STORAGE, after, limit);
+    for donor in donors {
+        DONOR_STORAGE.with(|s| s.borrow_mut().insert(donor.id, donor));
+    }
+    next
+}
+
+// Lowercase words of a hospital name, ignoring punctuation
+fn normalize_name(name: &str) -> String {
+    name.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Find the hospital a free-text name refers to: a hospital whose name, or name and city, equals
+// the text once normalized, or else the only hospital whose name contains the text or is
+// contained in it. Ambiguous and unknown names match nothing.
+fn match_hospital(text: &str, hospitals: &[Hospital]) -> Option<HospitalId> {
+    let text = normalize_name(text);
+    if text.is_empty() {
+        return None;
+    }
+    let only = |candidates: Vec<HospitalId>| match candidates[..] {
+        [id] => Some(id),
+        _ => None,
+    };
+    let exact: Vec<HospitalId> = hospitals
+        .iter()
+        .filter(|hospital| {
+            let name = normalize_name(&hospital.name);
+            name == text || normalize_name(&format!("{} {}", hospital.name, hospital.city)) == text
+        })
+        .map(|hospital| hospital.id)
+        .collect();
+    if !exact.is_empty() {
+        return only(exact);
+    }
+    only(
+        hospitals
+            .iter()
+            .filter(|hospital| {
+                let name = normalize_name(&hospital.name);
+                !name.is_empty() && (text.contains(&name) || name.contains(&text))
+            })
+            .map(|hospital| hospital.id)
+            .collect(),
+    )
+}
+
+// Link a batch of patients registered with a free-text hospital name to the hospital it names.
+// Every batch reads the hospitals again, as a name is matched against all of them.
+fn link_patient_hospitals(after: Option<u64>, limit: usize) -> Option<u64> {
+    let hospitals: Vec<Hospital> =
+        HOSPITAL_STORAGE.with(|s| s.borrow().iter().map(|(_, h)| h).collect());
+    let (patients, next) = schema::batch(&PATIENT_STORAGE, after, limit);
+    let unlinked = patients
+        .into_iter()
+        .filter(|p| p.hospital_id.is_none() && !p.hospital.is_empty());
+    for patient in unlinked {
+        if let Some(hospital_id) = match_hospital(&patient.hospital, &hospitals) {
+            let patient = Patient {
+                hospital: String::new(),
+                hospital_id: Some(hospital_id),
+                ..patient
+            };
+            PATIENT_STORAGE.with(|s| s.borrow_mut().insert(patient.id, patient));
+        }
+    }
+    next
+}
+
+fn bootstrap_super_admin(admin: Principal) {
+    roles::grant(
+        admin,
+        RoleGrant {
+            role: Role::SuperAdmin,
+            scope_id: None,
+        },
+    );
+}
+
+// Give the owners of records created before roles existed the matching scoped role
+fn backfill_owner_roles() {
+    let grants: Vec<(Principal, Role, u64)> = HOSPITAL_STORAGE
+        .with(|s| {
+            s.borrow()
+                .iter()
+                .filter_map(|(id, hospital)| {
+                    hospital
+                        .owner
+                        .map(|owner| (owner, Role::HospitalAdmin, id.0))
+                })
+                .collect::<Vec<_>>()
+        })
+        .into_iter()
+        .chain(PATIENT_STORAGE.with(|s| {
+            s.borrow()
+                .iter()
+                .filter_map(|(id, patient)| {
+                    patient
+                        .owner
+                        .map(|owner| (owner, Role::PatientGuardian, id.0))
+                })
+                .collect::<Vec<_>>()
+        }))
+        .chain(DONOR_STORAGE.with(|s| {
+            s.borrow()
+                .iter()
+                .filter_map(|(id, donor)| donor.owner.map(|owner| (owner, Role::Donor, id.0)))
+                .collect::<Vec<_>>()
+        }))
+        .collect();
+
+    for (owner, role, id) in grants {
+        roles::grant(
+            owner,
+            RoleGrant {
+                role,
+                scope_id: Some(id),
+            },
+        );
+    }
+}
+
+// Check that a role grant targets an existing record of the right kind
+fn validate_role_scope(role: Role, scope_id: Option<u64>) -> Result<(), Error> {
+    let exists = match (role, scope_id) {
+        (Role::SuperAdmin, None) => true,
+        (Role::SuperAdmin, Some(_)) => {
+            return Err(Error::InvalidPayload {
+                msg: "SuperAdmin role cannot be scoped to a record".to_string(),
+            })
+        }
+        (_, None) => {
+            return Err(Error::InvalidPayload {
+                msg: format!("{:?} role needs a scope id", role),
+            })
+        }
+        (Role::HospitalAdmin | Role::HospitalStaff, Some(id)) => {
+            HOSPITAL_STORAGE.with(|s| s.borrow().contains_key(&HospitalId(id)))
+        }
+        (Role::PatientGuardian, Some(id)) => {
+            PATIENT_STORAGE.with(|s| s.borrow().contains_key(&PatientId(id)))
+        }
+        (Role::Donor, Some(id)) => DONOR_STORAGE.with(|s| s.borrow().contains_key(&DonorId(id))),
+    };
+    match exists {
+        true => Ok(()),
+        false => Err(Error::NotFound {
+            msg: format!("no record of id: {:?} for role {:?}", scope_id, role),
+        }),
+    }
+}
+
+// Check that the caller may hand out or take away a role: super-admins manage every role,
+// hospital admins manage the admins of their hospital and guardians manage guardians
+fn authorize_role_change(
+    caller: &Principal,
+    role: Role,
+    scope_id: Option<u64>,
+) -> Result<(), Error> {
+    match role {
+        Role::HospitalAdmin => require_role(caller, &[Role::HospitalAdmin], scope_id),
+        // staff roles follow the membership table so they carry permissions
+        Role::HospitalStaff => Err(Error::InvalidPayload {
+            msg: "HospitalStaff is managed with invite_staff and remove_staff".to_string(),
+        }),
+        Role::PatientGuardian => require_role(caller, &[Role::PatientGuardian], scope_id),
+        Role::SuperAdmin | Role::Donor => require_role(caller, &[], scope_id),
+    }
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct EditPatientPayload {
-    patient_id: u64,
-    needed_pints: u32,
-    password: String,
-    is_complete: bool,
+fn schedule_password_migration() {
+    // raw_rand cannot be called from post_upgrade itself, so the migration runs from a timer
+    ic_cdk_timers::set_timer(Duration::ZERO, || ic_cdk::spawn(migrate_legacy_passwords()));
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
-struct DonorPayload {
-    #[validate(length(min = 3))]
-    name: String,
-    blood_group: String,
-    #[validate(length(min = 4))]
-    password: String,
+async fn migrate_legacy_passwords() {
+    let seed = match credentials::random_seed().await {
+        Ok(seed) => seed,
+        Err(_) => {
+            schedule_password_migration();
+            return;
+        }
+    };
+    // re-arm until a batch comes back short, keeping each tick under the instruction limit
+    if hash_plaintext_passwords(&seed, PASSWORD_MIGRATION_BATCH) == PASSWORD_MIGRATION_BATCH {
+        schedule_password_migration();
+    }
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct EditHospitalPayload {
-    hospital_id: u64,
-    name: String,
-    password: String,
+// Move up to `limit` plaintext passwords into the credential storage, returns how many were moved
+fn hash_plaintext_passwords(seed: &[u8], limit: usize) -> usize {
+    let mut migrated = 0;
+
+    let hospitals: Vec<Hospital> = HOSPITAL_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, hospital)| hospital)
+            .filter(|hospital| !hospital.password.is_empty())
+            .take(limit)
+            .collect()
+    });
+    for hospital in hospitals {
+        let salt = credentials::derive_salt(seed, HOSPITAL_ACCOUNT, hospital.id.0);
+        let credential = Credential::new(&hospital.password, &salt);
+        CREDENTIAL_STORAGE.with(|s| {
+            s.borrow_mut()
+                .insert((HOSPITAL_ACCOUNT, hospital.id.0), credential)
+        });
+        indexes::store_hospital(Hospital {
+            password: String::new(),
+            ..hospital
+        });
+        migrated += 1;
+    }
+
+    let patients: Vec<Patient> = PATIENT_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, patient)| patient)
+            .filter(|patient| !patient.password.is_empty())
+            .take(limit - migrated)
+            .collect()
+    });
+    for patient in patients {
+        let salt = credentials::derive_salt(seed, PATIENT_ACCOUNT, patient.id.0);
+        let credential = Credential::new(&patient.password, &salt);
+        CREDENTIAL_STORAGE.with(|s| {
+            s.borrow_mut()
+                .insert((PATIENT_ACCOUNT, patient.id.0), credential)
+        });
+        indexes::store_patient(Patient {
+            password: String::new(),
+            ..patient
+        });
+        migrated += 1;
+    }
+
+    let donors: Vec<Donor> = DONOR_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(_, donor)| donor)
+            .filter(|donor| !donor.password.is_empty())
+            .take(limit - migrated)
+            .collect()
+    });
+    for donor in donors {
+        let salt = credentials::derive_salt(seed, DONOR_ACCOUNT, donor.id.0);
+        let credential = Credential::new(&donor.password, &salt);
+        CREDENTIAL_STORAGE.with(|s| {
+            s.borrow_mut()
+                .insert((DONOR_ACCOUNT, donor.id.0), credential)
+        });
+        indexes::store_donor(Donor {
+            password: String::new(),
+            ..donor
+        });
+        migrated += 1;
+    }
+
+    migrated
 }
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
-struct PledgePayload {
-    donor_id: u64,
-    recipient_id: u64,
-    pints_pledge: u32,
-    password: String,
+// grant a role to a principal
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn grant_role(payload: RolePayload) -> Result<RoleAssignment, Error> {
+    let caller = caller_principal()?;
+    validate_role_scope(payload.role, payload.scope_id)?;
+    authorize_role_change(&caller, payload.role, payload.scope_id)?;
+    if payload.principal == Principal::anonymous() {
+        return Err(Error::InvalidPayload {
+            msg: "roles cannot be granted to the anonymous principal".to_string(),
+        });
+    }
+
+    roles::grant(
+        payload.principal,
+        RoleGrant {
+            role: payload.role,
+            scope_id: payload.scope_id,
+        },
+    );
+    Ok(RoleAssignment {
+        principal: payload.principal,
+        role: payload.role,
+        scope_id: payload.scope_id,
+    })
+}
+
+// revoke a role from a principal, the last super-admin cannot be removed
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn revoke_role(payload: RolePayload) -> Result<RoleAssignment, Error> {
+    let caller = caller_principal()?;
+    authorize_role_change(&caller, payload.role, payload.scope_id)?;
+    if payload.role == Role::SuperAdmin && roles::super_admin_count() <= 1 {
+        return Err(Error::InvalidPayload {
+            msg: "cannot revoke the last SuperAdmin".to_string(),
+        });
+    }
+
+    let grant = RoleGrant {
+        role: payload.role,
+        scope_id: payload.scope_id,
+    };
+    match roles::revoke(payload.principal, grant) {
+        true => Ok(RoleAssignment {
+            principal: payload.principal,
+            role: payload.role,
+            scope_id: payload.scope_id,
+        }),
+        false => Err(Error::NotFound {
+            msg: format!(
+                "principal {} does not hold role {:?}",
+                payload.principal, payload.role
+            ),
+        }),
+    }
 }
 
-// Query function to get all hospitals
+// list the roles of a principal, defaults to the caller; only super-admins can list other principals
 #[ic_cdk::query]
-fn get_all_hospitals() -> Result<Vec<Hospital>, Error> {
-    // Retrieve all Hospitals from the storage
-    let hospital_map: Vec<(u64, Hospital)> = HOSPITAL_STORAGE.with(|s| s.borrow().iter().collect());
-    // Extract the Hospitals from the tuple and create a vector
-    let hospitals: Vec<Hospital> = hospital_map
+fn list_roles(principal: Option<Principal>) -> Result<Vec<RoleAssignment>, Error> {
+    let caller = ic_cdk::caller();
+    let principal = principal.unwrap_or(caller);
+    if principal != caller && !roles::is_super_admin(&caller) {
+        return Err(Error::Unauthorized {
+            msg: "Unauthorized, only a SuperAdmin can list the roles of other principals"
+                .to_string(),
+        });
+    }
+
+    Ok(roles::roles_of(&principal)
         .into_iter()
-        .map(|(_, hospital)| hospital)
-        .map(|hospital| Hospital {
-            password: "******".to_string(),
-            ..hospital
+        .map(|grant| RoleAssignment {
+            principal,
+            role: grant.role,
+            scope_id: grant.scope_id,
         })
-        .collect();
+        .collect())
+}
 
-    match hospitals.len() {
+// Query function to get a page of all hospitals
+#[ic_cdk::query]
+fn get_all_hospitals(
+    options: ListOptions<HospitalId>,
+) -> Result<Page<Hospital, HospitalId>, Error> {
+    // Read a page of the Hospitals from the storage
+    let page = HOSPITAL_STORAGE.with(|s| {
+        let hospitals = s.borrow();
+        pagination::paginate_map(&hospitals, &options)
+    })?;
+
+    match page.total {
         0 => Err(Error::NotFound {
-            msg: format!("no Hospitals found"),
+            msg: "no Hospitals found".to_string(),
         }),
-        _ => Ok(hospitals),
+        _ => Ok(page.map(|hospital| Hospital {
+            password: "******".to_string(),
+            ..hospital
+        })),
     }
 }
 
-// Get Hospitals by city and name content
+// Get a page of the Hospitals whose name or city holds the words searched for
 #[ic_cdk::query]
-fn get_hospital_by_city_and_name(search: String) -> Result<Vec<Hospital>, Error> {
-    let query = search.to_lowercase();
-    // Retrieve all Hospitals from the storage
-    let hospital_map: Vec<(u64, Hospital)> = HOSPITAL_STORAGE.with(|s| s.borrow().iter().collect());
-    // Extract the Hospitals from the tuple and create a vector
-    let hospitals: Vec<Hospital> = hospital_map
-        .into_iter()
-        .map(|(_, hospital)| hospital)
-        .collect();
+fn get_hospital_by_city_and_name(
+    search: String,
+    options: ListOptions<HospitalId>,
+) -> Result<Page<Hospital, HospitalId>, Error> {
+    schema::check_migrated()?;
+    let matches = search::search_records(
+        &search,
+        &[SearchField::HospitalName, SearchField::HospitalCity],
+    )?;
+    let page = HOSPITAL_STORAGE.with(|s| {
+        let hospitals = s.borrow();
+        pagination::paginate(
+            matches.iter().filter_map(|(record, _, _)| match record {
+                Match::Hospital(id) => hospitals.get(id),
+                Match::Patient(_) => None,
+            }),
+            &options,
+            |id| hospitals.get(&id),
+        )
+    })?;
 
-    // Filter the hospitals by city or name
-    let incomplete_patients: Vec<Hospital> = hospitals
-        .into_iter()
-        .filter(|hospital| {
-            (hospital.city).to_lowercase().contains(&query)
-                || (hospital.name).to_lowercase().contains(&query)
-        })
-        .map(|hospital| Hospital {
+    // Check if any hospitals are found
+    match page.total {
+        0 => Err(Error::NotFound {
+            msg: format!("no hospitals for city or name: {} could be found", search),
+        }),
+        _ => Ok(page.map(|hospital| Hospital {
             password: "******".to_string(),
             ..hospital
+        })),
+    }
+}
+
+// Get a page of the Hospitals in a city, ignoring case and punctuation
+#[ic_cdk::query]
+fn get_hospitals_by_city(
+    city: String,
+    options: ListOptions<HospitalId>,
+) -> Result<Page<Hospital, HospitalId>, Error> {
+    schema::check_migrated()?;
+    let key = IndexText::new(&city);
+    let page = HOSPITAL_STORAGE.with(|s| {
+        let hospitals = s.borrow();
+        indexes::scan(&HOSPITAL_CITY_INDEX, &key, |ids| {
+            pagination::paginate(
+                ids.filter_map(|id| hospitals.get(&id))
+                    // cities cut to the same index text are told apart by the record
+                    .filter(|hospital| normalize_name(&hospital.city) == normalize_name(&city)),
+                &options,
+                |id| hospitals.get(&id),
+            )
         })
-        .collect();
+    })?;
 
-    // Check if any hospitals are found
-    match incomplete_patients.len() {
+    match page.total {
         0 => Err(Error::NotFound {
-            msg: format!(
-                "no Food hospitals for city or name: {} could be found",
-                query
-            ),
+            msg: format!("no hospitals found in city: {}", city),
         }),
-        _ => Ok(incomplete_patients),
+        _ => Ok(page.map(|hospital| Hospital {
+            password: "******".to_string(),
+            ..hospital
+        })),
     }
 }
 
 // get hospital by ID
 #[ic_cdk::query]
-fn get_hospital_by_id(id: u64) -> Result<Hospital, Error> {
+fn get_hospital_by_id(id: HospitalId) -> Result<Hospital, Error> {
     match HOSPITAL_STORAGE.with(|hospitals| hospitals.borrow().get(&id)) {
         Some(hospital) => Ok(Hospital {
             password: "******".to_string(),
@@ -251,65 +1284,82 @@ fn get_hospital_by_id(id: u64) -> Result<Hospital, Error> {
     }
 }
 
-// Create new Hospital
-#[ic_cdk::update]
+// Create new unverified Hospital administered by the caller
+#[ic_cdk::update(guard = "caller_is_authenticated")]
 fn add_hospital(payload: HospitalPayload) -> Result<Hospital, Error> {
+    let caller = caller_principal()?;
+    create_hospital(caller, payload)
+}
+
+fn create_hospital(caller: Principal, payload: HospitalPayload) -> Result<Hospital, Error> {
     // validate payload
-    let validate_payload = payload.validate();
-    if validate_payload.is_err() {
-        return Err(Error::InvalidPayload {
-            msg: validate_payload.unwrap_err().to_string(),
-        });
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
     }
+    payload.location.map(GeoPoint::check).transpose()?;
 
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_id = *counter.borrow().get();
-            counter.borrow_mut().set(current_id + 1)
-        })
-        .expect("Cannot increment Ids");
+    let id = HospitalId::next();
 
     let hospital = Hospital {
         id,
+        owner: Some(caller),
+        verified_by: None,
         name: payload.name.clone(),
         address: payload.address,
         city: payload.city,
-        password: payload.password,
+        password: String::new(),
         donations: 0,
-        donors_ids: vec![],
+        donors_ids: None,
+        location: payload.location,
     };
 
-    match HOSPITAL_STORAGE.with(|s| s.borrow_mut().insert(id, hospital.clone())) {
-        Some(_) => Err(Error::InvalidPayload {
+    // refuse before storing, an id already in use must never replace its record
+    if HOSPITAL_STORAGE.with(|s| s.borrow().contains_key(&id)) {
+        return Err(Error::InvalidPayload {
             msg: format!("Could not add hospital name: {}", payload.name),
-        }),
-        None => Ok(hospital),
+        });
     }
+    indexes::store_hospital(hospital.clone());
+    roles::grant(
+        caller,
+        RoleGrant {
+            role: Role::HospitalAdmin,
+            scope_id: Some(id.0),
+        },
+    );
+    Ok(hospital)
 }
 
-// update function to edit a hospital where only owners of hospitals can edit title, is_community, price and description. Non owners can only edit descriptions of communtiy hospitals. authorizations is by password
-#[ic_cdk::update]
+// update function to edit a hospital, only staff allowed to edit the hospital profile can edit it
+#[ic_cdk::update(guard = "caller_is_authenticated")]
 fn edit_hospital(payload: EditHospitalPayload) -> Result<Hospital, Error> {
+    let caller = caller_principal()?;
+    update_hospital(caller, payload)
+}
+
+fn update_hospital(caller: Principal, payload: EditHospitalPayload) -> Result<Hospital, Error> {
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
     let hospital = HOSPITAL_STORAGE.with(|hospitals| hospitals.borrow().get(&payload.hospital_id));
 
     match hospital {
         Some(hospital) => {
-            // check if the password provided matches hospital
-            if hospital.password != payload.password {
-                return Err(Error::Unauthorized {
-                    msg: format!("Unauthorized, password does not match, try again"),
-                });
-            }
+            // check if the caller may edit the hospital profile
+            staff::require_permission(&caller, hospital.id, StaffPermission::EditHospitalProfile)?;
+            payload.location.map(GeoPoint::check).transpose()?;
 
             let new_hospital = Hospital {
                 name: payload.name,
+                location: payload.location.or(hospital.location),
                 ..hospital.clone()
             };
 
-            match HOSPITAL_STORAGE
-                .with(|s| s.borrow_mut().insert(hospital.id, new_hospital.clone()))
-            {
-                Some(_) => Ok(new_hospital),
+            match indexes::store_hospital(new_hospital.clone()) {
+                Some(_) => {
+                    staff::record_activity(hospital.id, caller, StaffAction::EditedProfile);
+                    Ok(new_hospital)
+                }
                 None => Err(Error::InvalidPayload {
                     msg: format!("Could not edit hospital title: {}", hospital.name),
                 }),
@@ -321,73 +1371,136 @@ fn edit_hospital(payload: EditHospitalPayload) -> Result<Hospital, Error> {
     }
 }
 
-// function to pledge to hospital
-#[ic_cdk::update]
-fn pledge_to_hospital(payload: PledgePayload) -> Result<String, Error> {
-    // get hospital
-    let hospital = HOSPITAL_STORAGE.with(|hospitals| hospitals.borrow().get(&payload.recipient_id));
-    match hospital {
+// bind a hospital created before principal ownership to the caller using its legacy password
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn claim_hospital(payload: ClaimPayload) -> Result<Hospital, Error> {
+    let caller = caller_principal()?;
+    match HOSPITAL_STORAGE.with(|hospitals| hospitals.borrow().get(&HospitalId(payload.id))) {
         Some(hospital) => {
-            // check if the password provided matches hospital
-            if hospital.password != payload.password {
-                return Err(Error::Unauthorized {
-                    msg: format!("Unauthorized, password does not match, try again"),
-                });
-            }
+            authorize_legacy_account(
+                &hospital.owner,
+                HOSPITAL_ACCOUNT,
+                hospital.id.0,
+                &hospital.password,
+                &payload.password,
+            )?;
+            CREDENTIAL_STORAGE.with(|s| s.borrow_mut().remove(&(HOSPITAL_ACCOUNT, hospital.id.0)));
+            let new_hospital = Hospital {
+                owner: Some(caller),
+                password: String::new(),
+                ..hospital
+            };
+            indexes::store_hospital(new_hospital.clone());
+            roles::grant(
+                caller,
+                RoleGrant {
+                    role: Role::HospitalAdmin,
+                    scope_id: Some(new_hospital.id.0),
+                },
+            );
+            Ok(new_hospital)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("hospital of id: {} not found", payload.id),
+        }),
+    }
+}
 
-            // check if donor has enough balance
-            let donor = DONOR_STORAGE.with(|donors| donors.borrow().get(&payload.donor_id));
-            match donor {
-                Some(donor) => {
-                    let mut new_donor_beneficiaries = donor.beneficiaries.clone();
-                    new_donor_beneficiaries.push(hospital.id);
-                    let new_donor = Donor {
-                        beneficiaries: new_donor_beneficiaries,
-                        ..donor
-                    };
-                    // update donor in storage
-                    match DONOR_STORAGE.with(|s| s.borrow_mut().insert(donor.id, new_donor.clone()))
-                    {
-                        Some(_) => {
-                            // update hospital
-                            let mut new_hospital_donors_ids = hospital.donors_ids.clone();
-                            new_hospital_donors_ids.push(donor.id);
-                            let new_hospital = Hospital {
-                                donors_ids: new_hospital_donors_ids,
-                                ..hospital.clone()
-                            };
-                            // update hospital in storage
-                            match HOSPITAL_STORAGE
-                                .with(|s| s.borrow_mut().insert(hospital.id, new_hospital.clone()))
-                            {
-                                Some(_) => Ok(format!(
-                                    "Succesfully pledged to hospital {}, visit address: {} to donate",
-                                    hospital.name, hospital.address
-                                )),
-                                None => Err(Error::InvalidPayload {
-                                    msg: format!("Could not update hospital"),
-                                }),
-                            }
-                        }
-                        None => Err(Error::InvalidPayload {
-                            msg: format!("Could not update donor"),
-                        }),
-                    }
-                }
-                None => Err(Error::NotFound {
-                    msg: format!("Donor of id: {} not found", payload.donor_id),
-                }),
+// rotate the password of a hospital that has not been claimed by a principal yet
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+async fn change_hospital_password(payload: ChangePasswordPayload) -> Result<String, Error> {
+    caller_principal()?;
+    // validate payload
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+
+    // fetch the salt first so the record cannot change between the check and the write
+    let salt = credentials::random_salt().await?;
+    match HOSPITAL_STORAGE.with(|hospitals| hospitals.borrow().get(&HospitalId(payload.id))) {
+        Some(hospital) => {
+            rotate_legacy_password(
+                &hospital.owner,
+                HOSPITAL_ACCOUNT,
+                hospital.id.0,
+                &hospital.password,
+                &payload,
+                &salt,
+            )?;
+            // drop the plaintext copy if the upgrade migration has not reached this record yet
+            if !hospital.password.is_empty() {
+                let new_hospital = Hospital {
+                    password: String::new(),
+                    ..hospital.clone()
+                };
+                indexes::store_hospital(new_hospital);
             }
+            Ok(format!("Password changed for hospital {}", hospital.name))
+        }
+        None => Err(Error::NotFound {
+            msg: format!("hospital of id: {} not found", payload.id),
+        }),
+    }
+}
+
+// mark a hospital as verified so its staff can register patients
+#[ic_cdk::update(guard = "caller_is_super_admin")]
+fn verify_hospital(id: HospitalId) -> Result<Hospital, Error> {
+    let caller = caller_principal()?;
+    match HOSPITAL_STORAGE.with(|hospitals| hospitals.borrow().get(&id)) {
+        Some(hospital) => {
+            let new_hospital = Hospital {
+                verified_by: Some(caller),
+                ..hospital
+            };
+            indexes::store_hospital(new_hospital.clone());
+            Ok(Hospital {
+                password: "******".to_string(),
+                ..new_hospital
+            })
+        }
+        None => Err(Error::NotFound {
+            msg: format!("hospital of id: {} not found", id),
+        }),
+    }
+}
+
+// link a patient the upgrade could not match to a hospital, so its donations can be confirmed
+#[ic_cdk::update(guard = "caller_is_super_admin")]
+fn link_patient_hospital(patient_id: PatientId, hospital_id: HospitalId) -> Result<Patient, Error> {
+    if !HOSPITAL_STORAGE.with(|s| s.borrow().contains_key(&hospital_id)) {
+        return Err(Error::NotFound {
+            msg: format!("hospital of id: {} not found", hospital_id),
+        });
+    }
+    match PATIENT_STORAGE.with(|patients| patients.borrow().get(&patient_id)) {
+        Some(patient) if patient.hospital_id.is_some() => Err(Error::InvalidPayload {
+            msg: format!(
+                "patient of id: {} is already linked to a hospital",
+                patient_id
+            ),
+        }),
+        Some(patient) => {
+            let new_patient = Patient {
+                hospital: String::new(),
+                hospital_id: Some(hospital_id),
+                ..patient
+            };
+            indexes::store_patient(new_patient.clone());
+            Ok(Patient {
+                password: "******".to_string(),
+                ..new_patient
+            })
         }
         None => Err(Error::NotFound {
-            msg: format!("hospital of id: {} not found", payload.recipient_id),
+            msg: format!("patient id:{} does not exist", patient_id),
         }),
     }
 }
 
 // Define query function to get a patient by ID
 #[ic_cdk::query]
-fn get_patient(id: u64) -> Result<Patient, Error> {
+fn get_patient(id: PatientId) -> Result<Patient, Error> {
     match PATIENT_STORAGE.with(|patients| patients.borrow().get(&id)) {
         Some(patient) => Ok(Patient {
             password: "******".to_string(),
@@ -399,102 +1512,231 @@ fn get_patient(id: u64) -> Result<Patient, Error> {
     }
 }
 
-// Query function to get all for incomplete donations patients
+// Query function to get a page of the patients with incomplete donations, optionally only those
+// of a blood group
 #[ic_cdk::query]
-fn get_incomplete_donation_patients() -> Result<Vec<Patient>, Error> {
-    // Retrieve all Patients from the storage
-    let patients_map: Vec<(u64, Patient)> = PATIENT_STORAGE.with(|s| s.borrow().iter().collect());
-    // Extract the Patients from the tuple and create a vector
-    let patients: Vec<Patient> = patients_map
-        .into_iter()
-        .map(|(_, patient)| patient)
-        .collect();
-
-    // Filter the patients by category
-    let incomplete_patients: Vec<Patient> = patients
-        .into_iter()
-        .filter(|patient| !patient.is_complete)
-        .collect();
-
-    // convert to Patient struct
-    let return_patients: Vec<Patient> = incomplete_patients
-        .into_iter()
-        .map(|patient| Patient {
-            password: "******".to_string(),
-            ..patient
-        })
-        .collect();
+fn get_incomplete_donation_patients(
+    blood_group: Option<String>,
+    options: ListOptions<PatientId>,
+) -> Result<Page<Patient, PatientId>, Error> {
+    schema::check_migrated()?;
+    let blood_group = blood_group
+        .map(|group| group.parse::<BloodGroup>())
+        .transpose()?;
+    // Read the open patients from the status index, or those of the group from the group index
+    let page = PATIENT_STORAGE.with(|s| {
+        let patients = s.borrow();
+        let read = |ids: &mut dyn Iterator<Item = PatientId>| {
+            pagination::paginate_ids(ids, &options, |id| patients.get(&id))
+        };
+        match blood_group {
+            Some(group) => indexes::scan(&PATIENT_GROUP_INDEX, &group, |ids| {
+                read(&mut ids.filter(|id| indexes::is_open(*id)))
+            }),
+            None => indexes::scan(&PATIENT_STATUS_INDEX, &indexes::OPEN, read),
+        }
+    })?;
 
     // Check if any patients are found
-    match return_patients.len() {
+    match page.total {
         0 => Err(Error::NotFound {
-            msg: format!("No patients for donations could be found"),
+            msg: "No patients for donations could be found".to_string(),
         }),
-        _ => Ok(return_patients),
+        _ => Ok(page.map(|patient| Patient {
+            password: "******".to_string(),
+            ..patient
+        })),
     }
 }
 
-// Update function to add a patient
-#[ic_cdk::update]
+// list a page of the patients registered at a hospital, for its admins and staff
+#[ic_cdk::query]
+fn get_patients_by_hospital(
+    hospital_id: HospitalId,
+    options: ListOptions<PatientId>,
+) -> Result<Page<Patient, PatientId>, Error> {
+    schema::check_migrated()?;
+    let caller = ic_cdk::caller();
+    if !HOSPITAL_STORAGE.with(|s| s.borrow().contains_key(&hospital_id)) {
+        return Err(Error::NotFound {
+            msg: format!("hospital of id: {} not found", hospital_id),
+        });
+    }
+    require_role(
+        &caller,
+        &[Role::HospitalAdmin, Role::HospitalStaff],
+        Some(hospital_id.0),
+    )?;
+    let page = PATIENT_STORAGE.with(|s| {
+        let patients = s.borrow();
+        indexes::scan(&PATIENT_HOSPITAL_INDEX, &hospital_id, |ids| {
+            pagination::paginate_ids(ids, &options, |id| patients.get(&id))
+        })
+    })?;
+    Ok(page.map(|patient| Patient {
+        password: "******".to_string(),
+        ..patient
+    }))
+}
+
+// Update function for staff of verified hospitals to add a patient, the caller becomes its guardian
+#[ic_cdk::update(guard = "caller_is_verified_hospital_staff")]
 fn add_patient(payload: PatientPayload) -> Result<Patient, Error> {
+    let caller = caller_principal()?;
     // validate payload
-    let validate_payload = payload.validate();
-    if validate_payload.is_err() {
-        return Err(Error::InvalidPayload {
-            msg: validate_payload.unwrap_err().to_string(),
-        });
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
     }
+    let hospital_id = payload.hospital_id;
+    match HOSPITAL_STORAGE.with(|s| s.borrow().get(&hospital_id)) {
+        Some(hospital) if hospital.verified_by.is_some() => {}
+        Some(_) => {
+            return Err(Error::Unauthorized {
+                msg: format!("hospital of id: {} is not verified", hospital_id),
+            })
+        }
+        None => {
+            return Err(Error::NotFound {
+                msg: format!("hospital of id: {} not found", hospital_id),
+            })
+        }
+    }
+    staff::require_permission(&caller, hospital_id, StaffPermission::RegisterPatients)?;
+    let blood_group: BloodGroup = payload.blood_group.parse()?;
+    let needs = payload.needs.unwrap_or_else(|| {
+        vec![ComponentPints {
+            component: Component::WholeBlood,
+            pints: payload.needed_pints,
+        }]
+    });
+    components::validate_needs(&needs)?;
 
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_id = *counter.borrow().get();
-            counter.borrow_mut().set(current_id + 1)
-        })
-        .expect("Cannot increment Ids");
+    let id = PatientId::next();
 
-    let patient = Patient {
+    let mut patient = Patient {
         id,
+        owner: Some(caller),
         name: payload.name.clone(),
         description: payload.description,
-        blood_group: payload.blood_group,
-        needed_pints: payload.needed_pints,
-        hospital: payload.hospital,
-        donations: 0,
-        password: payload.password,
-        is_complete: false,
-        donors_ids: vec![],
+        blood_group,
+        hospital_id: Some(hospital_id),
+        password: String::new(),
+        urgency: payload.urgency,
+        registered_at: Some(now()),
+        ..Default::default()
     };
+    patient.set_needs(
+        needs
+            .iter()
+            .map(|need| ComponentNeed {
+                component: need.component,
+                needed_pints: need.pints,
+                donations: 0,
+            })
+            .collect(),
+    )?;
 
-    match PATIENT_STORAGE.with(|s| s.borrow_mut().insert(id, patient.clone())) {
-        None => Ok(patient),
-        Some(_) => Err(Error::InvalidPayload {
+    // refuse before storing, an id already in use must never replace its record
+    if PATIENT_STORAGE.with(|s| s.borrow().contains_key(&id)) {
+        return Err(Error::InvalidPayload {
             msg: format!("Could not add patient name: {}", payload.name),
-        }),
+        });
+    }
+    indexes::store_patient(patient.clone());
+    roles::grant(
+        caller,
+        RoleGrant {
+            role: Role::PatientGuardian,
+            scope_id: Some(id.0),
+        },
+    );
+    staff::record_activity(
+        hospital_id,
+        caller,
+        StaffAction::RegisteredPatient { patient_id: id },
+    );
+    Ok(patient)
+}
+
+// The needs of a patient after an edit, donations already made are kept
+fn edited_needs(
+    patient: &Patient,
+    payload: &EditPatientPayload,
+) -> Result<Vec<ComponentNeed>, Error> {
+    let current = patient.needs();
+    let needs = match &payload.needs {
+        Some(needs) => needs.clone(),
+        None => match current[..] {
+            [need] => vec![ComponentPints {
+                component: need.component,
+                pints: payload.needed_pints,
+            }],
+            _ => {
+                return Err(Error::InvalidPayload {
+                    msg: "patient needs several components, edit them with needs".to_string(),
+                })
+            }
+        },
+    };
+    components::validate_needs(&needs)?;
+    if let Some(dropped) = current.iter().find(|need| {
+        need.donations > 0 && !needs.iter().any(|kept| kept.component == need.component)
+    }) {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "{:?} already has donations and cannot be removed",
+                dropped.component
+            ),
+        });
     }
+    Ok(needs
+        .iter()
+        .map(|need| ComponentNeed {
+            component: need.component,
+            needed_pints: need.pints,
+            donations: current
+                .iter()
+                .find(|existing| existing.component == need.component)
+                .map_or(0, |existing| existing.donations),
+        })
+        .collect())
 }
 
-// update function to edit a patient where authorizations is by password
-#[ic_cdk::update]
+// update function to edit a patient, only guardians of the patient can edit it
+#[ic_cdk::update(guard = "caller_is_authenticated")]
 fn edit_patient(payload: EditPatientPayload) -> Result<Patient, Error> {
+    let caller = caller_principal()?;
+    update_patient(caller, payload)
+}
+
+fn update_patient(caller: Principal, payload: EditPatientPayload) -> Result<Patient, Error> {
     let patient = PATIENT_STORAGE.with(|patients| patients.borrow().get(&payload.patient_id));
 
     match patient {
         Some(patient) => {
-            // check if the password provided matches patient
-            if patient.password != payload.password {
-                return Err(Error::Unauthorized {
-                    msg: format!("Unauthorized, password does not match, try again"),
-                });
-            }
+            // check if the caller is a guardian of the patient
+            require_role(&caller, &[Role::PatientGuardian], Some(patient.id.0))?;
 
-            let new_patient = Patient {
-                needed_pints: payload.needed_pints,
-                is_complete: payload.is_complete,
-                ..patient.clone()
-            };
+            let needs = edited_needs(&patient, &payload)?;
+            let mut new_patient = patient.clone();
+            new_patient.set_needs(needs)?;
+            if payload.urgency.is_some() {
+                new_patient.urgency = payload.urgency;
+            }
+            // the guardian can close the patient before every need is met, but a patient whose
+            // needs are met stays complete
+            if payload.is_complete == Some(true) {
+                new_patient.is_complete = true;
+            }
 
-            match PATIENT_STORAGE.with(|s| s.borrow_mut().insert(patient.id, new_patient.clone())) {
-                Some(_) => Ok(new_patient),
+            match indexes::store_patient(new_patient.clone()) {
+                Some(_) => {
+                    // a raised need is first covered by donors waiting on standby
+                    if !new_patient.is_complete {
+                        pledges::promote_standby(patient.id);
+                    }
+                    Ok(new_patient)
+                }
                 None => Err(Error::InvalidPayload {
                     msg: format!("Could not edit patient name: {}", patient.name),
                 }),
@@ -506,117 +1748,216 @@ fn edit_patient(payload: EditPatientPayload) -> Result<Patient, Error> {
     }
 }
 
-// function to pledge to patient
-#[ic_cdk::update]
-fn pledge_to_patient(payload: PledgePayload) -> Result<String, Error> {
-    // get patient
-    let patient = PATIENT_STORAGE.with(|patients| patients.borrow().get(&payload.recipient_id));
-    match patient {
+// bind a patient created before principal ownership to the caller using its legacy password
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn claim_patient(payload: ClaimPayload) -> Result<Patient, Error> {
+    let caller = caller_principal()?;
+    match PATIENT_STORAGE.with(|patients| patients.borrow().get(&PatientId(payload.id))) {
         Some(patient) => {
-            // check if the password provided matches patient
-            if patient.password != payload.password {
-                return Err(Error::Unauthorized {
-                    msg: format!("Unauthorized, password does not match, try again"),
-                });
-            }
+            authorize_legacy_account(
+                &patient.owner,
+                PATIENT_ACCOUNT,
+                patient.id.0,
+                &patient.password,
+                &payload.password,
+            )?;
+            CREDENTIAL_STORAGE.with(|s| s.borrow_mut().remove(&(PATIENT_ACCOUNT, patient.id.0)));
+            let new_patient = Patient {
+                owner: Some(caller),
+                password: String::new(),
+                ..patient
+            };
+            indexes::store_patient(new_patient.clone());
+            roles::grant(
+                caller,
+                RoleGrant {
+                    role: Role::PatientGuardian,
+                    scope_id: Some(new_patient.id.0),
+                },
+            );
+            Ok(new_patient)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("patient of id: {} not found", payload.id),
+        }),
+    }
+}
 
-            // check if donor has enough balance
-            let donor = DONOR_STORAGE.with(|donors| donors.borrow().get(&payload.donor_id));
-            match donor {
-                Some(donor) => {
-                    if patient.donations >= patient.needed_pints {
-                        return Err(Error::InvalidPayload {
-                            msg: format!(
-                                "Patient has already reached their needed donation target"
-                            ),
-                        });
-                    }
-                    let mut new_donor_beneficiaries = donor.beneficiaries.clone();
-                    new_donor_beneficiaries.push(patient.id);
-                    let new_donor = Donor {
-                        beneficiaries: new_donor_beneficiaries,
-                        ..donor
-                    };
-                    // update donor in storage
-                    match DONOR_STORAGE.with(|s| s.borrow_mut().insert(donor.id, new_donor.clone()))
-                    {
-                        Some(_) => {
-                            // update patient
-                            let mut new_patient_donors_ids = patient.donors_ids.clone();
-                            let is_complete = patient.needed_pints <= (patient.donations + payload.pints_pledge);
-                            new_patient_donors_ids.push(donor.id);
-                            let new_patient = Patient {
-                                donors_ids: new_patient_donors_ids, 
-                                is_complete,
-                                donations: patient.donations + payload.pints_pledge,
-                                ..patient.clone()
-                            };
-                            // update patient in storage
-                            match PATIENT_STORAGE
-                                .with(|s| s.borrow_mut().insert(patient.id, new_patient.clone()))
-                            {
-                                Some(_) => Ok(format!(
-                                    "Succesfully pledged to patient {}, visit hospital: {} to donate",
-                                    patient.name, patient.hospital
-                                )),
-                                None => Err(Error::InvalidPayload {
-                                    msg: format!("Could not update patient"),
-                                }),
-                            }
-                        }
-                        None => Err(Error::InvalidPayload {
-                            msg: format!("Could not update donor"),
-                        }),
-                    }
-                }
-                None => Err(Error::NotFound {
-                    msg: format!("Donor of id: {} not found", payload.donor_id),
-                }),
+// rotate the password of a patient that has not been claimed by a principal yet
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+async fn change_patient_password(payload: ChangePasswordPayload) -> Result<String, Error> {
+    caller_principal()?;
+    // validate payload
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+
+    // fetch the salt first so the record cannot change between the check and the write
+    let salt = credentials::random_salt().await?;
+    match PATIENT_STORAGE.with(|patients| patients.borrow().get(&PatientId(payload.id))) {
+        Some(patient) => {
+            rotate_legacy_password(
+                &patient.owner,
+                PATIENT_ACCOUNT,
+                patient.id.0,
+                &patient.password,
+                &payload,
+                &salt,
+            )?;
+            // drop the plaintext copy if the upgrade migration has not reached this record yet
+            if !patient.password.is_empty() {
+                let new_patient = Patient {
+                    password: String::new(),
+                    ..patient.clone()
+                };
+                indexes::store_patient(new_patient);
             }
+            Ok(format!("Password changed for patient {}", patient.name))
         }
         None => Err(Error::NotFound {
-            msg: format!("patient of id: {} not found", payload.recipient_id),
+            msg: format!("patient of id: {} not found", payload.id),
         }),
     }
 }
 
-// add donor
-#[ic_cdk::update]
+// add donor owned by the caller
+#[ic_cdk::update(guard = "caller_is_authenticated")]
 fn add_donor(payload: DonorPayload) -> Result<Donor, Error> {
+    let caller = caller_principal()?;
     // validate payload
-    let validate_payload = payload.validate();
-    if validate_payload.is_err() {
-        return Err(Error::InvalidPayload {
-            msg: validate_payload.unwrap_err().to_string(),
-        });
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
     }
+    let blood_group: BloodGroup = payload.blood_group.parse()?;
 
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_id = *counter.borrow().get();
-            counter.borrow_mut().set(current_id + 1)
-        })
-        .expect("Cannot increment Ids");
+    let id = DonorId::next();
 
     let donor = Donor {
         id,
+        owner: Some(caller),
         name: payload.name.clone(),
-        blood_group: payload.blood_group,
-        password: payload.password,
-        beneficiaries: vec![],
+        blood_group,
+        password: String::new(),
+        beneficiaries: None,
+        city: payload.city.filter(|city| !city.trim().is_empty()),
     };
 
-    match DONOR_STORAGE.with(|s| s.borrow_mut().insert(id, donor.clone())) {
-        None => Ok(donor),
-        Some(_) => Err(Error::InvalidPayload {
+    // refuse before storing, an id already in use must never replace its record
+    if DONOR_STORAGE.with(|s| s.borrow().contains_key(&id)) {
+        return Err(Error::InvalidPayload {
             msg: format!("Could not add donor name: {}", payload.name),
+        });
+    }
+    indexes::store_donor(donor.clone());
+    roles::grant(
+        caller,
+        RoleGrant {
+            role: Role::Donor,
+            scope_id: Some(id.0),
+        },
+    );
+    Ok(donor)
+}
+
+// update function for a donor to set their home city
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn edit_donor(payload: EditDonorPayload) -> Result<Donor, Error> {
+    let caller = caller_principal()?;
+    require_role(&caller, &[Role::Donor], Some(payload.donor_id.0))?;
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    match DONOR_STORAGE.with(|donors| donors.borrow().get(&payload.donor_id)) {
+        Some(donor) => {
+            let city = payload.city.trim();
+            let new_donor = Donor {
+                city: (!city.is_empty()).then(|| city.to_string()),
+                ..donor
+            };
+            indexes::store_donor(new_donor.clone());
+            Ok(new_donor)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("donor id:{} does not exist", payload.donor_id),
+        }),
+    }
+}
+
+// bind a donor created before principal ownership to the caller using its legacy password
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn claim_donor(payload: ClaimPayload) -> Result<Donor, Error> {
+    let caller = caller_principal()?;
+    match DONOR_STORAGE.with(|donors| donors.borrow().get(&DonorId(payload.id))) {
+        Some(donor) => {
+            authorize_legacy_account(
+                &donor.owner,
+                DONOR_ACCOUNT,
+                donor.id.0,
+                &donor.password,
+                &payload.password,
+            )?;
+            CREDENTIAL_STORAGE.with(|s| s.borrow_mut().remove(&(DONOR_ACCOUNT, donor.id.0)));
+            let new_donor = Donor {
+                owner: Some(caller),
+                password: String::new(),
+                ..donor
+            };
+            indexes::store_donor(new_donor.clone());
+            roles::grant(
+                caller,
+                RoleGrant {
+                    role: Role::Donor,
+                    scope_id: Some(new_donor.id.0),
+                },
+            );
+            Ok(new_donor)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("donor id:{} does not exist", payload.id),
+        }),
+    }
+}
+
+// rotate the password of a donor that has not been claimed by a principal yet
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+async fn change_donor_password(payload: ChangePasswordPayload) -> Result<String, Error> {
+    caller_principal()?;
+    // validate payload
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+
+    // fetch the salt first so the record cannot change between the check and the write
+    let salt = credentials::random_salt().await?;
+    match DONOR_STORAGE.with(|donors| donors.borrow().get(&DonorId(payload.id))) {
+        Some(donor) => {
+            rotate_legacy_password(
+                &donor.owner,
+                DONOR_ACCOUNT,
+                donor.id.0,
+                &donor.password,
+                &payload,
+                &salt,
+            )?;
+            // drop the plaintext copy if the upgrade migration has not reached this record yet
+            if !donor.password.is_empty() {
+                let new_donor = Donor {
+                    password: String::new(),
+                    ..donor.clone()
+                };
+                indexes::store_donor(new_donor);
+            }
+            Ok(format!("Password changed for donor {}", donor.name))
+        }
+        None => Err(Error::NotFound {
+            msg: format!("donor id:{} does not exist", payload.id),
         }),
     }
 }
 
 // get donor by ID
 #[ic_cdk::query]
-fn get_donor_by_id(id: u64) -> Result<Donor, Error> {
+fn get_donor_by_id(id: DonorId) -> Result<Donor, Error> {
     match DONOR_STORAGE.with(|donors| donors.borrow().get(&id)) {
         Some(donor) => Ok(Donor {
             password: "******".to_string(),
@@ -628,14 +1969,223 @@ fn get_donor_by_id(id: u64) -> Result<Donor, Error> {
     }
 }
 
+// list a page of the donors of a blood group, for staff of verified hospitals
+#[ic_cdk::query(guard = "caller_is_verified_hospital_staff")]
+fn get_donors_by_blood_group(
+    blood_group: String,
+    options: ListOptions<DonorId>,
+) -> Result<Page<Donor, DonorId>, Error> {
+    schema::check_migrated()?;
+    let blood_group: BloodGroup = blood_group.parse()?;
+    let page = DONOR_STORAGE.with(|s| {
+        let donors = s.borrow();
+        indexes::scan(&DONOR_GROUP_INDEX, &blood_group, |ids| {
+            pagination::paginate_ids(ids, &options, |id| donors.get(&id))
+        })
+    })?;
+    Ok(page.map(|donor| Donor {
+        password: "******".to_string(),
+        ..donor
+    }))
+}
+
 // Define an Error enum for handling errors
-#[derive(candid::CandidType, Deserialize, Serialize)]
+#[derive(candid::CandidType, Deserialize, Serialize, Debug)]
 enum Error {
     NotFound { msg: String },
     AlreadyInit { msg: String },
     InvalidPayload { msg: String },
     Unauthorized { msg: String },
+    IncompatibleBloodGroup { msg: String },
+    IneligibleDonor { msg: String },
+    Migrating { msg: String },
 }
 
 // Candid generator for exporting the Candid interface
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hospital(id: u64, name: &str, city: &str) -> Hospital {
+        Hospital {
+            id: HospitalId(id),
+            name: name.to_string(),
+            city: city.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn free_text_names_match_one_hospital() {
+        let hospitals = [
+            hospital(1, "Kenyatta National Hospital", "Nairobi"),
+            hospital(2, "Aga Khan Hospital", "Nairobi"),
+            hospital(3, "Aga Khan Hospital", "Mombasa"),
+        ];
+        assert_eq!(
+            match_hospital("kenyatta national hospital", &hospitals),
+            Some(HospitalId(1))
+        );
+        assert_eq!(match_hospital("Kenyatta", &hospitals), Some(HospitalId(1)));
+        assert_eq!(
+            match_hospital("Aga Khan Hospital, Mombasa", &hospitals),
+            Some(HospitalId(3))
+        );
+        // two hospitals share the name and the city is missing
+        assert_eq!(match_hospital("Aga Khan Hospital", &hospitals), None);
+        assert_eq!(match_hospital("Mater Hospital", &hospitals), None);
+        assert_eq!(match_hospital(" - ", &hospitals), None);
+    }
+
+    #[test]
+    fn need_totals_never_overflow() {
+        let need = |component, donations| ComponentNeed {
+            component,
+            needed_pints: 1,
+            donations,
+        };
+        let mut patient = Patient::default();
+        patient
+            .set_needs(vec![
+                need(Component::Plasma, 2),
+                need(Component::Platelets, 0),
+            ])
+            .unwrap();
+        assert_eq!((patient.needed_pints, patient.donations), (2, 2));
+        assert!(!patient.is_complete);
+        assert!(patient
+            .set_needs(vec![
+                need(Component::Plasma, u32::MAX),
+                need(Component::Platelets, 1)
+            ])
+            .is_err());
+        assert_eq!(patient.donations, 2);
+    }
+
+    #[test]
+    fn guardians_close_patients_but_cannot_reopen_met_needs() {
+        schema::init_version();
+        let guardian = Principal::from_slice(&[2; 10]);
+        let patient_id = PatientId(2);
+        let mut patient = Patient {
+            id: patient_id,
+            name: "Jane".to_string(),
+            ..Default::default()
+        };
+        patient
+            .set_needs(vec![ComponentNeed {
+                component: Component::WholeBlood,
+                needed_pints: 2,
+                donations: 2,
+            }])
+            .unwrap();
+        indexes::store_patient(patient);
+        roles::grant(
+            guardian,
+            RoleGrant {
+                role: Role::PatientGuardian,
+                scope_id: Some(patient_id.0),
+            },
+        );
+        let edit = |needed_pints, is_complete| EditPatientPayload {
+            patient_id,
+            needed_pints,
+            is_complete,
+            ..Default::default()
+        };
+        // the needs are met, asking to reopen is ignored
+        assert!(
+            update_patient(guardian, edit(2, Some(false)))
+                .unwrap()
+                .is_complete
+        );
+        // raising the need reopens the patient
+        assert!(!update_patient(guardian, edit(3, None)).unwrap().is_complete);
+        // the guardian closes it before the need is met
+        let patient = update_patient(guardian, edit(3, Some(true))).unwrap();
+        assert!(patient.is_complete);
+        assert_eq!((patient.needed_pints, patient.donations), (3, 2));
+        // only guardians edit the patient
+        assert!(update_patient(Principal::from_slice(&[3; 10]), edit(4, None)).is_err());
+    }
+
+    #[test]
+    fn payloads_longer_than_a_record_holds_are_refused() {
+        schema::init_version();
+        let admin = Principal::from_slice(&[1; 29]);
+        // four byte characters, the longest texts a payload accepts
+        let text = |length: u64| "\u{1d11e}".repeat(length as usize);
+        let payload = HospitalPayload {
+            name: text(MAX_NAME_LENGTH),
+            address: text(MAX_ADDRESS_LENGTH),
+            city: text(MAX_CITY_LENGTH),
+            location: Some(GeoPoint {
+                latitude: 1.0,
+                longitude: 1.0,
+            }),
+        };
+        let hospital = create_hospital(admin, payload.clone()).unwrap();
+        let hospital = Hospital {
+            verified_by: Some(admin),
+            donations: u32::MAX,
+            ..hospital
+        };
+        assert!(hospital.to_bytes().len() <= Hospital::MAX_SIZE as usize);
+        let patient = Patient {
+            id: PatientId(u64::MAX),
+            owner: Some(admin),
+            name: text(MAX_NAME_LENGTH),
+            hospital_id: Some(hospital.id),
+            description: text(MAX_DESCRIPTION_LENGTH),
+            urgency: Some(Urgency::default()),
+            registered_at: Some(u64::MAX),
+            needs: Some(
+                [
+                    Component::WholeBlood,
+                    Component::Plasma,
+                    Component::Platelets,
+                    Component::PackedRedCells,
+                    Component::Cryoprecipitate,
+                ]
+                .into_iter()
+                .map(|component| ComponentNeed {
+                    component,
+                    needed_pints: u32::MAX,
+                    donations: u32::MAX,
+                })
+                .collect(),
+            ),
+            ..Default::default()
+        };
+        assert!(patient.to_bytes().len() <= Patient::MAX_SIZE as usize);
+        let donor = Donor {
+            id: DonorId(u64::MAX),
+            owner: Some(admin),
+            name: text(MAX_NAME_LENGTH),
+            city: Some(text(MAX_CITY_LENGTH)),
+            ..Default::default()
+        };
+        assert!(donor.to_bytes().len() <= Donor::MAX_SIZE as usize);
+
+        // one character more is refused
+        let long = HospitalPayload {
+            city: text(MAX_CITY_LENGTH + 1),
+            ..payload
+        };
+        assert!(matches!(
+            create_hospital(admin, long),
+            Err(Error::InvalidPayload { .. })
+        ));
+        let edit = EditHospitalPayload {
+            hospital_id: hospital.id,
+            name: text(MAX_NAME_LENGTH + 1),
+            location: None,
+        };
+        assert!(matches!(
+            update_hospital(admin, edit),
+            Err(Error::InvalidPayload { .. })
+        ));
+    }
+}
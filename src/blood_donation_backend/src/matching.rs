@@ -0,0 +1,504 @@
+use crate::blood_group::{BloodGroup, TYPED_GROUPS};
+use crate::components::Component;
+use crate::eligibility;
+use crate::geo::{self, GeoPoint};
+use crate::ids::{DonorId, HospitalId, PatientId};
+use crate::indexes::{self, IndexText};
+use crate::pledges;
+use crate::roles::{self, caller_is_verified_hospital_staff, Role};
+use crate::schema;
+use crate::{
+    now, Donor, Error, Hospital, Patient, Urgency, DONOR_GROUP_INDEX, DONOR_STORAGE,
+    HOSPITAL_STORAGE, PATIENT_STATUS_INDEX, PATIENT_STORAGE,
+};
+use candid::Principal;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+
+const DAY: u64 = 86_400_000_000_000;
+
+// Matches returned when a request sets no limit, and the most a request can ask for
+const DEFAULT_MATCHES: u32 = 20;
+const MAX_MATCHES: u32 = 100;
+
+// Points of the parts of a match score
+const SAME_GROUP_POINTS: u32 = 30;
+const COMPATIBLE_GROUP_POINTS: u32 = 20;
+const URGENT_POINTS: u32 = 40;
+const CRITICAL_POINTS: u32 = 80;
+const POINTS_PER_PINT: u32 = 2;
+const MAX_NEED_POINTS: u32 = 20;
+// full points in the same place, one point less every `KM_PER_POINT`
+const NEARBY_POINTS: u32 = 30;
+const KM_PER_POINT: f64 = 10.0;
+// one point per day waited
+const MAX_WAITING_POINTS: u32 = 30;
+
+// Why a donor and a patient were matched, `total` is the sum of the other parts
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) struct MatchScore {
+    compatibility: u32,
+    urgency: u32,
+    need: u32,
+    distance: u32,
+    waiting: u32,
+    total: u32,
+}
+
+// A patient a donor can give to now, `components` are the needs the donor can cover and
+// `distance_km` is None when the donor's home or the hospital has no known location
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct PatientMatch {
+    patient: Patient,
+    components: Vec<Component>,
+    distance_km: Option<f64>,
+    score: MatchScore,
+}
+
+// A donor who can give to a patient now
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct DonorMatch {
+    donor: Donor,
+    components: Vec<Component>,
+    distance_km: Option<f64>,
+    score: MatchScore,
+}
+
+// How far a donor lives from a patient's hospital
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Distance {
+    Km(f64),
+    // in the same city, without locations to measure
+    SameCity,
+    Unknown,
+}
+
+impl Distance {
+    fn km(self) -> Option<f64> {
+        match self {
+            Distance::Km(km) => Some(km),
+            _ => None,
+        }
+    }
+
+    fn points(self) -> u32 {
+        match self {
+            Distance::Km(km) => NEARBY_POINTS.saturating_sub((km / KM_PER_POINT) as u32),
+            Distance::SameCity => NEARBY_POINTS,
+            Distance::Unknown => 0,
+        }
+    }
+}
+
+// Where a donor lives as far as it is known: their city, located at the centre of its hospitals
+struct Home {
+    city: Option<IndexText>,
+    location: Option<GeoPoint>,
+}
+
+impl Home {
+    // `located` keeps the cities already located while matching many donors
+    fn of(donor: &Donor, located: &mut BTreeMap<IndexText, Option<GeoPoint>>) -> Home {
+        let city = donor.city.as_deref().map(IndexText::new);
+        let location = city.as_ref().and_then(|city| {
+            *located
+                .entry(city.clone())
+                .or_insert_with(|| geo::city_location(city))
+        });
+        Home { city, location }
+    }
+
+    fn distance_to(&self, hospital: Option<&Hospital>) -> Distance {
+        let hospital = match hospital {
+            Some(hospital) => hospital,
+            None => return Distance::Unknown,
+        };
+        match (self.location, hospital.location) {
+            (Some(home), Some(site)) => Distance::Km(home.distance_km(site)),
+            _ if self
+                .city
+                .as_ref()
+                .is_some_and(|city| *city == IndexText::new(&hospital.city)) =>
+            {
+                Distance::SameCity
+            }
+            _ => Distance::Unknown,
+        }
+    }
+}
+
+fn score(
+    donor_group: BloodGroup,
+    patient: &Patient,
+    remaining: u32,
+    distance: Distance,
+    at: u64,
+) -> MatchScore {
+    let compatibility = match donor_group == patient.blood_group {
+        true => SAME_GROUP_POINTS,
+        false => COMPATIBLE_GROUP_POINTS,
+    };
+    let urgency = match patient.urgency() {
+        Urgency::Routine => 0,
+        Urgency::Urgent => URGENT_POINTS,
+        Urgency::Critical => CRITICAL_POINTS,
+    };
+    let need = remaining
+        .saturating_mul(POINTS_PER_PINT)
+        .min(MAX_NEED_POINTS);
+    let waiting = match patient.registered_at {
+        Some(registered_at) => {
+            (at.saturating_sub(registered_at) / DAY).min(MAX_WAITING_POINTS as u64) as u32
+        }
+        // patients stored before registration times were recorded have waited the longest
+        None => MAX_WAITING_POINTS,
+    };
+    let distance = distance.points();
+    MatchScore {
+        compatibility,
+        urgency,
+        need,
+        distance,
+        waiting,
+        total: compatibility + urgency + need + distance + waiting,
+    }
+}
+
+// Components of a patient's needs a donor can give from their eligible collections, with the
+// pints still wanted of them once pledges awaiting collection are counted
+fn coverable_needs(
+    donor_group: BloodGroup,
+    eligible: &[Component],
+    patient: &Patient,
+) -> (Vec<Component>, u32) {
+    let mut components = vec![];
+    let mut remaining = 0;
+    for need in patient.needs() {
+        let wanted = pledges::remaining_need(patient, need.component, None);
+        if wanted > 0
+            && need.component.compatible(donor_group, patient.blood_group)
+            && eligible.contains(&need.component.collected_as())
+        {
+            components.push(need.component);
+            remaining += wanted;
+        }
+    }
+    (components, remaining)
+}
+
+// Keep the best matches, highest score first and the oldest record among equal scores
+fn best<T>(matches: impl Iterator<Item = (MatchScore, u64, T)>, limit: Option<u32>) -> Vec<T> {
+    let limit = limit.unwrap_or(DEFAULT_MATCHES).clamp(1, MAX_MATCHES) as usize;
+    let mut window = BTreeMap::new();
+    for (score, id, item) in matches {
+        window.insert((Reverse(score.total), id), item);
+        if window.len() > limit {
+            window.pop_last();
+        }
+    }
+    window.into_values().collect()
+}
+
+fn hospital_of(
+    id: Option<HospitalId>,
+    hospitals: &mut BTreeMap<HospitalId, Option<Hospital>>,
+) -> Option<Hospital> {
+    let id = id?;
+    hospitals
+        .entry(id)
+        .or_insert_with(|| HOSPITAL_STORAGE.with(|s| s.borrow().get(&id)))
+        .clone()
+}
+
+// Open patients the donor can give to at `at`, best matches first
+fn patients_for_donor(
+    donor: &Donor,
+    at: u64,
+    limit: Option<u32>,
+) -> Result<Vec<PatientMatch>, Error> {
+    pledges::check_no_open_pledge(donor.id)?;
+    let eligible =
+        eligibility::eligible_collections(donor.id, &pledges::collections_of_donor(donor.id), at)?;
+    let home = Home::of(donor, &mut BTreeMap::new());
+    let mut hospitals = BTreeMap::new();
+    let ids: Vec<PatientId> =
+        indexes::scan(&PATIENT_STATUS_INDEX, &indexes::OPEN, |ids| ids.collect());
+    let matches = ids
+        .into_iter()
+        .filter_map(|id| PATIENT_STORAGE.with(|s| s.borrow().get(&id)))
+        .filter_map(|patient| {
+            let (components, remaining) = coverable_needs(donor.blood_group, &eligible, &patient);
+            if components.is_empty() {
+                return None;
+            }
+            let hospital = hospital_of(patient.hospital_id, &mut hospitals);
+            let distance = home.distance_to(hospital.as_ref());
+            let score = score(donor.blood_group, &patient, remaining, distance, at);
+            Some((
+                score,
+                patient.id.0,
+                PatientMatch {
+                    patient: Patient {
+                        password: "******".to_string(),
+                        ..patient
+                    },
+                    components,
+                    distance_km: distance.km(),
+                    score,
+                },
+            ))
+        });
+    Ok(best(matches, limit))
+}
+
+// Donors who can give to the patient at `at`, best matches first
+fn donors_for_patient(
+    patient: &Patient,
+    at: u64,
+    limit: Option<u32>,
+) -> Result<Vec<DonorMatch>, Error> {
+    let needs: Vec<Component> = patient
+        .needs()
+        .iter()
+        .filter(|need| pledges::remaining_need(patient, need.component, None) > 0)
+        .map(|need| need.component)
+        .collect();
+    if patient.is_complete || needs.is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "patient of id: {} needs no more donations than are pledged",
+                patient.id
+            ),
+        });
+    }
+    let hospital = hospital_of(patient.hospital_id, &mut BTreeMap::new());
+    let mut located = BTreeMap::new();
+    let mut ids = vec![];
+    for group in TYPED_GROUPS.into_iter().chain([BloodGroup::Unknown]) {
+        if needs
+            .iter()
+            .any(|component| component.compatible(group, patient.blood_group))
+        {
+            indexes::scan(&DONOR_GROUP_INDEX, &group, |group_ids| {
+                ids.extend(group_ids)
+            });
+        }
+    }
+    let matches = ids
+        .into_iter()
+        .filter(|id| !pledges::has_open_pledge(*id))
+        .filter_map(|id| DONOR_STORAGE.with(|s| s.borrow().get(&id)))
+        .filter_map(|donor| {
+            let eligible = eligibility::eligible_collections(
+                donor.id,
+                &pledges::collections_of_donor(donor.id),
+                at,
+            )
+            .ok()?;
+            let (components, remaining) = coverable_needs(donor.blood_group, &eligible, patient);
+            if components.is_empty() {
+                return None;
+            }
+            let distance = Home::of(&donor, &mut located).distance_to(hospital.as_ref());
+            let score = score(donor.blood_group, patient, remaining, distance, at);
+            Some((
+                score,
+                donor.id.0,
+                DonorMatch {
+                    donor: Donor {
+                        password: "******".to_string(),
+                        ..donor
+                    },
+                    components,
+                    distance_km: distance.km(),
+                    score,
+                },
+            ))
+        });
+    Ok(best(matches, limit))
+}
+
+// rank the open patients a donor can give to now by blood compatibility, urgency, remaining
+// need, distance from the donor's home city and time waiting, for the donor and hospital staff
+#[ic_cdk::query]
+fn match_patients_for_donor(
+    donor_id: DonorId,
+    limit: Option<u32>,
+) -> Result<Vec<PatientMatch>, Error> {
+    schema::check_migrated()?;
+    let caller = ic_cdk::caller();
+    if !roles::is_verified_hospital_staff(&caller) {
+        roles::require_role(&caller, &[Role::Donor], Some(donor_id.0))?;
+    }
+    match DONOR_STORAGE.with(|s| s.borrow().get(&donor_id)) {
+        Some(donor) => patients_for_donor(&donor, now(), limit),
+        None => Err(Error::NotFound {
+            msg: format!("Donor of id: {} not found", donor_id),
+        }),
+    }
+}
+
+// Donors for a patient, for staff of the hospital the patient is registered at
+fn donor_matches(
+    caller: &Principal,
+    patient_id: PatientId,
+    at: u64,
+    limit: Option<u32>,
+) -> Result<Vec<DonorMatch>, Error> {
+    match PATIENT_STORAGE.with(|s| s.borrow().get(&patient_id)) {
+        Some(patient) => {
+            roles::require_role(
+                caller,
+                &[Role::HospitalAdmin, Role::HospitalStaff],
+                patient.hospital_id.map(|id| id.0),
+            )?;
+            donors_for_patient(&patient, at, limit)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("patient of id: {} not found", patient_id),
+        }),
+    }
+}
+
+// rank the donors who can give to a patient now the same way, for staff of the verified hospital
+// the patient is registered at
+#[ic_cdk::query(guard = "caller_is_verified_hospital_staff")]
+fn match_donors_for_patient(
+    patient_id: PatientId,
+    limit: Option<u32>,
+) -> Result<Vec<DonorMatch>, Error> {
+    schema::check_migrated()?;
+    donor_matches(&ic_cdk::caller(), patient_id, now(), limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roles::RoleGrant;
+
+    const AT: u64 = 100 * DAY;
+
+    fn hospital(id: u64, city: &str, latitude: f64, longitude: f64) {
+        indexes::store_hospital(Hospital {
+            id: HospitalId(id),
+            city: city.to_string(),
+            location: Some(GeoPoint {
+                latitude,
+                longitude,
+            }),
+            ..Default::default()
+        });
+    }
+
+    fn patient(id: u64, group: BloodGroup, hospital_id: u64, urgency: Urgency, waited: u64) {
+        indexes::store_patient(Patient {
+            id: PatientId(id),
+            blood_group: group,
+            hospital_id: Some(HospitalId(hospital_id)),
+            needed_pints: 2,
+            donations: 1,
+            is_complete: id == 3,
+            urgency: Some(urgency),
+            registered_at: Some(AT - waited * DAY),
+            ..Default::default()
+        });
+    }
+
+    fn donor(id: u64, group: BloodGroup, city: Option<&str>) -> Donor {
+        let donor = Donor {
+            id: DonorId(id),
+            blood_group: group,
+            city: city.map(str::to_string),
+            ..Default::default()
+        };
+        indexes::store_donor(donor.clone());
+        donor
+    }
+
+    fn setup() {
+        hospital(1, "Nairobi", -1.2864, 36.8172);
+        hospital(2, "Mombasa", -4.0435, 39.6682);
+        patient(1, BloodGroup::APositive, 1, Urgency::Routine, 0);
+        patient(2, BloodGroup::ONegative, 2, Urgency::Critical, 0);
+        patient(3, BloodGroup::ABPositive, 1, Urgency::Critical, 0);
+        patient(4, BloodGroup::BPositive, 1, Urgency::Urgent, 10);
+    }
+
+    #[test]
+    fn patients_are_ranked_for_a_donor() {
+        setup();
+        let matches =
+            patients_for_donor(&donor(1, BloodGroup::ONegative, Some("nairobi")), AT, None)
+                .unwrap();
+        let ranked: Vec<(u64, u32)> = matches
+            .iter()
+            .map(|m| (m.patient.id.0, m.score.total))
+            .collect();
+        // the critical patient far away, the urgent one waiting for 10 days in the donor's city,
+        // then the routine one; the complete patient is left out
+        assert_eq!(ranked, [(2, 112), (4, 102), (1, 52)]);
+        assert_eq!(matches[2].distance_km, Some(0.0));
+        assert_eq!(matches[0].score.distance, 0);
+        assert_eq!(matches[0].components, [Component::WholeBlood]);
+
+        let matches = patients_for_donor(&donor(2, BloodGroup::APositive, None), AT, None).unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].distance_km, None);
+    }
+
+    #[test]
+    fn donors_are_ranked_for_a_patient() {
+        setup();
+        donor(1, BloodGroup::ONegative, Some("Nairobi"));
+        donor(2, BloodGroup::APositive, None);
+        donor(3, BloodGroup::BPositive, Some("Nairobi"));
+        donor(4, BloodGroup::APositive, Some("Nairobi"));
+        let patient = PATIENT_STORAGE.with(|s| s.borrow().get(&PatientId(1)).unwrap());
+        let ranked: Vec<(u64, u32)> = donors_for_patient(&patient, AT, Some(2))
+            .unwrap()
+            .iter()
+            .map(|m| (m.donor.id.0, m.score.total))
+            .collect();
+        // the donor of the same group in the patient's city comes first, the B donor cannot give
+        // and the donor without a city is past the limit
+        assert_eq!(ranked, [(4, 62), (1, 52)]);
+
+        let complete = PATIENT_STORAGE.with(|s| s.borrow().get(&PatientId(3)).unwrap());
+        assert!(matches!(
+            donors_for_patient(&complete, AT, None),
+            Err(Error::InvalidPayload { .. })
+        ));
+    }
+
+    #[test]
+    fn only_staff_of_the_patients_hospital_match_donors() {
+        setup();
+        donor(1, BloodGroup::ONegative, Some("Nairobi"));
+        let staff = |n: u8, hospital_id: u64| {
+            let principal = Principal::from_slice(&[n; 10]);
+            roles::grant(
+                principal,
+                RoleGrant {
+                    role: Role::HospitalStaff,
+                    scope_id: Some(hospital_id),
+                },
+            );
+            principal
+        };
+        let (nairobi, mombasa) = (staff(1, 1), staff(2, 2));
+        assert_eq!(
+            donor_matches(&nairobi, PatientId(1), AT, None)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(matches!(
+            donor_matches(&mombasa, PatientId(1), AT, None),
+            Err(Error::Unauthorized { .. })
+        ));
+        assert!(matches!(
+            donor_matches(&nairobi, PatientId(9), AT, None),
+            Err(Error::NotFound { .. })
+        ));
+    }
+}
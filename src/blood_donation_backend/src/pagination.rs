@@ -0,0 +1,384 @@
+use crate::{Error, Memory};
+use ic_stable_structures::{BoundedStorable, StableBTreeMap};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::ops::Bound;
+
+// Most items returned by a single page of a list
+const MAX_PAGE_SIZE: u32 = 100;
+// Items returned when a list request sets no limit
+const DEFAULT_PAGE_SIZE: u32 = 20;
+
+// Orders a list can be read in. Ids are handed out in creation order, so `Created` is id order
+// and every list supports it.
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug, Default,
+)]
+pub(crate) enum SortBy {
+    Name,
+    City,
+    #[default]
+    Created,
+    Urgency,
+}
+
+// How to read a page of a list, `start_after` is the `next` cursor of the previous page
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ListOptions<C> {
+    pub(crate) start_after: Option<C>,
+    pub(crate) limit: Option<u32>,
+    pub(crate) sort_by: Option<SortBy>,
+    pub(crate) descending: Option<bool>,
+}
+
+// A page of a list with the number of items in the whole list, `next` is None on the last page
+#[derive(candid::CandidType, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) struct Page<T, C> {
+    pub(crate) items: Vec<T>,
+    pub(crate) next: Option<C>,
+    pub(crate) total: u64,
+}
+
+impl<T, C> Page<T, C> {
+    pub(crate) fn map<U>(self, f: impl FnMut(T) -> U) -> Page<U, C> {
+        Page {
+            items: self.items.into_iter().map(f).collect(),
+            next: self.next,
+            total: self.total,
+        }
+    }
+}
+
+// Key an item sorts by, items with equal keys are ordered by their cursor
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub(crate) enum SortKey {
+    Unsorted,
+    Text(String),
+    // higher ranks first
+    Rank(Reverse<u64>),
+}
+
+// Items that can be listed a page at a time
+pub(crate) trait Listed {
+    type Cursor: Ord + Copy + fmt::Display;
+    // what the list holds, for error messages
+    const NAME: &'static str;
+    // orders the list supports besides `Created`
+    const SORTS: &'static [SortBy] = &[];
+
+    fn cursor(&self) -> Self::Cursor;
+
+    fn sort_key(&self, _sort_by: SortBy) -> SortKey {
+        SortKey::Unsorted
+    }
+}
+
+fn page_size<C>(options: &ListOptions<C>) -> usize {
+    options
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE) as usize
+}
+
+// Page of items read one past its size, the extra item tells that another page follows
+fn page_of<T: Listed>(mut items: Vec<T>, limit: usize, total: u64) -> Page<T, T::Cursor> {
+    let next = if items.len() > limit {
+        items.truncate(limit);
+        items.last().map(T::cursor)
+    } else {
+        None
+    };
+    Page { items, next, total }
+}
+
+// Read a page of `items`. Only the page being built is kept in memory, so a list can be read
+// whatever its size. `lookup` finds the item a page starts after to place it in the sort order.
+pub(crate) fn paginate<T: Listed>(
+    items: impl Iterator<Item = T>,
+    options: &ListOptions<T::Cursor>,
+    lookup: impl Fn(T::Cursor) -> Option<T>,
+) -> Result<Page<T, T::Cursor>, Error> {
+    let sort_by = options.sort_by.unwrap_or_default();
+    if sort_by != SortBy::Created && !T::SORTS.contains(&sort_by) {
+        return Err(Error::InvalidPayload {
+            msg: format!("{} cannot be sorted by {:?}", T::NAME, sort_by),
+        });
+    }
+    let limit = page_size(options);
+    let descending = options.descending.unwrap_or(false);
+    let after = match options.start_after {
+        Some(cursor) if sort_by == SortBy::Created => Some((SortKey::Unsorted, cursor)),
+        Some(cursor) => match lookup(cursor) {
+            Some(item) => Some((item.sort_key(sort_by), cursor)),
+            None => {
+                return Err(Error::NotFound {
+                    msg: format!("{} cursor: {} not found", T::NAME, cursor),
+                })
+            }
+        },
+        None => None,
+    };
+
+    let mut total = 0;
+    let mut window = BTreeMap::new();
+    for item in items {
+        total += 1;
+        let key = (item.sort_key(sort_by), item.cursor());
+        if after.as_ref().is_some_and(|after| match descending {
+            true => key >= *after,
+            false => key <= *after,
+        }) {
+            continue;
+        }
+        window.insert(key, item);
+        // keep one item past the page to tell whether another page follows
+        if window.len() > limit + 1 {
+            match descending {
+                true => window.pop_first(),
+                false => window.pop_last(),
+            };
+        }
+    }
+
+    let items: Vec<T> = match descending {
+        true => window.into_values().rev().collect(),
+        false => window.into_values().collect(),
+    };
+    Ok(page_of(items, limit, total))
+}
+
+// Read a page of the items whose cursors `ids` yields in ascending order, as index scans do. In
+// creation order only the cursors are counted and only the items of the page are looked up.
+pub(crate) fn paginate_ids<T: Listed>(
+    ids: impl Iterator<Item = T::Cursor>,
+    options: &ListOptions<T::Cursor>,
+    lookup: impl Fn(T::Cursor) -> Option<T>,
+) -> Result<Page<T, T::Cursor>, Error> {
+    if options.sort_by.unwrap_or_default() != SortBy::Created {
+        return paginate(ids.filter_map(&lookup), options, &lookup);
+    }
+    let limit = page_size(options);
+    let descending = options.descending.unwrap_or(false);
+    let mut total = 0;
+    let mut window = VecDeque::new();
+    for id in ids {
+        total += 1;
+        match (descending, options.start_after) {
+            (true, Some(after)) if id >= after => continue,
+            (false, Some(after)) if id <= after => continue,
+            _ => {}
+        }
+        window.push_back(id);
+        // the cursors nearest the start, and one past the page
+        if window.len() > limit + 1 {
+            match descending {
+                true => window.pop_front(),
+                false => window.pop_back(),
+            };
+        }
+    }
+    let ids: Vec<T::Cursor> = match descending {
+        true => window.into_iter().rev().collect(),
+        false => window.into_iter().collect(),
+    };
+    Ok(page_of(
+        ids.into_iter().filter_map(lookup).collect(),
+        limit,
+        total,
+    ))
+}
+
+// Read a page of every item of a map keyed by cursor. In creation order the page is read by
+// seeking to where it starts and the total is the length of the map, so only the items of the
+// page are decoded.
+pub(crate) fn paginate_map<T>(
+    map: &StableBTreeMap<T::Cursor, T, Memory>,
+    options: &ListOptions<T::Cursor>,
+) -> Result<Page<T, T::Cursor>, Error>
+where
+    T: Listed + BoundedStorable,
+    T::Cursor: BoundedStorable,
+{
+    if options.sort_by.unwrap_or_default() != SortBy::Created {
+        return paginate(map.iter().map(|(_, item)| item), options, |cursor| {
+            map.get(&cursor)
+        });
+    }
+    let limit = page_size(options);
+    let items = match options.descending.unwrap_or(false) {
+        false => {
+            let start = match options.start_after {
+                Some(after) => Bound::Excluded(after),
+                None => Bound::Unbounded,
+            };
+            map.range((start, Bound::Unbounded))
+                .take(limit + 1)
+                .map(|(_, item)| item)
+                .collect()
+        }
+        // the map only iterates forward, step back one entry below the last one read
+        true => {
+            let mut items = vec![];
+            let mut entry = match options.start_after {
+                Some(after) => map.iter_upper_bound(&after).next(),
+                None => map.last_key_value(),
+            };
+            while let Some((cursor, item)) = entry {
+                items.push(item);
+                if items.len() > limit {
+                    break;
+                }
+                entry = map.iter_upper_bound(&cursor).next();
+            }
+            items
+        }
+    };
+    Ok(page_of(items, limit, map.len()))
+}
+
+// Case-insensitive key for sorting by text
+pub(crate) fn text_key(text: &str) -> SortKey {
+    SortKey::Text(text.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ids::HospitalId;
+    use crate::{Hospital, HOSPITAL_STORAGE};
+
+    #[derive(Clone, PartialEq, Debug)]
+    struct Item(u64, &'static str);
+
+    impl Listed for Item {
+        type Cursor = u64;
+        const NAME: &'static str = "items";
+        const SORTS: &'static [SortBy] = &[SortBy::Name];
+
+        fn cursor(&self) -> u64 {
+            self.0
+        }
+
+        fn sort_key(&self, sort_by: SortBy) -> SortKey {
+            match sort_by {
+                SortBy::Name => text_key(self.1),
+                _ => SortKey::Unsorted,
+            }
+        }
+    }
+
+    const ITEMS: [Item; 5] = [
+        Item(1, "delta"),
+        Item(2, "Alpha"),
+        Item(3, "charlie"),
+        Item(4, "bravo"),
+        Item(5, "alpha"),
+    ];
+
+    fn read_all(options: ListOptions<u64>) -> Vec<u64> {
+        let lookup = |id| ITEMS.iter().find(|item| item.0 == id).cloned();
+        let mut options = ListOptions {
+            limit: Some(2),
+            ..options
+        };
+        let mut ids = vec![];
+        loop {
+            let page = paginate(ITEMS.iter().cloned(), &options, lookup).unwrap();
+            // reading the cursors alone gives the same pages
+            let ids_page = paginate_ids(ITEMS.iter().map(|item| item.0), &options, lookup).unwrap();
+            assert_eq!(page, ids_page);
+            assert_eq!(page.total, 5);
+            ids.extend(page.items.iter().map(|item| item.0));
+            match page.next {
+                Some(next) => options.start_after = Some(next),
+                None => return ids,
+            }
+        }
+    }
+
+    #[test]
+    fn pages_follow_the_sort_order() {
+        assert_eq!(read_all(ListOptions::default()), [1, 2, 3, 4, 5]);
+        assert_eq!(
+            read_all(ListOptions {
+                descending: Some(true),
+                ..Default::default()
+            }),
+            [5, 4, 3, 2, 1]
+        );
+        // equal names are ordered by id
+        assert_eq!(
+            read_all(ListOptions {
+                sort_by: Some(SortBy::Name),
+                ..Default::default()
+            }),
+            [2, 5, 4, 3, 1]
+        );
+        assert_eq!(
+            read_all(ListOptions {
+                sort_by: Some(SortBy::Name),
+                descending: Some(true),
+                ..Default::default()
+            }),
+            [1, 3, 4, 5, 2]
+        );
+    }
+
+    #[test]
+    fn maps_are_read_from_where_the_page_starts() {
+        for id in 1..=5 {
+            HOSPITAL_STORAGE.with(|s| {
+                s.borrow_mut().insert(
+                    HospitalId(id),
+                    Hospital {
+                        id: HospitalId(id),
+                        ..Default::default()
+                    },
+                )
+            });
+        }
+        let read_all = |descending| {
+            let mut options = ListOptions {
+                limit: Some(2),
+                descending: Some(descending),
+                ..Default::default()
+            };
+            let mut ids = vec![];
+            loop {
+                let page = HOSPITAL_STORAGE
+                    .with(|s| paginate_map(&s.borrow(), &options))
+                    .unwrap();
+                assert_eq!(page.total, 5);
+                ids.extend(page.items.iter().map(|hospital| hospital.id.0));
+                match page.next {
+                    Some(next) => options.start_after = Some(next),
+                    None => return ids,
+                }
+            }
+        };
+        assert_eq!(read_all(false), [1, 2, 3, 4, 5]);
+        assert_eq!(read_all(true), [5, 4, 3, 2, 1]);
+    }
+
+    #[test]
+    fn unsupported_orders_and_unknown_cursors_are_refused() {
+        let options = ListOptions {
+            sort_by: Some(SortBy::Urgency),
+            ..Default::default()
+        };
+        assert!(matches!(
+            paginate(ITEMS.iter().cloned(), &options, |_| None),
+            Err(Error::InvalidPayload { .. })
+        ));
+        let options = ListOptions {
+            start_after: Some(9),
+            sort_by: Some(SortBy::Name),
+            ..Default::default()
+        };
+        assert!(matches!(
+            paginate(ITEMS.iter().cloned(), &options, |_| None),
+            Err(Error::NotFound { .. })
+        ));
+    }
+}
@@ -0,0 +1,2074 @@
+use crate::components::{Component, ComponentNeed};
+use crate::eligibility;
+use crate::ids::{DonorId, HospitalId, PatientId, PledgeId};
+use crate::indexes::{self, IndexKey, SecondaryIndex};
+use crate::inventory;
+use crate::pagination::{self, ListOptions, Listed, Page};
+use crate::relations;
+use crate::roles::{self, caller_is_authenticated, caller_is_verified_hospital_staff, Role};
+use crate::schema;
+use crate::screening;
+use crate::staff::{self, StaffAction, StaffPermission};
+use crate::{
+    caller_principal, now, Donor, Error, Hospital, Patient, DONOR_PLEDGE_INDEX, DONOR_STORAGE,
+    HOSPITAL_PLEDGE_INDEX, HOSPITAL_STORAGE, PATIENT_HOSPITAL_INDEX, PATIENT_PLEDGE_INDEX,
+    PATIENT_STORAGE, PLEDGE_CHANGE_STORAGE, PLEDGE_STORAGE,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::ops::Bound;
+use validator::Validate;
+
+const MAX_REASON_LENGTH: u64 = 200;
+
+// Most pints a donor can give in a single donation
+const MAX_PINTS_PER_DONATION: u32 = 2;
+
+// Most blood units a single collection can be recorded with
+const MAX_UNITS_PER_COLLECTION: u64 = 4;
+const MAX_UNIT_ID_LENGTH: usize = 32;
+
+// The hospital or patient a pledge is made to
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) enum PledgeRecipient {
+    Hospital(HospitalId),
+    Patient(PatientId),
+}
+
+// Lifecycle of a pledge: `Pledged` until the recipient accepts (`Scheduled`) or declines
+// (`Cancelled`) it, then `Collected` once blood is drawn, `NoShow` when staff record that the
+// donor missed the collection and `Expired` when it was never collected. Pledges made once a patient's need is covered wait
+// as `Standby` and become `Pledged` when other pledges fall through.
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) enum PledgeStatus {
+    Pledged,
+    Scheduled,
+    Collected,
+    Cancelled,
+    NoShow,
+    Expired,
+    Standby,
+}
+
+// Blood actually drawn for a pledge, recorded by the hospital that collected it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Collection {
+    pints: u32,
+    unit_ids: Vec<String>,
+    collected_at: u64,
+    confirmed_by: Principal,
+    component: Option<Component>,
+    hospital_id: HospitalId,
+}
+
+// A pledge of blood from a donor to a hospital or patient
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Pledge {
+    id: PledgeId,
+    donor_id: DonorId,
+    recipient: PledgeRecipient,
+    pints: u32,
+    status: PledgeStatus,
+    created_at: u64,
+    updated_at: u64,
+    collection: Option<Collection>,
+    // whole blood when None, as for pledges made before components were tracked
+    component: Option<Component>,
+}
+
+impl Pledge {
+    fn component(&self) -> Component {
+        self.component.unwrap_or_default()
+    }
+}
+
+impl Listed for Pledge {
+    type Cursor = PledgeId;
+    const NAME: &'static str = "pledges";
+
+    fn cursor(&self) -> PledgeId {
+        self.id
+    }
+}
+
+impl Storable for Pledge {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Pledge {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) enum PledgeChangeKind {
+    Cancelled,
+    Amended { from_pints: u32, to_pints: u32 },
+    NoShow,
+}
+
+// A cancellation, amendment or missed collection of a pledge, kept with the reason given for it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct PledgeChange {
+    pledge_id: PledgeId,
+    kind: PledgeChangeKind,
+    previous_status: PledgeStatus,
+    reason: String,
+    changed_by: Principal,
+    at: u64,
+}
+
+impl Storable for PledgeChange {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PledgeChange {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Outcome of a dry-run pledge, `reasons` lists every check the pledge would fail
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct PledgeEligibility {
+    eligible: bool,
+    reasons: Vec<Error>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Validate)]
+pub(crate) struct PledgePayload {
+    donor_id: DonorId,
+    recipient: PledgeRecipient,
+    #[validate(range(min = 1, max = "MAX_PINTS_PER_DONATION"))]
+    pints_pledge: u32,
+    // whole blood when not given
+    component: Option<Component>,
+}
+
+// What became of a pledge: `applied_pints` is the part of `requested_pints` counted towards the
+// recipient's need, 0 for a standby pledge
+#[derive(candid::CandidType, Serialize, Deserialize)]
+pub(crate) struct PledgeReceipt {
+    pledge: Pledge,
+    requested_pints: u32,
+    applied_pints: u32,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct ConfirmDonationPayload {
+    pledge_id: PledgeId,
+    #[validate(range(min = 1, max = "MAX_PINTS_PER_DONATION"))]
+    pints: u32,
+    #[validate(length(min = 1, max = "MAX_UNITS_PER_COLLECTION"))]
+    unit_ids: Vec<String>,
+    // the pledged component when not given
+    component: Option<Component>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct CancelPledgePayload {
+    pledge_id: PledgeId,
+    #[validate(length(min = 3, max = "MAX_REASON_LENGTH"))]
+    reason: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct NoShowPayload {
+    pledge_id: PledgeId,
+    #[validate(length(min = 3, max = "MAX_REASON_LENGTH"))]
+    reason: String,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct AmendPledgePayload {
+    pledge_id: PledgeId,
+    #[validate(range(min = 1, max = "MAX_PINTS_PER_DONATION"))]
+    pints: u32,
+    #[validate(length(min = 3, max = "MAX_REASON_LENGTH"))]
+    reason: String,
+}
+
+// Check that the caller is the donor itself, nobody can pledge on behalf of a donor
+fn authorize_donor(caller: &Principal, donor_id: DonorId) -> Result<(), Error> {
+    match roles::has_role(caller, Role::Donor, Some(donor_id.0)) {
+        true => Ok(()),
+        false => Err(Error::Unauthorized {
+            msg: format!("Unauthorized, caller is not donor id: {}", donor_id),
+        }),
+    }
+}
+
+// Check that the caller may accept or decline pledges made to a recipient
+fn authorize_recipient(caller: &Principal, recipient: PledgeRecipient) -> Result<(), Error> {
+    match recipient {
+        PledgeRecipient::Hospital(id) => {
+            staff::require_permission(caller, id, StaffPermission::ConfirmDonations)
+        }
+        PledgeRecipient::Patient(id) => {
+            roles::require_role(caller, &[Role::PatientGuardian], Some(id.0))
+        }
+    }
+}
+
+fn get_hospital(id: HospitalId) -> Result<Hospital, Error> {
+    HOSPITAL_STORAGE
+        .with(|hospitals| hospitals.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("hospital of id: {} not found", id),
+        })
+}
+
+fn get_patient(id: PatientId) -> Result<Patient, Error> {
+    PATIENT_STORAGE
+        .with(|patients| patients.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("patient of id: {} not found", id),
+        })
+}
+
+fn get_donor(id: DonorId) -> Result<Donor, Error> {
+    DONOR_STORAGE
+        .with(|donors| donors.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("Donor of id: {} not found", id),
+        })
+}
+
+fn get_pledge(id: PledgeId) -> Result<Pledge, Error> {
+    PLEDGE_STORAGE
+        .with(|pledges| pledges.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("pledge of id: {} not found", id),
+        })
+}
+
+fn check_status(pledge: &Pledge, expected: PledgeStatus) -> Result<(), Error> {
+    if pledge.status != expected {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "pledge of id: {} is {:?}, expected {:?}",
+                pledge.id, pledge.status, expected
+            ),
+        });
+    }
+    Ok(())
+}
+
+fn set_status(pledge: Pledge, status: PledgeStatus) -> Pledge {
+    let pledge = Pledge {
+        status,
+        updated_at: now(),
+        ..pledge
+    };
+    PLEDGE_STORAGE.with(|s| s.borrow_mut().insert(pledge.id, pledge.clone()));
+    pledge
+}
+
+// Components and collection times of every donation a donor gave
+pub(crate) fn collections_of_donor(donor_id: DonorId) -> Vec<(Component, u64)> {
+    read_indexed(&DONOR_PLEDGE_INDEX, &donor_id, |pledges| {
+        pledges
+            .filter_map(|pledge| collection_of(&pledge))
+            .collect()
+    })
+}
+
+// Hospitals a donor pledged to, directly or through a patient registered at them
+pub(crate) fn hospitals_of_donor(donor_id: DonorId) -> BTreeSet<HospitalId> {
+    let recipients: Vec<PledgeRecipient> =
+        read_indexed(&DONOR_PLEDGE_INDEX, &donor_id, |pledges| {
+            pledges.map(|pledge| pledge.recipient).collect()
+        });
+    PATIENT_STORAGE.with(|s| {
+        let patients = s.borrow();
+        recipients
+            .into_iter()
+            .filter_map(|recipient| match recipient {
+                PledgeRecipient::Hospital(id) => Some(id),
+                PledgeRecipient::Patient(id) => patients.get(&id).and_then(|p| p.hospital_id),
+            })
+            .collect()
+    })
+}
+
+// Component and collection time of a collected pledge
+fn collection_of(pledge: &Pledge) -> Option<(Component, u64)> {
+    match (&pledge.status, &pledge.collection) {
+        (PledgeStatus::Collected, Some(collection)) => Some((
+            collection.component.unwrap_or_default().collected_as(),
+            collection.collected_at,
+        )),
+        _ => None,
+    }
+}
+
+// The open pledge of a donor, one not settled yet
+fn open_pledge_of(donor_id: DonorId) -> Option<Pledge> {
+    read_indexed(&DONOR_PLEDGE_INDEX, &donor_id, |mut pledges| {
+        (&mut pledges).find(|pledge| {
+            matches!(
+                pledge.status,
+                PledgeStatus::Pledged | PledgeStatus::Scheduled | PledgeStatus::Standby
+            )
+        })
+    })
+}
+
+pub(crate) fn has_open_pledge(donor_id: DonorId) -> bool {
+    open_pledge_of(donor_id).is_some()
+}
+
+// A donor gives one donation at a time, a new pledge waits until the open one is settled
+pub(crate) fn check_no_open_pledge(donor_id: DonorId) -> Result<(), Error> {
+    if let Some(pledge) = open_pledge_of(donor_id) {
+        return Err(Error::IneligibleDonor {
+            msg: format!(
+                "donor of id: {} already has open pledge of id: {}",
+                donor_id, pledge.id
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Check that the donor may pledge again: not deferred, past the interval of the collection the
+// component comes from and without another open pledge
+fn check_donor_can_pledge(donor_id: DonorId, component: Component) -> Result<(), Error> {
+    eligibility::check_donor_eligible(donor_id, component, now())?;
+    check_no_open_pledge(donor_id)
+}
+
+// Read the pledges indexed under a key of a pledge index, in id order
+fn read_indexed<K: IndexKey, R>(
+    index: &'static SecondaryIndex<K, PledgeId>,
+    key: &K,
+    read: impl FnOnce(&mut dyn Iterator<Item = Pledge>) -> R,
+) -> R {
+    indexes::scan(index, key, |ids| {
+        PLEDGE_STORAGE.with(|s| {
+            let pledges = s.borrow();
+            read(&mut ids.filter_map(|id| pledges.get(&id)))
+        })
+    })
+}
+
+// Store a new pledge and index it by its donor and its recipient, neither changes once the
+// pledge is made
+fn store_new_pledge(pledge: &Pledge) {
+    PLEDGE_STORAGE.with(|s| s.borrow_mut().insert(pledge.id, pledge.clone()));
+    DONOR_PLEDGE_INDEX.with(|s| s.borrow_mut().insert((pledge.donor_id, pledge.id), ()));
+    match pledge.recipient {
+        PledgeRecipient::Hospital(id) => {
+            HOSPITAL_PLEDGE_INDEX.with(|s| s.borrow_mut().insert((id, pledge.id), ()))
+        }
+        PledgeRecipient::Patient(id) => {
+            PATIENT_PLEDGE_INDEX.with(|s| s.borrow_mut().insert((id, pledge.id), ()))
+        }
+    };
+}
+
+// A page of the pledges indexed under a key of a pledge index
+fn indexed_page<K: IndexKey>(
+    index: &'static SecondaryIndex<K, PledgeId>,
+    key: &K,
+    options: &ListOptions<PledgeId>,
+) -> Result<Page<Pledge, PledgeId>, Error> {
+    read_indexed(index, key, |pledges| {
+        pagination::paginate(pledges, options, |id| {
+            PLEDGE_STORAGE.with(|s| s.borrow().get(&id))
+        })
+    })
+}
+
+fn check_patient_needs_donations(patient: &Patient, component: Component) -> Result<(), Error> {
+    match patient
+        .needs()
+        .iter()
+        .find(|need| need.component == component)
+    {
+        None => Err(Error::InvalidPayload {
+            msg: format!("Patient does not need {:?}", component),
+        }),
+        Some(need) if need.donations >= need.needed_pints => Err(Error::InvalidPayload {
+            msg: format!(
+                "Patient has already reached their needed donation target of {:?}",
+                component
+            ),
+        }),
+        Some(_) => Ok(()),
+    }
+}
+
+// Pints of a component a patient still needs once collected donations and pledges awaiting
+// collection are counted, leaving out the pledge `except` when one is being changed
+pub(crate) fn remaining_need(
+    patient: &Patient,
+    component: Component,
+    except: Option<PledgeId>,
+) -> u32 {
+    let outstanding: u32 = read_indexed(&PATIENT_PLEDGE_INDEX, &patient.id, |pledges| {
+        pledges
+            .filter(|pledge| {
+                pledge.component() == component
+                    && matches!(
+                        pledge.status,
+                        PledgeStatus::Pledged | PledgeStatus::Scheduled
+                    )
+                    && Some(pledge.id) != except
+            })
+            .map(|pledge| pledge.pints)
+            .sum()
+    });
+    patient
+        .needs()
+        .iter()
+        .find(|need| need.component == component)
+        .map_or(0, |need| {
+            need.needed_pints
+                .saturating_sub(need.donations + outstanding)
+        })
+}
+
+// Turn standby pledges into pledges, oldest first, while the patient needs more blood than is
+// pledged. A promoted pledge is clipped to the pints still needed.
+pub(crate) fn promote_standby(patient_id: PatientId) {
+    let patient = match get_patient(patient_id) {
+        Ok(patient) => patient,
+        Err(_) => return,
+    };
+    let standby: Vec<Pledge> = read_indexed(&PATIENT_PLEDGE_INDEX, &patient_id, |pledges| {
+        pledges
+            .filter(|pledge| pledge.status == PledgeStatus::Standby)
+            .collect()
+    });
+    for pledge in standby {
+        let remaining = remaining_need(&patient, pledge.component(), None);
+        if remaining > 0 {
+            let pints = pledge.pints.min(remaining);
+            set_status(Pledge { pints, ..pledge }, PledgeStatus::Pledged);
+        }
+    }
+}
+
+// Check that the donor's component can be given to the patient
+fn check_compatibility(
+    donor: &Donor,
+    patient: &Patient,
+    component: Component,
+) -> Result<(), Error> {
+    if !component.compatible(donor.blood_group, patient.blood_group) {
+        return Err(Error::IncompatibleBloodGroup {
+            msg: format!(
+                "donor blood group {} cannot give {:?} to patient blood group {}",
+                donor.blood_group, component, patient.blood_group
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Run every check a pledge to a patient goes through without recording anything
+fn eligibility(
+    caller: &Principal,
+    donor_id: DonorId,
+    patient_id: PatientId,
+    component: Component,
+) -> PledgeEligibility {
+    let mut reasons = vec![];
+    let donor = get_donor(donor_id).map_err(|e| reasons.push(e)).ok();
+    let patient = get_patient(patient_id).map_err(|e| reasons.push(e)).ok();
+    if donor.is_some() {
+        if let Err(e) = authorize_donor(caller, donor_id) {
+            reasons.push(e);
+        }
+        if let Err(e) = check_donor_can_pledge(donor_id, component) {
+            reasons.push(e);
+        }
+    }
+    if let Some(patient) = &patient {
+        if let Err(e) = check_patient_needs_donations(patient, component) {
+            reasons.push(e);
+        }
+    }
+    if let (Some(donor), Some(patient)) = (&donor, &patient) {
+        if let Err(e) = check_compatibility(donor, patient, component) {
+            reasons.push(e);
+        }
+    }
+    PledgeEligibility {
+        eligible: reasons.is_empty(),
+        reasons,
+    }
+}
+
+// Record a pledge from the calling donor, it takes effect once the recipient accepts it
+fn create_pledge(
+    caller: Principal,
+    donor_id: DonorId,
+    recipient: PledgeRecipient,
+    pints: u32,
+    component: Component,
+) -> Result<Pledge, Error> {
+    let donor = get_donor(donor_id)?;
+    authorize_donor(&caller, donor_id)?;
+    check_donor_can_pledge(donor_id, component)?;
+    // pledges over a patient's remaining need are clipped, or wait as standby once it is covered
+    let (pints, status) = match recipient {
+        PledgeRecipient::Hospital(id) => {
+            get_hospital(id)?;
+            (pints, PledgeStatus::Pledged)
+        }
+        PledgeRecipient::Patient(id) => {
+            let patient = get_patient(id)?;
+            check_patient_needs_donations(&patient, component)?;
+            check_compatibility(&donor, &patient, component)?;
+            match remaining_need(&patient, component, None) {
+                0 => (pints, PledgeStatus::Standby),
+                remaining => (pints.min(remaining), PledgeStatus::Pledged),
+            }
+        }
+    };
+
+    let id = PledgeId::next();
+
+    let pledge = Pledge {
+        id,
+        donor_id,
+        recipient,
+        pints,
+        status,
+        created_at: now(),
+        updated_at: now(),
+        collection: None,
+        component: Some(component),
+    };
+    store_new_pledge(&pledge);
+    Ok(pledge)
+}
+
+// Schedule a pledge once the recipient agrees to it, nothing is counted until blood is collected
+fn accept(caller: Principal, pledge_id: PledgeId) -> Result<String, Error> {
+    let pledge = get_pledge(pledge_id)?;
+    authorize_recipient(&caller, pledge.recipient)?;
+    check_status(&pledge, PledgeStatus::Pledged)?;
+
+    let message = match pledge.recipient {
+        PledgeRecipient::Hospital(id) => {
+            let hospital = get_hospital(id)?;
+            staff::record_activity(
+                hospital.id,
+                caller,
+                StaffAction::RecordedPledge {
+                    donor_id: pledge.donor_id,
+                },
+            );
+            format!(
+                "Succesfully pledged to hospital {}, visit address: {} to donate",
+                hospital.name, hospital.address
+            )
+        }
+        PledgeRecipient::Patient(id) => {
+            let patient = get_patient(id)?;
+            check_patient_needs_donations(&patient, pledge.component())?;
+            match patient.hospital_id.map(get_hospital).transpose()? {
+                Some(hospital) => format!(
+                    "Succesfully pledged to patient {}, visit hospital: {} at {} to donate",
+                    patient.name, hospital.name, hospital.address
+                ),
+                None => format!(
+                    "Succesfully pledged to patient {}, visit hospital: {} to donate",
+                    patient.name, patient.hospital
+                ),
+            }
+        }
+    };
+    set_status(pledge, PledgeStatus::Scheduled);
+    Ok(message)
+}
+
+// Move the recipient's donations from `from` collected pints to `to` collected pints. The donor
+// is linked to the recipient when a collection is first recorded and unlinked when it is undone,
+// and a patient's donations of the component and completion follow the new total.
+fn apply_collected_pints(
+    recipient: PledgeRecipient,
+    component: Component,
+    donor_id: DonorId,
+    from: Option<u32>,
+    to: Option<u32>,
+) -> Result<(), Error> {
+    let donations = |current: u32| {
+        current
+            .saturating_sub(from.unwrap_or(0))
+            .saturating_add(to.unwrap_or(0))
+    };
+
+    match recipient {
+        PledgeRecipient::Hospital(id) => {
+            let mut hospital = get_hospital(id)?;
+            hospital.donations = donations(hospital.donations);
+            indexes::store_hospital(hospital);
+        }
+        PledgeRecipient::Patient(id) => {
+            let mut patient = get_patient(id)?;
+            let mut needs = patient.needs();
+            match needs.iter_mut().find(|need| need.component == component) {
+                Some(need) => need.donations = donations(need.donations),
+                // a component the patient no longer lists still counts towards the totals
+                None => needs.push(ComponentNeed {
+                    component,
+                    needed_pints: 0,
+                    donations: donations(0),
+                }),
+            }
+            patient.set_needs(needs)?;
+            indexes::store_patient(patient);
+        }
+    }
+
+    match (from, to) {
+        (None, Some(_)) => relations::link_donation(recipient, donor_id),
+        (Some(_), None) => relations::unlink_donation(recipient, donor_id),
+        _ => {}
+    }
+    Ok(())
+}
+
+// The hospital recording a collection: the pledged hospital itself, or the hospital of the patient.
+// Patients the upgrade could not link to a hospital wait for a super-admin to link them.
+fn collecting_hospital(
+    caller: &Principal,
+    recipient: PledgeRecipient,
+) -> Result<HospitalId, Error> {
+    match recipient {
+        PledgeRecipient::Hospital(id) => {
+            staff::require_permission(caller, id, StaffPermission::ConfirmDonations)?;
+            Ok(id)
+        }
+        PledgeRecipient::Patient(id) => match get_patient(id)?.hospital_id {
+            Some(hospital_id) => {
+                staff::require_permission(caller, hospital_id, StaffPermission::ConfirmDonations)?;
+                Ok(hospital_id)
+            }
+            None => Err(Error::InvalidPayload {
+                msg: format!(
+                    "patient of id: {} is not linked to a hospital, a super-admin must link it first",
+                    id
+                ),
+            }),
+        },
+    }
+}
+
+fn authorize_viewer(caller: &Principal, pledge: &Pledge) -> Result<(), Error> {
+    if authorize_donor(caller, pledge.donor_id).is_ok()
+        || collecting_hospital(caller, pledge.recipient).is_ok()
+    {
+        return Ok(());
+    }
+    authorize_recipient(caller, pledge.recipient)
+}
+
+pub(crate) fn authorize_pledge_viewer(
+    caller: &Principal,
+    pledge_id: PledgeId,
+) -> Result<(), Error> {
+    authorize_viewer(caller, &get_pledge(pledge_id)?)
+}
+
+// Who is answering the screening of an open pledge: the donor for themselves, or staff on behalf
+// of the hospital collecting the blood. Returns the donor id and the collecting hospital.
+pub(crate) fn screening_submitter(
+    caller: &Principal,
+    pledge_id: PledgeId,
+) -> Result<(DonorId, Option<HospitalId>), Error> {
+    let pledge = get_pledge(pledge_id)?;
+    if !matches!(
+        pledge.status,
+        PledgeStatus::Pledged | PledgeStatus::Scheduled
+    ) {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "pledge of id: {} is {:?} and cannot be screened",
+                pledge.id, pledge.status
+            ),
+        });
+    }
+    if authorize_donor(caller, pledge.donor_id).is_ok() {
+        return Ok((pledge.donor_id, None));
+    }
+    let hospital_id = collecting_hospital(caller, pledge.recipient)?;
+    Ok((pledge.donor_id, Some(hospital_id)))
+}
+
+// Record the blood drawn for a scheduled pledge and apply it to the donor and recipient records
+fn collect(caller: Principal, payload: ConfirmDonationPayload) -> Result<Pledge, Error> {
+    schema::check_migrated()?;
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    if let Some(unit_id) = payload
+        .unit_ids
+        .iter()
+        .find(|unit_id| unit_id.is_empty() || unit_id.len() > MAX_UNIT_ID_LENGTH)
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "invalid unit id: {:?}, expected 1 to {} characters",
+                unit_id, MAX_UNIT_ID_LENGTH
+            ),
+        });
+    }
+    let pledge = get_pledge(payload.pledge_id)?;
+    let hospital_id = collecting_hospital(&caller, pledge.recipient)?;
+    check_status(&pledge, PledgeStatus::Scheduled)?;
+    if payload.pints > pledge.pints {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "pledge of id: {} is for {} pints, not {}",
+                pledge.id, pledge.pints, payload.pints
+            ),
+        });
+    }
+    let donor = get_donor(pledge.donor_id)?;
+    let component = pledge.component();
+    if payload
+        .component
+        .is_some_and(|collected| collected != component)
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "pledge of id: {} is for {:?}, not {:?}",
+                pledge.id,
+                component,
+                payload.component.unwrap_or_default()
+            ),
+        });
+    }
+    eligibility::check_donor_eligible(donor.id, component, now())?;
+    screening::check_cleared(pledge.id)?;
+    inventory::check_new_labels(hospital_id, &payload.unit_ids)?;
+
+    let blood_group = donor.blood_group;
+    apply_collected_pints(
+        pledge.recipient,
+        component,
+        donor.id,
+        None,
+        Some(payload.pints),
+    )?;
+    staff::record_activity(
+        hospital_id,
+        caller,
+        StaffAction::ConfirmedDonation {
+            pledge_id: pledge.id,
+        },
+    );
+    // the collected units wait in the hospital's quarantine until their tests clear
+    let collected_at = now();
+    for unit_id in &payload.unit_ids {
+        inventory::add_unit(
+            hospital_id,
+            unit_id.clone(),
+            component,
+            blood_group,
+            collected_at,
+            Some(pledge.id),
+        );
+    }
+    let pledge = Pledge {
+        collection: Some(Collection {
+            pints: payload.pints,
+            unit_ids: payload.unit_ids,
+            collected_at,
+            confirmed_by: caller,
+            component: Some(component),
+            hospital_id,
+        }),
+        ..pledge
+    };
+    let pledge = set_status(pledge, PledgeStatus::Collected);
+    promote_standby_for(pledge.recipient);
+    Ok(pledge)
+}
+
+// Cancel a pledge the recipient does not want, without touching the donor or recipient records
+fn decline(caller: Principal, pledge_id: PledgeId) -> Result<Pledge, Error> {
+    let pledge = get_pledge(pledge_id)?;
+    authorize_recipient(&caller, pledge.recipient)?;
+    if pledge.status != PledgeStatus::Standby {
+        check_status(&pledge, PledgeStatus::Pledged)?;
+    }
+    let pledge = set_status(pledge, PledgeStatus::Cancelled);
+    promote_standby_for(pledge.recipient);
+    Ok(pledge)
+}
+
+fn promote_standby_for(recipient: PledgeRecipient) {
+    if let PledgeRecipient::Patient(id) = recipient {
+        promote_standby(id);
+    }
+}
+
+// Read up to `limit` pledges after `after`, expire those created before `cutoff` that were never
+// collected, then fill the needs they leave open from the standby pledges. Returns the expired
+// pledges with their recipients and the last pledge read, None once every pledge was read.
+pub(crate) fn expire_stale_pledges(
+    cutoff: u64,
+    after: Option<PledgeId>,
+    limit: usize,
+) -> (Vec<(PledgeId, PledgeRecipient)>, Option<PledgeId>) {
+    let start = match after {
+        Some(id) => Bound::Excluded(id),
+        None => Bound::Unbounded,
+    };
+    let read: Vec<Pledge> = PLEDGE_STORAGE.with(|s| {
+        s.borrow()
+            .range((start, Bound::Unbounded))
+            .take(limit)
+            .map(|(_, pledge)| pledge)
+            .collect()
+    });
+    let next = match read.len() {
+        len if len == limit => read.last().map(|pledge| pledge.id),
+        _ => None,
+    };
+    let expired: Vec<(PledgeId, PledgeRecipient)> = read
+        .into_iter()
+        .filter(|pledge| {
+            pledge.created_at < cutoff
+                && matches!(
+                    pledge.status,
+                    PledgeStatus::Pledged | PledgeStatus::Scheduled | PledgeStatus::Standby
+                )
+        })
+        .map(|pledge| set_status(pledge, PledgeStatus::Expired))
+        .map(|pledge| (pledge.id, pledge.recipient))
+        .collect();
+    // once per patient however many of their pledges expired
+    let patients: BTreeSet<PatientId> = expired
+        .iter()
+        .filter_map(|(_, recipient)| match recipient {
+            PledgeRecipient::Patient(id) => Some(*id),
+            PledgeRecipient::Hospital(_) => None,
+        })
+        .collect();
+    for patient_id in patients {
+        promote_standby(patient_id);
+    }
+    (expired, next)
+}
+
+// Donors change their own pledges until blood is collected, afterwards only staff allowed to
+// confirm donations at the hospital that recorded the collection can correct it
+fn authorize_change(caller: &Principal, pledge: &Pledge) -> Result<(), Error> {
+    match (pledge.status, &pledge.collection) {
+        (PledgeStatus::Pledged | PledgeStatus::Scheduled | PledgeStatus::Standby, _) => {
+            authorize_donor(caller, pledge.donor_id)
+        }
+        (PledgeStatus::Collected, Some(collection)) => staff::require_permission(
+            caller,
+            collection.hospital_id,
+            StaffPermission::ConfirmDonations,
+        ),
+        (status, _) => Err(Error::InvalidPayload {
+            msg: format!(
+                "pledge of id: {} is {:?} and can no longer change",
+                pledge.id, status
+            ),
+        }),
+    }
+}
+
+fn record_change(pledge: &Pledge, kind: PledgeChangeKind, reason: String, changed_by: Principal) {
+    let seq = changes_of(pledge.id).len() as u64;
+    let change = PledgeChange {
+        pledge_id: pledge.id,
+        kind,
+        previous_status: pledge.status,
+        reason,
+        changed_by,
+        at: now(),
+    };
+    PLEDGE_CHANGE_STORAGE.with(|s| s.borrow_mut().insert((pledge.id, seq), change));
+}
+
+fn changes_of(pledge_id: PledgeId) -> Vec<PledgeChange> {
+    PLEDGE_CHANGE_STORAGE.with(|s| {
+        s.borrow()
+            .range((pledge_id, 0)..)
+            .take_while(|((id, _), _)| *id == pledge_id)
+            .map(|(_, change)| change)
+            .collect()
+    })
+}
+
+// Withdraw a pledge, a collected pledge is also taken off the recipient's donations
+fn cancel(caller: Principal, payload: CancelPledgePayload) -> Result<Pledge, Error> {
+    schema::check_migrated()?;
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    let pledge = get_pledge(payload.pledge_id)?;
+    authorize_change(&caller, &pledge)?;
+    if let Some(collection) = &pledge.collection {
+        apply_collected_pints(
+            pledge.recipient,
+            pledge.component(),
+            pledge.donor_id,
+            Some(collection.pints),
+            None,
+        )?;
+        // the blood of a cancelled collection leaves the stock
+        inventory::discard_pledge_units(
+            caller,
+            collection.hospital_id,
+            pledge.id,
+            0,
+            &payload.reason,
+        );
+    }
+    record_change(&pledge, PledgeChangeKind::Cancelled, payload.reason, caller);
+    let pledge = set_status(pledge, PledgeStatus::Cancelled);
+    promote_standby_for(pledge.recipient);
+    Ok(pledge)
+}
+
+// Change the pints of a pledge, or of the recorded collection once the pledge is collected
+fn amend(caller: Principal, payload: AmendPledgePayload) -> Result<Pledge, Error> {
+    schema::check_migrated()?;
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    let pledge = get_pledge(payload.pledge_id)?;
+    authorize_change(&caller, &pledge)?;
+    let from_pints = match &pledge.collection {
+        Some(collection) => collection.pints,
+        None => pledge.pints,
+    };
+    // raising an uncollected pledge to a patient is clipped to the pints still needed
+    let mut to_pints = payload.pints;
+    if let PledgeRecipient::Patient(id) = pledge.recipient {
+        let pending = matches!(
+            pledge.status,
+            PledgeStatus::Pledged | PledgeStatus::Scheduled
+        );
+        if pending && to_pints > from_pints {
+            let available = remaining_need(&get_patient(id)?, pledge.component(), Some(pledge.id));
+            to_pints = to_pints.min(available.max(from_pints));
+        }
+    }
+
+    let pledge = match pledge.collection.clone() {
+        // the units drawn hold the pints collected, a correction can only lower them
+        Some(collection) if to_pints > collection.pints => {
+            return Err(Error::InvalidPayload {
+                msg: format!(
+                    "pledge of id: {} collected {} pints and cannot be amended up to {}",
+                    pledge.id, collection.pints, to_pints
+                ),
+            })
+        }
+        Some(collection) => {
+            apply_collected_pints(
+                pledge.recipient,
+                pledge.component(),
+                pledge.donor_id,
+                Some(collection.pints),
+                Some(to_pints),
+            )?;
+            // each unit holds an even share of the pints collected, units over the amended
+            // pints leave the stock
+            let keep = (collection.unit_ids.len() as u32 * to_pints).div_ceil(collection.pints);
+            inventory::discard_pledge_units(
+                caller,
+                collection.hospital_id,
+                pledge.id,
+                keep as usize,
+                &payload.reason,
+            );
+            Pledge {
+                collection: Some(Collection {
+                    pints: to_pints,
+                    ..collection
+                }),
+                ..pledge
+            }
+        }
+        None => Pledge {
+            pints: to_pints,
+            ..pledge
+        },
+    };
+    record_change(
+        &pledge,
+        PledgeChangeKind::Amended {
+            from_pints,
+            to_pints,
+        },
+        payload.reason,
+        caller,
+    );
+    let status = pledge.status;
+    let pledge = set_status(pledge, status);
+    promote_standby_for(pledge.recipient);
+    Ok(pledge)
+}
+
+// Close a scheduled pledge whose donor missed the collection, the pints it held go back to the
+// recipient's need
+fn no_show(caller: Principal, payload: NoShowPayload) -> Result<Pledge, Error> {
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    let pledge = get_pledge(payload.pledge_id)?;
+    collecting_hospital(&caller, pledge.recipient)?;
+    check_status(&pledge, PledgeStatus::Scheduled)?;
+    record_change(&pledge, PledgeChangeKind::NoShow, payload.reason, caller);
+    let pledge = set_status(pledge, PledgeStatus::NoShow);
+    promote_standby_for(pledge.recipient);
+    Ok(pledge)
+}
+
+// Pledges made to a hospital or to the patients registered at it
+fn hospital_pledge_page(
+    hospital_id: HospitalId,
+    options: &ListOptions<PledgeId>,
+) -> Result<Page<Pledge, PledgeId>, Error> {
+    let mut ids: BTreeSet<PledgeId> =
+        indexes::scan(&HOSPITAL_PLEDGE_INDEX, &hospital_id, |ids| ids.collect());
+    let patients: Vec<PatientId> =
+        indexes::scan(&PATIENT_HOSPITAL_INDEX, &hospital_id, |ids| ids.collect());
+    for patient_id in patients {
+        indexes::scan(&PATIENT_PLEDGE_INDEX, &patient_id, |pledge_ids| {
+            ids.extend(pledge_ids)
+        });
+    }
+    PLEDGE_STORAGE.with(|s| {
+        let pledges = s.borrow();
+        pagination::paginate(
+            ids.into_iter().filter_map(|id| pledges.get(&id)),
+            options,
+            |id| pledges.get(&id),
+        )
+    })
+}
+
+// function for a donor to pledge to a hospital or a patient, the hospital staff or a guardian of
+// the patient then accepts the pledge
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn make_pledge(payload: PledgePayload) -> Result<PledgeReceipt, Error> {
+    let caller = caller_principal()?;
+    if let Err(e) = payload.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    let pledge = create_pledge(
+        caller,
+        payload.donor_id,
+        payload.recipient,
+        payload.pints_pledge,
+        payload.component.unwrap_or_default(),
+    )?;
+    let applied_pints = match pledge.status {
+        PledgeStatus::Standby => 0,
+        _ => pledge.pints,
+    };
+    Ok(PledgeReceipt {
+        pledge,
+        requested_pints: payload.pints_pledge,
+        applied_pints,
+    })
+}
+
+// accept a pledge on behalf of the hospital or patient it was made to
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn accept_pledge(pledge_id: PledgeId) -> Result<String, Error> {
+    let caller = caller_principal()?;
+    accept(caller, pledge_id)
+}
+
+// confirm that blood was drawn for a scheduled pledge, only then are the recipient's donations updated
+#[ic_cdk::update(guard = "caller_is_verified_hospital_staff")]
+fn confirm_donation(payload: ConfirmDonationPayload) -> Result<Pledge, Error> {
+    let caller = caller_principal()?;
+    collect(caller, payload)
+}
+
+// withdraw a pledge with a reason, rolling back a collection already counted for the recipient
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn cancel_pledge(payload: CancelPledgePayload) -> Result<Pledge, Error> {
+    let caller = caller_principal()?;
+    cancel(caller, payload)
+}
+
+// change the pints of a pledge with a reason, adjusting the recipient's donations once collected
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn amend_pledge(payload: AmendPledgePayload) -> Result<Pledge, Error> {
+    let caller = caller_principal()?;
+    amend(caller, payload)
+}
+
+// record that the donor of a scheduled pledge missed the collection, for staff allowed to confirm
+// donations for the recipient
+#[ic_cdk::update(guard = "caller_is_verified_hospital_staff")]
+fn mark_no_show(payload: NoShowPayload) -> Result<Pledge, Error> {
+    let caller = caller_principal()?;
+    no_show(caller, payload)
+}
+
+// decline a pledge on behalf of the hospital or patient it was made to
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn decline_pledge(pledge_id: PledgeId) -> Result<Pledge, Error> {
+    let caller = caller_principal()?;
+    decline(caller, pledge_id)
+}
+
+// explain whether the caller could pledge a component (whole blood when not given) as a donor to
+// a patient, and why not
+#[ic_cdk::query]
+fn check_pledge_eligibility(
+    donor_id: DonorId,
+    patient_id: PatientId,
+    component: Option<Component>,
+) -> PledgeEligibility {
+    eligibility(
+        &ic_cdk::caller(),
+        donor_id,
+        patient_id,
+        component.unwrap_or_default(),
+    )
+}
+
+// get a pledge by ID, visible to the donor, the recipient side and staff who can collect it
+#[ic_cdk::query]
+fn get_pledge_by_id(id: PledgeId) -> Result<Pledge, Error> {
+    let pledge = get_pledge(id)?;
+    authorize_viewer(&ic_cdk::caller(), &pledge)?;
+    Ok(pledge)
+}
+
+// list the cancellations and amendments of a pledge with their reasons
+#[ic_cdk::query]
+fn get_pledge_changes(pledge_id: PledgeId) -> Result<Vec<PledgeChange>, Error> {
+    get_pledge_by_id(pledge_id)?;
+    Ok(changes_of(pledge_id))
+}
+
+// list a page of the pledges made by a donor
+#[ic_cdk::query]
+fn get_pledges_by_donor(
+    donor_id: DonorId,
+    options: ListOptions<PledgeId>,
+) -> Result<Page<Pledge, PledgeId>, Error> {
+    roles::require_role(&ic_cdk::caller(), &[Role::Donor], Some(donor_id.0))?;
+    indexed_page(&DONOR_PLEDGE_INDEX, &donor_id, &options)
+}
+
+// list a page of the pledges made to a patient
+#[ic_cdk::query]
+fn get_pledges_by_patient(
+    patient_id: PatientId,
+    options: ListOptions<PledgeId>,
+) -> Result<Page<Pledge, PledgeId>, Error> {
+    roles::require_role(
+        &ic_cdk::caller(),
+        &[Role::PatientGuardian],
+        Some(patient_id.0),
+    )?;
+    indexed_page(&PATIENT_PLEDGE_INDEX, &patient_id, &options)
+}
+
+// list a page of the pledges made to a hospital or to its patients
+#[ic_cdk::query]
+fn get_pledges_by_hospital(
+    hospital_id: HospitalId,
+    options: ListOptions<PledgeId>,
+) -> Result<Page<Pledge, PledgeId>, Error> {
+    schema::check_migrated()?;
+    roles::require_role(
+        &ic_cdk::caller(),
+        &[Role::HospitalAdmin, Role::HospitalStaff],
+        Some(hospital_id.0),
+    )?;
+    hospital_pledge_page(hospital_id, &options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blood_group::BloodGroup;
+    use crate::inventory::UnitStatus;
+    use crate::roles::RoleGrant;
+    use crate::screening::{ScreeningAnswers, ScreeningPayload};
+    use crate::{
+        DONOR_HOSPITAL_INDEX, DONOR_PATIENT_INDEX, HOSPITAL_DONOR_INDEX, INVENTORY_STORAGE,
+        PATIENT_DONOR_INDEX,
+    };
+
+    fn principal(n: u8) -> Principal {
+        Principal::from_slice(&[n; 10])
+    }
+
+    fn grant(principal: Principal, role: Role, id: u64) {
+        roles::grant(
+            principal,
+            RoleGrant {
+                role,
+                scope_id: Some(id),
+            },
+        );
+    }
+
+    // whether the donor is in the recipient's donors and the recipient in the donor's beneficiaries
+    fn linked(recipient: PledgeRecipient, donor_id: DonorId) -> bool {
+        let (recipient_linked, beneficiary_linked) = match recipient {
+            PledgeRecipient::Hospital(id) => (
+                HOSPITAL_DONOR_INDEX.with(|s| s.borrow().contains_key(&(id, donor_id))),
+                DONOR_HOSPITAL_INDEX.with(|s| s.borrow().contains_key(&(donor_id, id))),
+            ),
+            PledgeRecipient::Patient(id) => (
+                PATIENT_DONOR_INDEX.with(|s| s.borrow().contains_key(&(id, donor_id))),
+                DONOR_PATIENT_INDEX.with(|s| s.borrow().contains_key(&(donor_id, id))),
+            ),
+        };
+        assert_eq!(recipient_linked, beneficiary_linked);
+        recipient_linked
+    }
+
+    // another O negative donor acting as principal(id)
+    fn add_donor(donor_id: DonorId) -> Principal {
+        DONOR_STORAGE.with(|s| {
+            s.borrow_mut().insert(
+                donor_id,
+                Donor {
+                    id: donor_id,
+                    blood_group: BloodGroup::ONegative,
+                    ..Default::default()
+                },
+            )
+        });
+        grant(principal(donor_id.0 as u8), Role::Donor, donor_id.0);
+        principal(donor_id.0 as u8)
+    }
+
+    fn setup() -> (HospitalId, PatientId, DonorId) {
+        // a fresh install, at the current schema
+        crate::schema::init_version();
+        let (hospital_id, patient_id, donor_id) = (HospitalId(1), PatientId(2), DonorId(3));
+        HOSPITAL_STORAGE.with(|s| {
+            s.borrow_mut().insert(
+                hospital_id,
+                Hospital {
+                    id: hospital_id,
+                    name: "City Hospital".to_string(),
+                    verified_by: Some(principal(9)),
+                    ..Default::default()
+                },
+            )
+        });
+        PATIENT_STORAGE.with(|s| {
+            s.borrow_mut().insert(
+                patient_id,
+                Patient {
+                    id: patient_id,
+                    name: "Jane".to_string(),
+                    blood_group: BloodGroup::APositive,
+                    hospital_id: Some(hospital_id),
+                    needed_pints: 2,
+                    ..Default::default()
+                },
+            )
+        });
+        DONOR_STORAGE.with(|s| {
+            s.borrow_mut().insert(
+                donor_id,
+                Donor {
+                    id: donor_id,
+                    name: "John".to_string(),
+                    blood_group: BloodGroup::ONegative,
+                    ..Default::default()
+                },
+            )
+        });
+        grant(principal(1), Role::HospitalAdmin, hospital_id.0);
+        grant(principal(2), Role::PatientGuardian, patient_id.0);
+        grant(principal(3), Role::Donor, donor_id.0);
+        (hospital_id, patient_id, donor_id)
+    }
+
+    #[test]
+    fn donor_pledges_as_themselves() {
+        let (_, patient_id, donor_id) = setup();
+        let pledge = create_pledge(
+            principal(3),
+            donor_id,
+            PledgeRecipient::Patient(patient_id),
+            1,
+            Component::WholeBlood,
+        );
+        assert!(pledge.is_ok());
+    }
+
+    #[test]
+    fn recipient_cannot_pledge_on_behalf_of_donor() {
+        let (hospital_id, patient_id, donor_id) = setup();
+        let to_hospital = create_pledge(
+            principal(1),
+            donor_id,
+            PledgeRecipient::Hospital(hospital_id),
+            1,
+            Component::WholeBlood,
+        );
+        let to_patient = create_pledge(
+            principal(2),
+            donor_id,
+            PledgeRecipient::Patient(patient_id),
+            1,
+            Component::WholeBlood,
+        );
+        assert!(matches!(to_hospital, Err(Error::Unauthorized { .. })));
+        assert!(matches!(to_patient, Err(Error::Unauthorized { .. })));
+    }
+
+    // clear the donor of a pledge as staff of hospital 1
+    fn screen(pledge_id: PledgeId) {
+        let answers = ScreeningAnswers {
+            weight_kg: 70,
+            haemoglobin_g_per_l: Some(140),
+            ..Default::default()
+        };
+        assert!(screening::submit(principal(1), ScreeningPayload { pledge_id, answers }).is_ok());
+    }
+
+    fn confirmation(pledge_id: PledgeId, pints: u32) -> ConfirmDonationPayload {
+        ConfirmDonationPayload {
+            pledge_id,
+            pints,
+            unit_ids: vec![format!("UNIT-{:04}", pledge_id)],
+            component: None,
+        }
+    }
+
+    #[test]
+    fn pledge_only_counts_once_collected() {
+        let (_, patient_id, donor_id) = setup();
+        let pledge = create_pledge(
+            principal(3),
+            donor_id,
+            PledgeRecipient::Patient(patient_id),
+            2,
+            Component::WholeBlood,
+        )
+        .ok()
+        .unwrap();
+        // blood can only be collected for a pledge the guardian accepted
+        assert!(collect(principal(1), confirmation(pledge.id, 2)).is_err());
+
+        assert!(accept(principal(2), pledge.id).is_ok());
+        screen(pledge.id);
+        assert_eq!(
+            get_pledge(pledge.id).unwrap().status,
+            PledgeStatus::Scheduled
+        );
+        assert_eq!(get_patient(patient_id).unwrap().donations, 0);
+        // a scheduled pledge cannot be accepted twice
+        assert!(accept(principal(2), pledge.id).is_err());
+        // guardians cannot confirm collections
+        assert!(collect(principal(2), confirmation(pledge.id, 1)).is_err());
+
+        let collected = collect(principal(1), confirmation(pledge.id, 1)).unwrap();
+        assert_eq!(collected.status, PledgeStatus::Collected);
+        assert_eq!(collected.collection.unwrap().pints, 1);
+        let patient = get_patient(patient_id).unwrap();
+        assert_eq!(patient.donations, 1);
+        assert!(!patient.is_complete);
+        assert!(linked(PledgeRecipient::Patient(patient_id), donor_id));
+        // the collected unit is quarantined in the hospital's inventory
+        assert_eq!(INVENTORY_STORAGE.with(|s| s.borrow().len()), 1);
+        assert!(collect(principal(1), confirmation(pledge.id, 1)).is_err());
+    }
+
+    #[test]
+    fn cancelling_collected_pledge_reopens_patient() {
+        let (_, patient_id, donor_id) = setup();
+        let pledge = create_pledge(
+            principal(3),
+            donor_id,
+            PledgeRecipient::Patient(patient_id),
+            2,
+            Component::WholeBlood,
+        )
+        .ok()
+        .unwrap();
+        assert!(accept(principal(2), pledge.id).is_ok());
+        screen(pledge.id);
+        assert!(collect(principal(1), confirmation(pledge.id, 2)).is_ok());
+        assert!(get_patient(patient_id).unwrap().is_complete);
+
+        let cancellation = CancelPledgePayload {
+            pledge_id: pledge.id,
+            reason: "unit failed screening".to_string(),
+        };
+        // the donor cannot undo blood that was already collected
+        assert!(cancel(principal(3), cancellation.clone()).is_err());
+        // nor can the patient's new hospital undo a collection recorded by another one
+        PATIENT_STORAGE.with(|s| {
+            let mut patient = s.borrow().get(&patient_id).unwrap();
+            patient.hospital_id = Some(HospitalId(5));
+            s.borrow_mut().insert(patient_id, patient)
+        });
+        grant(principal(5), Role::HospitalAdmin, 5);
+        assert!(matches!(
+            cancel(principal(5), cancellation.clone()),
+            Err(Error::Unauthorized { .. })
+        ));
+        assert_eq!(
+            cancel(principal(1), cancellation).unwrap().status,
+            PledgeStatus::Cancelled
+        );
+        let patient = get_patient(patient_id).unwrap();
+        assert_eq!(patient.donations, 0);
+        assert!(!patient.is_complete);
+        assert!(!linked(PledgeRecipient::Patient(patient_id), donor_id));
+        assert_eq!(changes_of(pledge.id).len(), 1);
+        // the collected unit is discarded with the reason
+        assert_eq!(
+            unit_statuses(pledge.id),
+            [(
+                UnitStatus::Discarded,
+                Some("unit failed screening".to_string())
+            )]
+        );
+    }
+
+    fn unit_statuses(pledge_id: PledgeId) -> Vec<(UnitStatus, Option<String>)> {
+        INVENTORY_STORAGE.with(|s| {
+            s.borrow()
+                .iter()
+                .map(|(_, unit)| unit)
+                .filter(|unit| unit.pledge_id == Some(pledge_id))
+                .map(|unit| (unit.status, unit.discard_reason))
+                .collect()
+        })
+    }
+
+    #[test]
+    fn amending_adjusts_pledge_then_collection() {
+        let (_, patient_id, donor_id) = setup();
+        let pledge = create_pledge(
+            principal(3),
+            donor_id,
+            PledgeRecipient::Patient(patient_id),
+            1,
+            Component::WholeBlood,
+        )
+        .ok()
+        .unwrap();
+        let amendment = |pints| AmendPledgePayload {
+            pledge_id: pledge.id,
+            pints,
+            reason: "changed my mind".to_string(),
+        };
+        assert!(amend(principal(2), amendment(2)).is_err());
+        assert_eq!(amend(principal(3), amendment(2)).unwrap().pints, 2);
+
+        assert!(accept(principal(2), pledge.id).is_ok());
+        screen(pledge.id);
+        let two_units = ConfirmDonationPayload {
+            unit_ids: vec!["UNIT-A".to_string(), "UNIT-B".to_string()],
+            ..confirmation(pledge.id, 2)
+        };
+        assert!(collect(principal(1), two_units).is_ok());
+        assert!(get_patient(patient_id).unwrap().is_complete);
+        let amended = amend(principal(1), amendment(1)).unwrap();
+        assert_eq!(amended.status, PledgeStatus::Collected);
+        // one unit per pint collected, the unit over the amended pints leaves the stock
+        assert_eq!(
+            unit_statuses(pledge.id),
+            [
+                (UnitStatus::Quarantined, None),
+                (UnitStatus::Discarded, Some("changed my mind".to_string()))
+            ]
+        );
+        let patient = get_patient(patient_id).unwrap();
+        assert_eq!(patient.donations, 1);
+        assert!(!patient.is_complete);
+        assert!(linked(PledgeRecipient::Patient(patient_id), donor_id));
+        assert!(matches!(
+            amend(principal(1), amendment(0)),
+            Err(Error::InvalidPayload { .. })
+        ));
+        // no more pints than were drawn, the discarded unit stays discarded
+        assert!(matches!(
+            amend(principal(1), amendment(2)),
+            Err(Error::InvalidPayload { .. })
+        ));
+        let pledge = get_pledge(pledge.id).unwrap();
+        assert_eq!(pledge.collection.unwrap().pints, 1);
+        assert_eq!(get_patient(patient_id).unwrap().donations, 1);
+        assert_eq!(changes_of(pledge.id).len(), 2);
+    }
+
+    #[test]
+    fn over_need_pledges_are_clipped_then_waitlisted() {
+        let (_, patient_id, donor_id) = setup();
+        let covering = create_pledge(
+            principal(3),
+            donor_id,
+            PledgeRecipient::Patient(patient_id),
+            2,
+            Component::WholeBlood,
+        )
+        .ok()
+        .unwrap();
+        let standby = create_pledge(
+            add_donor(DonorId(4)),
+            DonorId(4),
+            PledgeRecipient::Patient(patient_id),
+            1,
+            Component::WholeBlood,
+        )
+        .ok()
+        .unwrap();
+        assert_eq!(standby.status, PledgeStatus::Standby);
+
+        // raise the need past what is pledged without going through edit_patient
+        PATIENT_STORAGE.with(|s| {
+            let patient = s.borrow().get(&patient_id).unwrap();
+            s.borrow_mut().insert(
+                patient_id,
+                Patient {
+                    needed_pints: 3,
+                    ..patient
+                },
+            )
+        });
+        let clipped = create_pledge(
+            add_donor(DonorId(5)),
+            DonorId(5),
+            PledgeRecipient::Patient(patient_id),
+            2,
+            Component::WholeBlood,
+        )
+        .ok()
+        .unwrap();
+        assert_eq!(clipped.status, PledgeStatus::Pledged);
+        assert_eq!(clipped.pints, 1);
+
+        // a pledge falling through promotes the oldest standby pledge
+        assert!(decline(principal(2), covering.id).is_ok());
+        assert_eq!(
+            get_pledge(standby.id).unwrap().status,
+            PledgeStatus::Pledged
+        );
+    }
+
+    #[test]
+    fn stale_pledges_expire_and_promote_standby() {
+        let (_, patient_id, donor_id) = setup();
+        let stale = create_pledge(
+            principal(3),
+            donor_id,
+            PledgeRecipient::Patient(patient_id),
+            2,
+            Component::WholeBlood,
+        )
+        .ok()
+        .unwrap();
+        let standby = create_pledge(
+            add_donor(DonorId(4)),
+            DonorId(4),
+            PledgeRecipient::Patient(patient_id),
+            1,
+            Component::WholeBlood,
+        )
+        .ok()
+        .unwrap();
+        PLEDGE_STORAGE.with(|s| {
+            s.borrow_mut().insert(
+                stale.id,
+                Pledge {
+                    created_at: 0,
+                    ..stale.clone()
+                },
+            )
+        });
+
+        // one pledge per batch, the standby pledge is promoted in the batch expiring the stale one
+        let (expired, next) = expire_stale_pledges(1, None, 1);
+        assert_eq!(
+            expired,
+            vec![(stale.id, PledgeRecipient::Patient(patient_id))]
+        );
+        assert_eq!(next, Some(stale.id));
+        assert_eq!(get_pledge(stale.id).unwrap().status, PledgeStatus::Expired);
+        assert_eq!(
+            get_pledge(standby.id).unwrap().status,
+            PledgeStatus::Pledged
+        );
+        assert_eq!(expire_stale_pledges(1, next, 1), (vec![], Some(standby.id)));
+        assert_eq!(expire_stale_pledges(1, Some(standby.id), 1), (vec![], None));
+        assert_eq!(expire_stale_pledges(1, None, 10), (vec![], None));
+    }
+
+    #[test]
+    fn donor_pledges_again_only_when_eligible() {
+        let (hospital_id, patient_id, donor_id) = setup();
+        let pledge = create_pledge(
+            principal(3),
+            donor_id,
+            PledgeRecipient::Hospital(hospital_id),
+            1,
+            Component::WholeBlood,
+        )
+        .ok()
+        .unwrap();
+        // one open pledge at a time
+        assert!(matches!(
+            create_pledge(
+                principal(3),
+                donor_id,
+                PledgeRecipient::Patient(patient_id),
+                1,
+                Component::WholeBlood
+            ),
+            Err(Error::IneligibleDonor { .. })
+        ));
+
+        assert!(accept(principal(1), pledge.id).is_ok());
+        screen(pledge.id);
+        assert!(collect(principal(1), confirmation(pledge.id, 1)).is_ok());
+        // whole blood was just collected, the donor waits out the interval
+        let again = create_pledge(
+            principal(3),
+            donor_id,
+            PledgeRecipient::Patient(patient_id),
+            1,
+            Component::WholeBlood,
+        );
+        assert!(matches!(again, Err(Error::IneligibleDonor { .. })));
+        assert!(!eligibility(&principal(3), donor_id, patient_id, Component::WholeBlood).eligible);
+    }
+
+    #[test]
+    fn collection_requires_cleared_screening() {
+        let (hospital_id, _, donor_id) = setup();
+        let pledge = create_pledge(
+            principal(3),
+            donor_id,
+            PledgeRecipient::Hospital(hospital_id),
+            1,
+            Component::WholeBlood,
+        )
+        .ok()
+        .unwrap();
+        assert!(accept(principal(1), pledge.id).is_ok());
+        assert!(matches!(
+            collect(principal(1), confirmation(pledge.id, 1)),
+            Err(Error::InvalidPayload { .. })
+        ));
+
+        // the donor's own answers defer them, which also blocks the collection
+        let answers = ScreeningAnswers {
+            illness_recently: true,
+            weight_kg: 70,
+            ..Default::default()
+        };
+        let screening = screening::submit(
+            principal(3),
+            ScreeningPayload {
+                pledge_id: pledge.id,
+                answers,
+            },
+        );
+        assert!(screening.is_ok());
+        assert!(matches!(
+            collect(principal(1), confirmation(pledge.id, 1)),
+            Err(Error::IneligibleDonor { .. })
+        ));
+        assert!(eligibility::check_donor_eligible(donor_id, Component::WholeBlood, now()).is_err());
+    }
+
+    #[test]
+    fn pledges_and_donations_follow_components() {
+        let (_, patient_id, donor_id) = setup();
+        PATIENT_STORAGE.with(|s| {
+            let mut patient = s.borrow().get(&patient_id).unwrap();
+            patient
+                .set_needs(vec![
+                    ComponentNeed {
+                        component: Component::PackedRedCells,
+                        needed_pints: 1,
+                        donations: 0,
+                    },
+                    ComponentNeed {
+                        component: Component::Plasma,
+                        needed_pints: 1,
+                        donations: 0,
+                    },
+                ])
+                .unwrap();
+            s.borrow_mut().insert(patient_id, patient)
+        });
+        let to_patient = PledgeRecipient::Patient(patient_id);
+        // O negative plasma carries antibodies against the patient's A antigen
+        assert!(matches!(
+            create_pledge(principal(3), donor_id, to_patient, 1, Component::Plasma),
+            Err(Error::IncompatibleBloodGroup { .. })
+        ));
+        assert!(
+            create_pledge(principal(3), donor_id, to_patient, 1, Component::Platelets).is_err()
+        );
+
+        let pledge = create_pledge(
+            principal(3),
+            donor_id,
+            to_patient,
+            2,
+            Component::PackedRedCells,
+        )
+        .ok()
+        .unwrap();
+        assert_eq!(pledge.pints, 1);
+        assert!(accept(principal(2), pledge.id).is_ok());
+        screen(pledge.id);
+        let plasma = ConfirmDonationPayload {
+            component: Some(Component::Plasma),
+            ..confirmation(pledge.id, 1)
+        };
+        assert!(collect(principal(1), plasma).is_err());
+        assert!(collect(principal(1), confirmation(pledge.id, 1)).is_ok());
+
+        let patient = get_patient(patient_id).unwrap();
+        assert_eq!((patient.needed_pints, patient.donations), (2, 1));
+        assert!(!patient.is_complete);
+        assert_eq!(patient.needs()[0].donations, 1);
+        assert_eq!(patient.needs()[1].donations, 0);
+    }
+
+    #[test]
+    fn collection_needs_units_and_pints() {
+        let (hospital_id, _, donor_id) = setup();
+        let pledge = create_pledge(
+            principal(3),
+            donor_id,
+            PledgeRecipient::Hospital(hospital_id),
+            1,
+            Component::WholeBlood,
+        )
+        .ok()
+        .unwrap();
+        assert!(accept(principal(1), pledge.id).is_ok());
+        screen(pledge.id);
+        let no_units = ConfirmDonationPayload {
+            unit_ids: vec![],
+            ..confirmation(pledge.id, 1)
+        };
+        assert!(matches!(
+            collect(principal(1), no_units),
+            Err(Error::InvalidPayload { .. })
+        ));
+        assert!(matches!(
+            collect(principal(1), confirmation(pledge.id, 0)),
+            Err(Error::InvalidPayload { .. })
+        ));
+        // more pints than pledged, or than a donation can hold
+        for pints in [2, u32::MAX] {
+            assert!(matches!(
+                collect(principal(1), confirmation(pledge.id, pints)),
+                Err(Error::InvalidPayload { .. })
+            ));
+        }
+        assert_eq!(
+            get_pledge(pledge.id).unwrap().status,
+            PledgeStatus::Scheduled
+        );
+    }
+
+    #[test]
+    fn unlinked_patient_collection_waits_for_link() {
+        let (hospital_id, patient_id, donor_id) = setup();
+        PATIENT_STORAGE.with(|s| {
+            let mut patient = s.borrow().get(&patient_id).unwrap();
+            patient.hospital_id = None;
+            patient.hospital = "Unknown Clinic".to_string();
+            s.borrow_mut().insert(patient_id, patient)
+        });
+        let pledge = create_pledge(
+            principal(3),
+            donor_id,
+            PledgeRecipient::Patient(patient_id),
+            1,
+            Component::WholeBlood,
+        )
+        .ok()
+        .unwrap();
+        assert!(accept(principal(2), pledge.id).is_ok());
+        // staff of a verified hospital cannot claim the collection for a patient of no hospital
+        assert!(matches!(
+            collecting_hospital(&principal(1), pledge.recipient),
+            Err(Error::InvalidPayload { .. })
+        ));
+        assert!(matches!(
+            collect(principal(1), confirmation(pledge.id, 1)),
+            Err(Error::InvalidPayload { .. })
+        ));
+        PATIENT_STORAGE.with(|s| {
+            let mut patient = s.borrow().get(&patient_id).unwrap();
+            patient.hospital_id = Some(hospital_id);
+            s.borrow_mut().insert(patient_id, patient)
+        });
+        assert_eq!(
+            collecting_hospital(&principal(1), pledge.recipient).ok(),
+            Some(hospital_id)
+        );
+    }
+
+    #[test]
+    fn donor_cannot_accept_own_pledge() {
+        let (hospital_id, patient_id, donor_id) = setup();
+        let to_patient = create_pledge(
+            principal(3),
+            donor_id,
+            PledgeRecipient::Patient(patient_id),
+            1,
+            Component::WholeBlood,
+        )
+        .ok()
+        .unwrap();
+        let to_hospital = create_pledge(
+            add_donor(DonorId(4)),
+            DonorId(4),
+            PledgeRecipient::Hospital(hospital_id),
+            1,
+            Component::WholeBlood,
+        )
+        .ok()
+        .unwrap();
+        assert!(matches!(
+            accept(principal(3), to_patient.id),
+            Err(Error::Unauthorized { .. })
+        ));
+        assert!(matches!(
+            accept(principal(4), to_hospital.id),
+            Err(Error::Unauthorized { .. })
+        ));
+        assert_eq!(get_patient(patient_id).unwrap().donations, 0);
+    }
+
+    #[test]
+    fn hospital_admin_accepts_pledge_to_hospital() {
+        let (hospital_id, _, donor_id) = setup();
+        let pledge = create_pledge(
+            principal(3),
+            donor_id,
+            PledgeRecipient::Hospital(hospital_id),
+            1,
+            Component::WholeBlood,
+        )
+        .ok()
+        .unwrap();
+        // a guardian of some patient has no say over hospital pledges
+        assert!(accept(principal(2), pledge.id).is_err());
+        assert!(accept(principal(1), pledge.id).is_ok());
+        screen(pledge.id);
+        assert!(collect(principal(1), confirmation(pledge.id, 1)).is_ok());
+        let hospital = get_hospital(hospital_id).unwrap();
+        assert!(linked(PledgeRecipient::Hospital(hospital_id), donor_id));
+        assert_eq!(hospital.donations, 1);
+    }
+
+    #[test]
+    fn declined_pledge_leaves_records_untouched() {
+        let (_, patient_id, donor_id) = setup();
+        let pledge = create_pledge(
+            principal(3),
+            donor_id,
+            PledgeRecipient::Patient(patient_id),
+            1,
+            Component::WholeBlood,
+        )
+        .ok()
+        .unwrap();
+        assert!(decline(principal(3), pledge.id).is_err());
+        assert_eq!(
+            decline(principal(2), pledge.id).unwrap().status,
+            PledgeStatus::Cancelled
+        );
+        assert!(accept(principal(2), pledge.id).is_err());
+        assert_eq!(get_patient(patient_id).unwrap().donations, 0);
+        assert!(!linked(PledgeRecipient::Patient(patient_id), donor_id));
+    }
+
+    #[test]
+    fn incompatible_pledge_is_refused() {
+        let (_, patient_id, donor_id) = setup();
+        DONOR_STORAGE.with(|s| {
+            s.borrow_mut().insert(
+                donor_id,
+                Donor {
+                    id: donor_id,
+                    blood_group: BloodGroup::BPositive,
+                    ..Default::default()
+                },
+            )
+        });
+        let pledge = create_pledge(
+            principal(3),
+            donor_id,
+            PledgeRecipient::Patient(patient_id),
+            1,
+            Component::WholeBlood,
+        );
+        assert!(matches!(pledge, Err(Error::IncompatibleBloodGroup { .. })));
+    }
+
+    #[test]
+    fn eligibility_lists_every_refusal() {
+        let (_, patient_id, donor_id) = setup();
+        assert!(eligibility(&principal(3), donor_id, patient_id, Component::WholeBlood).eligible);
+
+        DONOR_STORAGE.with(|s| {
+            s.borrow_mut().insert(
+                donor_id,
+                Donor {
+                    id: donor_id,
+                    blood_group: BloodGroup::ABPositive,
+                    ..Default::default()
+                },
+            )
+        });
+        let result = eligibility(&principal(2), donor_id, patient_id, Component::WholeBlood);
+        assert!(!result.eligible);
+        assert!(matches!(
+            result.reasons[..],
+            [
+                Error::Unauthorized { .. },
+                Error::IncompatibleBloodGroup { .. }
+            ]
+        ));
+        assert_eq!(
+            eligibility(
+                &principal(3),
+                donor_id,
+                PatientId(99),
+                Component::WholeBlood
+            )
+            .reasons
+            .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn staff_mark_missed_collections() {
+        let (_, patient_id, donor_id) = setup();
+        let pledge = create_pledge(
+            principal(3),
+            donor_id,
+            PledgeRecipient::Patient(patient_id),
+            2,
+            Component::WholeBlood,
+        )
+        .ok()
+        .unwrap();
+        let standby = create_pledge(
+            add_donor(DonorId(4)),
+            DonorId(4),
+            PledgeRecipient::Patient(patient_id),
+            1,
+            Component::WholeBlood,
+        )
+        .ok()
+        .unwrap();
+        let payload = NoShowPayload {
+            pledge_id: pledge.id,
+            reason: "missed the appointment".to_string(),
+        };
+        // only scheduled pledges can be missed
+        assert!(matches!(
+            no_show(principal(1), payload.clone()),
+            Err(Error::InvalidPayload { .. })
+        ));
+        assert!(accept(principal(2), pledge.id).is_ok());
+        // the donor and staff of other hospitals cannot record it
+        assert!(no_show(principal(3), payload.clone()).is_err());
+        grant(principal(5), Role::HospitalAdmin, 5);
+        assert!(no_show(principal(5), payload.clone()).is_err());
+
+        let pledge = no_show(principal(1), payload).unwrap();
+        assert_eq!(pledge.status, PledgeStatus::NoShow);
+        let changes = changes_of(pledge.id);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].kind, PledgeChangeKind::NoShow);
+        assert_eq!(changes[0].previous_status, PledgeStatus::Scheduled);
+        // the pints go back to the need and the standby pledge takes them
+        assert_eq!(
+            get_pledge(standby.id).unwrap().status,
+            PledgeStatus::Pledged
+        );
+        // the donor can pledge again
+        assert!(check_no_open_pledge(donor_id).is_ok());
+    }
+
+    #[test]
+    fn hospital_pledges_include_those_to_its_patients() {
+        let (hospital_id, patient_id, donor_id) = setup();
+        // register the patient again so it is indexed at its hospital
+        let patient = PATIENT_STORAGE.with(|s| s.borrow_mut().remove(&patient_id).unwrap());
+        indexes::store_patient(patient);
+        let to_patient = create_pledge(
+            principal(3),
+            donor_id,
+            PledgeRecipient::Patient(patient_id),
+            1,
+            Component::WholeBlood,
+        )
+        .ok()
+        .unwrap();
+        let to_hospital = create_pledge(
+            add_donor(DonorId(4)),
+            DonorId(4),
+            PledgeRecipient::Hospital(hospital_id),
+            1,
+            Component::WholeBlood,
+        )
+        .ok()
+        .unwrap();
+        let page = hospital_pledge_page(hospital_id, &ListOptions::default()).unwrap();
+        let ids: Vec<PledgeId> = page.items.iter().map(|pledge| pledge.id).collect();
+        assert_eq!(ids, [to_patient.id, to_hospital.id]);
+        // the donor pledged to the hospital through its patient
+        assert_eq!(
+            hospitals_of_donor(donor_id).into_iter().collect::<Vec<_>>(),
+            [hospital_id]
+        );
+        // donors and patients list their own pledges only
+        let listed = |page: Result<Page<Pledge, PledgeId>, Error>| -> Vec<PledgeId> {
+            page.unwrap().items.iter().map(|pledge| pledge.id).collect()
+        };
+        let options = ListOptions::default();
+        assert_eq!(
+            listed(indexed_page(&DONOR_PLEDGE_INDEX, &DonorId(4), &options)),
+            [to_hospital.id]
+        );
+        assert_eq!(
+            listed(indexed_page(&PATIENT_PLEDGE_INDEX, &patient_id, &options)),
+            [to_patient.id]
+        );
+    }
+}
@@ -0,0 +1,419 @@
+use crate::ids::{DonorId, HospitalId, PatientId, RecordId};
+use crate::pledges::PledgeRecipient;
+use crate::roles::{self, Role};
+use crate::schema;
+use crate::{
+    Error, Memory, DONOR_HOSPITAL_INDEX, DONOR_PATIENT_INDEX, DONOR_STORAGE, HOSPITAL_DONOR_INDEX,
+    HOSPITAL_STORAGE, PATIENT_DONOR_INDEX, PATIENT_STORAGE,
+};
+use candid::Principal;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
+// Most ids returned by a single page of a relation
+const MAX_PAGE_SIZE: u32 = 100;
+
+// Relations keyed by (owner id, related id), valued by the number of collections behind them so
+// a donor stays linked until their last collection for the recipient is undone
+type RelationIndex<O, R> = LocalKey<RefCell<StableBTreeMap<(O, R), u32, Memory>>>;
+
+// A page of related ids in ascending order, `next` is passed as `after` to read the next page
+// and `total` counts the related ids of every page
+#[derive(candid::CandidType, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) struct IdPage<T> {
+    ids: Vec<T>,
+    next: Option<T>,
+    total: u64,
+}
+
+fn link<O: RecordId, R: RecordId>(index: &'static RelationIndex<O, R>, owner: O, related: R) {
+    index.with(|s| {
+        let count = s.borrow().get(&(owner, related)).unwrap_or(0);
+        s.borrow_mut().insert((owner, related), count + 1);
+    });
+}
+
+fn unlink<O: RecordId, R: RecordId>(index: &'static RelationIndex<O, R>, owner: O, related: R) {
+    index.with(|s| {
+        let count = s.borrow().get(&(owner, related));
+        match count {
+            Some(count) if count > 1 => {
+                s.borrow_mut().insert((owner, related), count - 1);
+            }
+            Some(_) => {
+                s.borrow_mut().remove(&(owner, related));
+            }
+            None => {}
+        }
+    });
+}
+
+fn page<O: RecordId, R: RecordId>(
+    index: &'static RelationIndex<O, R>,
+    owner: O,
+    after: Option<R>,
+    limit: u32,
+) -> IdPage<R> {
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    let total = index.with(|s| {
+        s.borrow()
+            .range((owner, R::default())..)
+            .take_while(|((id, _), _)| *id == owner)
+            .count() as u64
+    });
+    let start = match after.map(R::successor) {
+        Some(None) => {
+            return IdPage {
+                ids: vec![],
+                next: None,
+                total,
+            }
+        }
+        Some(Some(id)) => (owner, id),
+        None => (owner, R::default()),
+    };
+    let mut ids: Vec<R> = index.with(|s| {
+        s.borrow()
+            .range(start..)
+            .take_while(|((id, _), _)| *id == owner)
+            .map(|((_, related), _)| related)
+            .take(limit + 1)
+            .collect()
+    });
+    let next = if ids.len() > limit {
+        ids.truncate(limit);
+        ids.last().copied()
+    } else {
+        None
+    };
+    IdPage { ids, next, total }
+}
+
+// Record that a donor gave to a recipient
+pub(crate) fn link_donation(recipient: PledgeRecipient, donor_id: DonorId) {
+    match recipient {
+        PledgeRecipient::Hospital(id) => {
+            link(&HOSPITAL_DONOR_INDEX, id, donor_id);
+            link(&DONOR_HOSPITAL_INDEX, donor_id, id);
+        }
+        PledgeRecipient::Patient(id) => {
+            link(&PATIENT_DONOR_INDEX, id, donor_id);
+            link(&DONOR_PATIENT_INDEX, donor_id, id);
+        }
+    }
+}
+
+// Undo one collection from a donor to a recipient
+pub(crate) fn unlink_donation(recipient: PledgeRecipient, donor_id: DonorId) {
+    match recipient {
+        PledgeRecipient::Hospital(id) => {
+            unlink(&HOSPITAL_DONOR_INDEX, id, donor_id);
+            unlink(&DONOR_HOSPITAL_INDEX, donor_id, id);
+        }
+        PledgeRecipient::Patient(id) => {
+            unlink(&PATIENT_DONOR_INDEX, id, donor_id);
+            unlink(&DONOR_PATIENT_INDEX, donor_id, id);
+        }
+    }
+}
+
+// Tell apart the hospital and patient ids given out by the single sequence they shared, which
+// never gave the same id to both
+fn is_hospital(id: u64) -> bool {
+    HOSPITAL_STORAGE.with(|s| s.borrow().contains_key(&HospitalId(id)))
+}
+
+// Move the donor ids stored in a batch of hospitals by earlier versions into the relation index
+pub(crate) fn move_hospital_relations(after: Option<u64>, limit: usize) -> Option<u64> {
+    let (hospitals, next) = schema::batch(&HOSPITAL_STORAGE, after, limit);
+    for mut hospital in hospitals {
+        if let Some(donors_ids) = hospital.donors_ids.take() {
+            for donor_id in donors_ids {
+                link(&HOSPITAL_DONOR_INDEX, hospital.id, donor_id);
+            }
+            HOSPITAL_STORAGE.with(|s| s.borrow_mut().insert(hospital.id, hospital));
+        }
+    }
+    next
+}
+
+// Move the donor ids stored in a batch of patients by earlier versions into the relation index
+pub(crate) fn move_patient_relations(after: Option<u64>, limit: usize) -> Option<u64> {
+    let (patients, next) = schema::batch(&PATIENT_STORAGE, after, limit);
+    for mut patient in patients {
+        if let Some(donors_ids) = patient.donors_ids.take() {
+            for donor_id in donors_ids {
+                link(&PATIENT_DONOR_INDEX, patient.id, donor_id);
+            }
+            PATIENT_STORAGE.with(|s| s.borrow_mut().insert(patient.id, patient));
+        }
+    }
+    next
+}
+
+// Move the beneficiaries stored in a batch of donors by earlier versions into the relation
+// indexes
+pub(crate) fn move_donor_relations(after: Option<u64>, limit: usize) -> Option<u64> {
+    let (donors, next) = schema::batch(&DONOR_STORAGE, after, limit);
+    for mut donor in donors {
+        if let Some(beneficiaries) = donor.beneficiaries.take() {
+            for beneficiary in beneficiaries {
+                match is_hospital(beneficiary) {
+                    true => link(&DONOR_HOSPITAL_INDEX, donor.id, HospitalId(beneficiary)),
+                    false => link(&DONOR_PATIENT_INDEX, donor.id, PatientId(beneficiary)),
+                }
+            }
+            DONOR_STORAGE.with(|s| s.borrow_mut().insert(donor.id, donor));
+        }
+    }
+    next
+}
+
+// Check that the caller may read who gave to a hospital: its admins and staff
+fn authorize_hospital_reader(caller: &Principal, hospital_id: HospitalId) -> Result<(), Error> {
+    if !HOSPITAL_STORAGE.with(|s| s.borrow().contains_key(&hospital_id)) {
+        return Err(Error::NotFound {
+            msg: format!("hospital of id: {} not found", hospital_id),
+        });
+    }
+    roles::require_role(
+        caller,
+        &[Role::HospitalAdmin, Role::HospitalStaff],
+        Some(hospital_id.0),
+    )
+}
+
+// Check that the caller may read who gave to a patient: its guardians and the admins and staff
+// of the hospital it is registered at
+fn authorize_patient_reader(caller: &Principal, patient_id: PatientId) -> Result<(), Error> {
+    let patient = PATIENT_STORAGE
+        .with(|s| s.borrow().get(&patient_id))
+        .ok_or(Error::NotFound {
+            msg: format!("patient id:{} does not exist", patient_id),
+        })?;
+    match (
+        roles::require_role(caller, &[Role::PatientGuardian], Some(patient_id.0)),
+        patient.hospital_id,
+    ) {
+        (Err(_), Some(hospital_id)) => roles::require_role(
+            caller,
+            &[Role::HospitalAdmin, Role::HospitalStaff],
+            Some(hospital_id.0),
+        ),
+        (guardian, _) => guardian,
+    }
+}
+
+// Check that the caller may read whom a donor gave to: the donor itself
+fn authorize_donor_reader(caller: &Principal, donor_id: DonorId) -> Result<(), Error> {
+    if !DONOR_STORAGE.with(|s| s.borrow().contains_key(&donor_id)) {
+        return Err(Error::NotFound {
+            msg: format!("Donor of id: {} not found", donor_id),
+        });
+    }
+    roles::require_role(caller, &[Role::Donor], Some(donor_id.0))
+}
+
+// list the donors who gave to a hospital, `limit` ids at a time, for its admins and staff
+#[ic_cdk::query]
+fn get_hospital_donors(
+    hospital_id: HospitalId,
+    after: Option<DonorId>,
+    limit: u32,
+) -> Result<IdPage<DonorId>, Error> {
+    schema::check_migrated()?;
+    authorize_hospital_reader(&ic_cdk::caller(), hospital_id)?;
+    Ok(page(&HOSPITAL_DONOR_INDEX, hospital_id, after, limit))
+}
+
+// list the donors who gave to a patient, `limit` ids at a time, for its guardians and the staff
+// of its hospital
+#[ic_cdk::query]
+fn get_patient_donors(
+    patient_id: PatientId,
+    after: Option<DonorId>,
+    limit: u32,
+) -> Result<IdPage<DonorId>, Error> {
+    schema::check_migrated()?;
+    authorize_patient_reader(&ic_cdk::caller(), patient_id)?;
+    Ok(page(&PATIENT_DONOR_INDEX, patient_id, after, limit))
+}
+
+// list the hospitals a donor gave to, `limit` ids at a time, for the donor
+#[ic_cdk::query]
+fn get_donor_hospitals(
+    donor_id: DonorId,
+    after: Option<HospitalId>,
+    limit: u32,
+) -> Result<IdPage<HospitalId>, Error> {
+    schema::check_migrated()?;
+    authorize_donor_reader(&ic_cdk::caller(), donor_id)?;
+    Ok(page(&DONOR_HOSPITAL_INDEX, donor_id, after, limit))
+}
+
+// list the patients a donor gave to, `limit` ids at a time, for the donor
+#[ic_cdk::query]
+fn get_donor_patients(
+    donor_id: DonorId,
+    after: Option<PatientId>,
+    limit: u32,
+) -> Result<IdPage<PatientId>, Error> {
+    schema::check_migrated()?;
+    authorize_donor_reader(&ic_cdk::caller(), donor_id)?;
+    Ok(page(&DONOR_PATIENT_INDEX, donor_id, after, limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Hospital;
+    use candid::Encode;
+    use ic_stable_structures::Storable;
+    use std::borrow::Cow;
+
+    #[test]
+    fn pages_follow_each_other() {
+        for donor_id in 1..=5 {
+            link_donation(PledgeRecipient::Patient(PatientId(7)), DonorId(donor_id));
+        }
+        link_donation(PledgeRecipient::Patient(PatientId(8)), DonorId(3));
+
+        let patient = PatientId(7);
+        let first = page(&PATIENT_DONOR_INDEX, patient, None, 2);
+        assert_eq!(first.ids, vec![DonorId(1), DonorId(2)]);
+        let second = page(&PATIENT_DONOR_INDEX, patient, first.next, 2);
+        assert_eq!(second.ids, vec![DonorId(3), DonorId(4)]);
+        let last = page(&PATIENT_DONOR_INDEX, patient, second.next, 2);
+        assert_eq!(
+            last,
+            IdPage {
+                ids: vec![DonorId(5)],
+                next: None,
+                total: 5
+            }
+        );
+        assert_eq!(
+            page(&DONOR_PATIENT_INDEX, DonorId(3), None, 10).ids,
+            vec![PatientId(7), PatientId(8)]
+        );
+    }
+
+    #[test]
+    fn stored_donor_ids_move_to_the_index() {
+        #[derive(candid::CandidType)]
+        struct StoredHospital {
+            id: u64,
+            name: String,
+            address: String,
+            password: String,
+            city: String,
+            donations: u32,
+            donors_ids: Vec<u64>,
+        }
+        let bytes = Encode!(&StoredHospital {
+            id: 4,
+            name: "City Hospital".to_string(),
+            address: String::new(),
+            password: String::new(),
+            city: "Nairobi".to_string(),
+            donations: 2,
+            donors_ids: vec![9, 9],
+        })
+        .unwrap();
+        let hospital = Hospital::from_bytes(Cow::Owned(bytes));
+        assert_eq!(hospital.donors_ids, Some(vec![DonorId(9), DonorId(9)]));
+        HOSPITAL_STORAGE.with(|s| s.borrow_mut().insert(HospitalId(4), hospital));
+
+        move_hospital_relations(None, 10);
+        assert_eq!(
+            page(&HOSPITAL_DONOR_INDEX, HospitalId(4), None, 10).ids,
+            vec![DonorId(9)]
+        );
+        assert_eq!(
+            HOSPITAL_DONOR_INDEX.with(|s| s.borrow().get(&(HospitalId(4), DonorId(9)))),
+            Some(2)
+        );
+        assert_eq!(
+            HOSPITAL_STORAGE.with(|s| s.borrow().get(&HospitalId(4)).unwrap().donors_ids),
+            None
+        );
+    }
+
+    #[test]
+    fn donor_stays_linked_until_last_collection_is_undone() {
+        let recipient = PledgeRecipient::Hospital(HospitalId(2));
+        let donor = DonorId(5);
+        link_donation(recipient, donor);
+        link_donation(recipient, donor);
+        unlink_donation(recipient, donor);
+        assert_eq!(
+            page(&HOSPITAL_DONOR_INDEX, HospitalId(2), None, 10).ids,
+            vec![donor]
+        );
+        unlink_donation(recipient, donor);
+        assert!(page(&HOSPITAL_DONOR_INDEX, HospitalId(2), None, 10)
+            .ids
+            .is_empty());
+        assert!(page(&DONOR_HOSPITAL_INDEX, donor, None, 10).ids.is_empty());
+    }
+
+    #[test]
+    fn relations_are_read_by_the_records_they_concern() {
+        let staff = Principal::from_slice(&[4; 10]);
+        let guardian = Principal::from_slice(&[5; 10]);
+        let donor = Principal::from_slice(&[6; 10]);
+        HOSPITAL_STORAGE.with(|s| {
+            s.borrow_mut().insert(
+                HospitalId(1),
+                Hospital {
+                    id: HospitalId(1),
+                    ..Default::default()
+                },
+            )
+        });
+        PATIENT_STORAGE.with(|s| {
+            s.borrow_mut().insert(
+                PatientId(2),
+                crate::Patient {
+                    id: PatientId(2),
+                    hospital_id: Some(HospitalId(1)),
+                    ..Default::default()
+                },
+            )
+        });
+        DONOR_STORAGE.with(|s| {
+            s.borrow_mut().insert(
+                DonorId(3),
+                crate::Donor {
+                    id: DonorId(3),
+                    ..Default::default()
+                },
+            )
+        });
+        let grant = |principal, role, id| {
+            roles::grant(
+                principal,
+                roles::RoleGrant {
+                    role,
+                    scope_id: Some(id),
+                },
+            )
+        };
+        grant(staff, Role::HospitalStaff, 1);
+        grant(guardian, Role::PatientGuardian, 2);
+        grant(donor, Role::Donor, 3);
+
+        assert!(authorize_hospital_reader(&staff, HospitalId(1)).is_ok());
+        assert!(authorize_hospital_reader(&donor, HospitalId(1)).is_err());
+        assert!(authorize_patient_reader(&guardian, PatientId(2)).is_ok());
+        assert!(authorize_patient_reader(&staff, PatientId(2)).is_ok());
+        assert!(authorize_patient_reader(&donor, PatientId(2)).is_err());
+        assert!(authorize_donor_reader(&donor, DonorId(3)).is_ok());
+        assert!(authorize_donor_reader(&staff, DonorId(3)).is_err());
+        assert!(matches!(
+            authorize_donor_reader(&donor, DonorId(9)),
+            Err(Error::NotFound { .. })
+        ));
+    }
+}
@@ -0,0 +1,252 @@
+use crate::ids::HospitalId;
+use crate::{Error, HOSPITAL_STORAGE, ROLE_STORAGE};
+use candid::Principal;
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+// Roles a principal can hold, every role except SuperAdmin is scoped to a record id
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Debug,
+)]
+pub(crate) enum Role {
+    SuperAdmin,
+    HospitalAdmin,
+    HospitalStaff,
+    Donor,
+    PatientGuardian,
+}
+
+const ROLES: [Role; 5] = [
+    Role::SuperAdmin,
+    Role::HospitalAdmin,
+    Role::HospitalStaff,
+    Role::Donor,
+    Role::PatientGuardian,
+];
+
+// A single role held by a principal, `scope_id` is the hospital, donor or patient id
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Debug,
+)]
+pub(crate) struct RoleGrant {
+    pub(crate) role: Role,
+    pub(crate) scope_id: Option<u64>,
+}
+
+// The first grant in key order, where scans of a principal's grants start
+impl Default for RoleGrant {
+    fn default() -> Self {
+        RoleGrant {
+            role: Role::SuperAdmin,
+            scope_id: None,
+        }
+    }
+}
+
+// Stored as the position of the role in `ROLES`, then a scope flag and the big-endian scope id,
+// so grants sort in the same order as bytes and as values
+impl Storable for RoleGrant {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = vec![
+            ROLES.iter().position(|role| *role == self.role).unwrap() as u8,
+            u8::from(self.scope_id.is_some()),
+        ];
+        bytes.extend(self.scope_id.unwrap_or(0).to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let scope = u64::from_be_bytes(bytes[2..10].try_into().unwrap());
+        RoleGrant {
+            role: ROLES[bytes[0] as usize],
+            scope_id: (bytes[1] == 1).then_some(scope),
+        }
+    }
+}
+
+impl BoundedStorable for RoleGrant {
+    const MAX_SIZE: u32 = 10;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+// Principal wrapper so principals can be used as stable map keys
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct StorablePrincipal(pub(crate) Principal);
+
+impl Default for StorablePrincipal {
+    fn default() -> Self {
+        StorablePrincipal(Principal::anonymous())
+    }
+}
+
+impl Storable for StorablePrincipal {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_slice())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        StorablePrincipal(Principal::from_slice(bytes.as_ref()))
+    }
+}
+
+impl BoundedStorable for StorablePrincipal {
+    const MAX_SIZE: u32 = 29;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Check if a principal holds a role for the given scope
+pub(crate) fn has_role(principal: &Principal, role: Role, scope_id: Option<u64>) -> bool {
+    has_grant(principal, RoleGrant { role, scope_id })
+}
+
+pub(crate) fn is_super_admin(principal: &Principal) -> bool {
+    has_role(principal, Role::SuperAdmin, None)
+}
+
+// Require one of the given roles for the scope, super-admins pass every check
+pub(crate) fn require_role(
+    principal: &Principal,
+    roles: &[Role],
+    scope_id: Option<u64>,
+) -> Result<(), Error> {
+    if is_super_admin(principal)
+        || roles
+            .iter()
+            .any(|role| has_role(principal, *role, scope_id))
+    {
+        return Ok(());
+    }
+    Err(Error::Unauthorized {
+        msg: format!("Unauthorized, caller needs one of the roles {:?}", roles),
+    })
+}
+
+// Grants are stored one entry each, keyed by principal and grant, so a principal can hold any
+// number of them
+pub(crate) fn roles_of(principal: &Principal) -> Vec<RoleGrant> {
+    let principal = StorablePrincipal(*principal);
+    ROLE_STORAGE.with(|s| {
+        s.borrow()
+            .range((principal, RoleGrant::default())..)
+            .take_while(|((holder, _), _)| *holder == principal)
+            .map(|((_, grant), _)| grant)
+            .collect()
+    })
+}
+
+pub(crate) fn has_grant(principal: &Principal, grant: RoleGrant) -> bool {
+    ROLE_STORAGE.with(|s| {
+        s.borrow()
+            .contains_key(&(StorablePrincipal(*principal), grant))
+    })
+}
+
+pub(crate) fn grant(principal: Principal, grant: RoleGrant) {
+    ROLE_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert((StorablePrincipal(principal), grant), ())
+    });
+}
+
+// Remove a role, returns false if the principal did not hold it
+pub(crate) fn revoke(principal: Principal, grant: RoleGrant) -> bool {
+    ROLE_STORAGE.with(|s| {
+        s.borrow_mut()
+            .remove(&(StorablePrincipal(principal), grant))
+            .is_some()
+    })
+}
+
+pub(crate) fn super_admin_count() -> usize {
+    ROLE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|((_, grant), _)| grant.role == Role::SuperAdmin)
+            .count()
+    })
+}
+
+// Check if a principal is admin or staff of at least one hospital verified by a super-admin
+pub(crate) fn is_verified_hospital_staff(principal: &Principal) -> bool {
+    roles_of(principal).iter().any(|grant| {
+        matches!(grant.role, Role::HospitalAdmin | Role::HospitalStaff)
+            && grant
+                .scope_id
+                .and_then(|id| HOSPITAL_STORAGE.with(|s| s.borrow().get(&HospitalId(id))))
+                .is_some_and(|hospital| hospital.verified_by.is_some())
+    })
+}
+
+// Guard for updates open to any authenticated principal
+pub(crate) fn caller_is_authenticated() -> Result<(), String> {
+    if ic_cdk::caller() == Principal::anonymous() {
+        return Err("anonymous principals cannot perform updates".to_string());
+    }
+    Ok(())
+}
+
+// Guard for updates reserved to canister administrators
+pub(crate) fn caller_is_super_admin() -> Result<(), String> {
+    caller_is_authenticated()?;
+    if !is_super_admin(&ic_cdk::caller()) {
+        return Err("caller is not a canister administrator".to_string());
+    }
+    Ok(())
+}
+
+// Guard for updates reserved to staff of verified hospitals
+pub(crate) fn caller_is_verified_hospital_staff() -> Result<(), String> {
+    caller_is_authenticated()?;
+    let caller = ic_cdk::caller();
+    if !is_super_admin(&caller) && !is_verified_hospital_staff(&caller) {
+        return Err("caller is not staff of a verified hospital".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_principal_can_hold_any_number_of_grants() {
+        let guardian = Principal::from_slice(&[7; 29]);
+        let other = Principal::from_slice(&[7; 28]);
+        grant(
+            other,
+            RoleGrant {
+                role: Role::SuperAdmin,
+                scope_id: None,
+            },
+        );
+        for id in 0..500 {
+            grant(
+                guardian,
+                RoleGrant {
+                    role: Role::PatientGuardian,
+                    scope_id: Some(id),
+                },
+            );
+        }
+        grant(
+            guardian,
+            RoleGrant {
+                role: Role::Donor,
+                scope_id: Some(3),
+            },
+        );
+        assert_eq!(roles_of(&guardian).len(), 501);
+        assert!(has_role(&guardian, Role::PatientGuardian, Some(499)));
+        assert!(!is_super_admin(&guardian));
+        assert_eq!(super_admin_count(), 1);
+
+        let donor = RoleGrant {
+            role: Role::Donor,
+            scope_id: Some(3),
+        };
+        assert!(revoke(guardian, donor));
+        assert!(!revoke(guardian, donor));
+        assert!(!has_role(&guardian, Role::Donor, Some(3)));
+        assert_eq!(roles_of(&other).len(), 1);
+    }
+}
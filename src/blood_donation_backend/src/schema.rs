@@ -0,0 +1,517 @@
+use crate::ids::RecordId;
+use crate::{ids, indexes, relations, search, staff, Error, Memory, SCHEMA_VERSION_CELL};
+use ic_stable_structures::{BoundedStorable, StableBTreeMap};
+use std::cell::RefCell;
+use std::ops::Bound;
+use std::thread::LocalKey;
+use std::time::Duration;
+
+// Schema version of the stable memory written by this build. Bump it with every change that
+// needs existing data rewritten and add the matching entry to `MIGRATIONS`.
+pub(crate) const SCHEMA_VERSION: u32 = 3;
+
+// Layout versions of the enveloped records, bumped when the candid type of the record changes
+// in a way older bytes cannot be decoded into
+pub(crate) const PATIENT_VERSION: u16 = 1;
+pub(crate) const HOSPITAL_VERSION: u16 = 1;
+pub(crate) const DONOR_VERSION: u16 = 1;
+
+// Enveloped records start with these bytes, followed by the layout version as little endian u16
+// and the candid encoding of the record. Candid itself starts with "DIDL".
+const ENVELOPE_MAGIC: &[u8; 4] = b"BDRV";
+
+// Records a migration pass goes through per timer tick, keeping each tick under the instruction
+// limit
+const MIGRATION_BATCH: usize = 100;
+
+// A pass of a migration over one storage: goes through up to `limit` records stored after the id
+// `after` and returns the id to resume after, or None once it reached the end of the storage
+type Pass = fn(Option<u64>, usize) -> Option<u64>;
+
+// A migration bringing the stable memory to `version`. `start` runs in post_upgrade itself,
+// before any message is handled, then every pass goes through its storage in batches from
+// timers. The version is stored once the last pass is done, so an upgrade in between runs the
+// whole migration again and each step must be safe to repeat.
+struct Migration {
+    version: u32,
+    start: fn(),
+    passes: &'static [Pass],
+}
+
+// Migrations in order, each bringing the stable memory to the schema version it is listed with
+const MIGRATIONS: [Migration; 3] = [
+    // Bring data written before the schema version existed up to date: id sequences starting
+    // after every id handed out so far, roles and memberships for existing owners, patients
+    // linked to hospitals, relations moved to indexes, and every patient, hospital and donor
+    // rewritten in an envelope
+    Migration {
+        version: 1,
+        start: start_v1,
+        passes: &[
+            crate::link_patient_hospitals,
+            relations::move_hospital_relations,
+            relations::move_patient_relations,
+            relations::move_donor_relations,
+            crate::rewrite_patients,
+            crate::rewrite_hospitals,
+            crate::rewrite_donors,
+        ],
+    },
+    // Index hospitals by city, patients by blood group, completion and hospital, and donors by
+    // blood group
+    Migration {
+        version: 2,
+        start: || {},
+        passes: &[
+            indexes::index_hospitals,
+            indexes::index_patients,
+            indexes::index_donors,
+        ],
+    },
+    // Index the words of hospital names, cities and addresses and of patient descriptions
+    Migration {
+        version: 3,
+        start: || {},
+        passes: &[search::index_hospital_words, search::index_patient_words],
+    },
+];
+
+// Where the running migrations are: the migration, its pass and the id the pass stopped after
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Progress {
+    migration: usize,
+    pass: usize,
+    after: Option<u64>,
+}
+
+// Prefix the candid encoding of a record with its layout version
+pub(crate) fn seal(version: u16, candid: Vec<u8>) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(ENVELOPE_MAGIC.len() + 2 + candid.len());
+    bytes.extend_from_slice(ENVELOPE_MAGIC);
+    bytes.extend_from_slice(&version.to_le_bytes());
+    bytes.extend_from_slice(&candid);
+    bytes
+}
+
+// Split stored bytes into the layout version and the candid encoding of the record. Records
+// written before envelopes existed are plain candid and have version 0.
+pub(crate) fn open(bytes: &[u8]) -> (u16, &[u8]) {
+    match bytes.strip_prefix(ENVELOPE_MAGIC) {
+        Some([low, high, candid @ ..]) => (u16::from_le_bytes([*low, *high]), candid),
+        _ => (0, bytes),
+    }
+}
+
+fn stored_version() -> u32 {
+    SCHEMA_VERSION_CELL.with(|cell| *cell.borrow().get())
+}
+
+fn set_version(version: u32) {
+    SCHEMA_VERSION_CELL
+        .with(|cell| cell.borrow_mut().set(version))
+        .expect("Cannot store the schema version");
+}
+
+// A fresh install starts at the current schema, nothing needs migrating
+pub(crate) fn init_version() {
+    set_version(SCHEMA_VERSION);
+}
+
+// Run the migrations the stable memory has not been through yet: their starts at once, then
+// their passes in batches from timers. Canisters installed before the schema version existed read
+// version 0 and run every migration. Refuses to run on memory written by a newer build, which
+// this build cannot read.
+pub(crate) fn migrate() {
+    if let Some(progress) = start_migrations() {
+        schedule_migration(progress);
+    }
+}
+
+// Run the starts of the pending migrations, returns where their passes begin
+fn start_migrations() -> Option<Progress> {
+    let from = stored_version();
+    if from > SCHEMA_VERSION {
+        ic_cdk::trap(&format!(
+            "stable memory has schema version {}, newer than the version {} of this build",
+            from, SCHEMA_VERSION
+        ));
+    }
+    for migration in MIGRATIONS.iter().filter(|m| m.version > from) {
+        (migration.start)();
+    }
+    first_pending(from)
+}
+
+// Refuse calls reading or updating the indexes while the migrations building them still run
+pub(crate) fn check_migrated() -> Result<(), Error> {
+    match stored_version() {
+        SCHEMA_VERSION => Ok(()),
+        version => Err(Error::Migrating {
+            msg: format!(
+                "the canister is migrating its data from schema version {} to {}, try again shortly",
+                version, SCHEMA_VERSION
+            ),
+        }),
+    }
+}
+
+// The start of the first migration newer than `version`
+fn first_pending(version: u32) -> Option<Progress> {
+    MIGRATIONS
+        .iter()
+        .position(|migration| migration.version > version)
+        .map(|migration| Progress {
+            migration,
+            pass: 0,
+            after: None,
+        })
+}
+
+fn schedule_migration(progress: Progress) {
+    ic_cdk_timers::set_timer(Duration::ZERO, move || {
+        // re-arm until the last migration is done
+        if let Some(next) = migrate_batch(progress, MIGRATION_BATCH) {
+            schedule_migration(next);
+        }
+    });
+}
+
+// Run one batch of the migrations from `progress`, returns where the next batch starts, or None
+// once the stable memory is at the schema version of this build
+fn migrate_batch(progress: Progress, limit: usize) -> Option<Progress> {
+    let migration = &MIGRATIONS[progress.migration];
+    if let Some(pass) = migration.passes.get(progress.pass) {
+        if let Some(after) = pass(progress.after, limit) {
+            return Some(Progress {
+                after: Some(after),
+                ..progress
+            });
+        }
+        if progress.pass + 1 < migration.passes.len() {
+            return Some(Progress {
+                pass: progress.pass + 1,
+                after: None,
+                ..progress
+            });
+        }
+    }
+    set_version(migration.version);
+    first_pending(migration.version)
+}
+
+// Up to `limit` records of a storage stored after the id `after`, and the id the next batch
+// starts after, None once the batch reached the end of the storage
+pub(crate) fn batch<I, T>(
+    storage: &'static LocalKey<RefCell<StableBTreeMap<I, T, Memory>>>,
+    after: Option<u64>,
+    limit: usize,
+) -> (Vec<T>, Option<u64>)
+where
+    I: RecordId + From<u64> + Into<u64>,
+    T: BoundedStorable,
+{
+    let start = after.map_or(Bound::Unbounded, |id| Bound::Excluded(I::from(id)));
+    let records: Vec<(I, T)> = storage.with(|s| {
+        s.borrow()
+            .range((start, Bound::Unbounded))
+            .take(limit)
+            .collect()
+    });
+    let next = match records.len() == limit {
+        true => records.last().map(|(id, _)| (*id).into()),
+        false => None,
+    };
+    (
+        records.into_iter().map(|(_, record)| record).collect(),
+        next,
+    )
+}
+
+// Start the id sequences after every id handed out so far and give existing owners and staff
+// their roles and memberships
+fn start_v1() {
+    ids::split_shared_sequence();
+    crate::backfill_owner_roles();
+    staff::backfill_memberships();
+}
+
+// the schema version of the stable memory
+#[ic_cdk::query]
+fn get_schema_version() -> u32 {
+    stored_version()
+}
+
+// Records stored by every earlier version of the canister, captured as hex from the build that
+// wrote them. A fixture is kept for each change to the layout of a record.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blood_group::BloodGroup;
+    use crate::components::Component;
+    use crate::ids::{DonorId, HospitalId, PatientId};
+    use crate::{Donor, Hospital, Patient, DONOR_STORAGE, HOSPITAL_STORAGE, PATIENT_STORAGE};
+    use candid::Principal;
+    use ic_stable_structures::Storable;
+    use std::borrow::Cow;
+
+    fn fixture<T: Storable>(hex: &str) -> T {
+        let bytes: Vec<u8> = (0..hex.trim().len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect();
+        T::from_bytes(Cow::Owned(bytes))
+    }
+
+    // Run migrations from `progress` to the end, `limit` records per batch
+    fn run_migrations(mut progress: Option<Progress>, limit: usize) {
+        while let Some(at) = progress {
+            progress = migrate_batch(at, limit);
+        }
+    }
+
+    fn owner() -> Option<Principal> {
+        Some(Principal::from_slice(&[1; 10]))
+    }
+
+    const PATIENTS: [(&str, &str); 8] = [
+        ("baseline", include_str!("../fixtures/patient_baseline.hex")),
+        ("owner", include_str!("../fixtures/patient_owner.hex")),
+        (
+            "typed_groups",
+            include_str!("../fixtures/patient_typed_groups.hex"),
+        ),
+        ("needs", include_str!("../fixtures/patient_needs.hex")),
+        (
+            "hospital_id",
+            include_str!("../fixtures/patient_hospital_id.hex"),
+        ),
+        (
+            "relation_index",
+            include_str!("../fixtures/patient_relation_index.hex"),
+        ),
+        ("urgency", include_str!("../fixtures/patient_urgency.hex")),
+        (
+            "registered_at",
+            include_str!("../fixtures/patient_registered_at.hex"),
+        ),
+    ];
+
+    const HOSPITALS: [(&str, &str); 5] = [
+        (
+            "baseline",
+            include_str!("../fixtures/hospital_baseline.hex"),
+        ),
+        ("owner", include_str!("../fixtures/hospital_owner.hex")),
+        (
+            "verified",
+            include_str!("../fixtures/hospital_verified.hex"),
+        ),
+        (
+            "relation_index",
+            include_str!("../fixtures/hospital_relation_index.hex"),
+        ),
+        (
+            "location",
+            include_str!("../fixtures/hospital_location.hex"),
+        ),
+    ];
+
+    const DONORS: [(&str, &str); 5] = [
+        ("baseline", include_str!("../fixtures/donor_baseline.hex")),
+        ("owner", include_str!("../fixtures/donor_owner.hex")),
+        (
+            "typed_groups",
+            include_str!("../fixtures/donor_typed_groups.hex"),
+        ),
+        (
+            "relation_index",
+            include_str!("../fixtures/donor_relation_index.hex"),
+        ),
+        ("city", include_str!("../fixtures/donor_city.hex")),
+    ];
+
+    #[test]
+    fn decodes_patients_of_every_version() {
+        for (version, hex) in PATIENTS {
+            let patient: Patient = fixture(hex);
+            assert_eq!(patient.id, PatientId(7), "{}", version);
+            assert_eq!(patient.name, "Jane", "{}", version);
+            assert_eq!(patient.blood_group, BloodGroup::OPositive, "{}", version);
+            assert_eq!(patient.needed_pints, 3, "{}", version);
+            assert_eq!(
+                patient.owner.is_some(),
+                version != "baseline",
+                "{}",
+                version
+            );
+            let needs = patient.needs();
+            assert_eq!(needs[0].component, Component::WholeBlood, "{}", version);
+            assert_eq!(needs[0].donations, 1, "{}", version);
+            // records written once relations had moved to indexes keep no donor ids
+            let indexed = matches!(version, "urgency" | "registered_at");
+            let donors_ids = (!indexed).then(|| vec![DonorId(4)]);
+            assert_eq!(patient.donors_ids, donors_ids, "{}", version);
+            let linked = indexed || matches!(version, "hospital_id" | "relation_index");
+            assert_eq!(patient.hospital_id.is_some(), linked, "{}", version);
+            assert_eq!(patient.hospital.is_empty(), linked, "{}", version);
+            assert_eq!(patient.urgency.is_some(), indexed, "{}", version);
+            assert_eq!(
+                patient.registered_at.is_some(),
+                version == "registered_at",
+                "{}",
+                version
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_hospitals_and_donors_of_every_version() {
+        for (version, hex) in HOSPITALS {
+            let hospital: Hospital = fixture(hex);
+            assert_eq!(hospital.id, HospitalId(2), "{}", version);
+            assert_eq!(hospital.city, "Nairobi", "{}", version);
+            assert_eq!(hospital.donations, 1, "{}", version);
+            assert_eq!(
+                hospital.owner,
+                if version == "baseline" { None } else { owner() },
+                "{}",
+                version
+            );
+            assert_eq!(
+                hospital.verified_by.is_some(),
+                matches!(version, "verified" | "relation_index" | "location"),
+                "{}",
+                version
+            );
+            assert_eq!(
+                hospital.location.is_some(),
+                version == "location",
+                "{}",
+                version
+            );
+        }
+        for (version, hex) in DONORS {
+            let donor: Donor = fixture(hex);
+            assert_eq!(donor.id, DonorId(4), "{}", version);
+            assert_eq!(donor.name, "John", "{}", version);
+            assert_eq!(donor.blood_group, BloodGroup::OPositive, "{}", version);
+            let beneficiaries = (version != "city").then(|| vec![7]);
+            assert_eq!(donor.beneficiaries, beneficiaries, "{}", version);
+            assert_eq!(donor.city.is_some(), version == "city", "{}", version);
+        }
+    }
+
+    #[test]
+    fn migration_rewrites_records_in_envelopes() {
+        let (_, patient) = PATIENTS[0];
+        let (_, hospital) = HOSPITALS[0];
+        let (_, donor) = DONORS[0];
+        PATIENT_STORAGE.with(|s| s.borrow_mut().insert(PatientId(7), fixture(patient)));
+        HOSPITAL_STORAGE.with(|s| s.borrow_mut().insert(HospitalId(2), fixture(hospital)));
+        DONOR_STORAGE.with(|s| s.borrow_mut().insert(DonorId(4), fixture(donor)));
+
+        assert_eq!(stored_version(), 0);
+        run_migrations(start_migrations(), 1);
+        assert_eq!(stored_version(), SCHEMA_VERSION);
+
+        let patient = PATIENT_STORAGE
+            .with(|s| s.borrow().get(&PatientId(7)))
+            .unwrap();
+        let bytes = patient.to_bytes();
+        assert_eq!(open(&bytes).0, PATIENT_VERSION);
+        // the free text hospital name matched the stored hospital
+        assert_eq!(patient.hospital_id, Some(HospitalId(2)));
+        assert_eq!(patient.donors_ids, None);
+        let donor = DONOR_STORAGE.with(|s| s.borrow().get(&DonorId(4))).unwrap();
+        assert_eq!(open(&donor.to_bytes()).0, DONOR_VERSION);
+        assert_eq!(donor.beneficiaries, None);
+    }
+
+    #[test]
+    fn migrations_run_in_batches_and_resume_from_the_start() {
+        for id in 1..=5 {
+            let patient: Patient = fixture(PATIENTS[0].1);
+            let patient = Patient {
+                id: PatientId(id),
+                ..patient
+            };
+            PATIENT_STORAGE.with(|s| s.borrow_mut().insert(PatientId(id), patient));
+        }
+        let (_, hospital) = HOSPITALS[0];
+        HOSPITAL_STORAGE.with(|s| s.borrow_mut().insert(HospitalId(2), fixture(hospital)));
+
+        // a batch stops after `limit` records and the version waits for the last pass
+        let mut progress = start_migrations();
+        for _ in 0..5 {
+            progress = migrate_batch(progress.unwrap(), 2);
+        }
+        assert_eq!(
+            progress,
+            Some(Progress {
+                migration: 0,
+                pass: 2,
+                after: Some(2)
+            })
+        );
+        assert_eq!(stored_version(), 0);
+
+        // an upgrade in the middle starts the migration again without linking donors twice
+        run_migrations(start_migrations(), 2);
+        assert_eq!(stored_version(), SCHEMA_VERSION);
+        for id in 1..=5 {
+            let patient = PATIENT_STORAGE
+                .with(|s| s.borrow().get(&PatientId(id)))
+                .unwrap();
+            assert_eq!(patient.hospital_id, Some(HospitalId(2)));
+            assert_eq!(patient.donors_ids, None);
+            assert_eq!(
+                crate::PATIENT_DONOR_INDEX.with(|s| s.borrow().get(&(PatientId(id), DonorId(4)))),
+                Some(1)
+            );
+            assert!(indexes::is_open(PatientId(id)));
+        }
+    }
+
+    #[test]
+    fn records_added_right_after_the_upgrade_get_new_ids() {
+        let (_, hospital) = HOSPITALS[1];
+        HOSPITAL_STORAGE.with(|s| s.borrow_mut().insert(HospitalId(2), fixture(hospital)));
+        // the counter shared by every record of the baseline build
+        crate::ID_COUNTER.with(|counter| counter.borrow_mut().set(3).unwrap());
+
+        // what post_upgrade runs before any message is handled
+        let progress = start_migrations();
+        let caller = Principal::from_slice(&[7; 10]);
+        let payload = crate::HospitalPayload {
+            name: "Coast General".to_string(),
+            address: "Hospital Road".to_string(),
+            city: "Mombasa".to_string(),
+            location: None,
+        };
+        let added = crate::create_hospital(caller, payload).unwrap();
+        assert_eq!(added.id, HospitalId(3));
+        let kept = HOSPITAL_STORAGE
+            .with(|s| s.borrow().get(&HospitalId(2)))
+            .unwrap();
+        assert_eq!(kept.city, "Nairobi");
+        // the owners of existing records hold their roles already
+        assert!(crate::roles::has_role(
+            &owner().unwrap(),
+            crate::roles::Role::HospitalAdmin,
+            Some(2)
+        ));
+
+        // lists read from indexes wait for the passes building them
+        assert!(matches!(
+            crate::get_hospitals_by_city("Nairobi".to_string(), Default::default()),
+            Err(Error::Migrating { .. })
+        ));
+        run_migrations(progress, 10);
+        assert_eq!(
+            crate::get_hospitals_by_city("Nairobi".to_string(), Default::default())
+                .unwrap()
+                .total,
+            1
+        );
+    }
+}
@@ -0,0 +1,500 @@
+use crate::ids::{DonorId, HospitalId, PledgeId};
+use crate::roles::{caller_is_authenticated, caller_is_super_admin};
+use crate::{
+    caller_principal, eligibility, now, pledges, Error, SCREENING_RULE_STORAGE, SCREENING_STORAGE,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+use validator::Validate;
+
+const MAX_MEDICATIONS: u64 = 10;
+const MAX_MEDICATION_LENGTH: usize = 64;
+const MAX_SCREENING_RULES: usize = 32;
+// Longest reason of a rule, and of the reasons of the failed rules joined into a deferral
+const MAX_REASON_LENGTH: usize = 200;
+
+// Answers to the health questionnaire taken before a collection
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, Validate)]
+pub(crate) struct ScreeningAnswers {
+    pub(crate) travelled_recently: bool,
+    #[validate(length(max = "MAX_MEDICATIONS"))]
+    pub(crate) medications: Vec<String>,
+    pub(crate) tattoo_or_piercing_recently: bool,
+    pub(crate) illness_recently: bool,
+    #[validate(range(min = 1))]
+    pub(crate) weight_kg: u32,
+    // measured by staff at the collection site, refused from donors
+    pub(crate) haemoglobin_g_per_l: Option<u32>,
+}
+
+// A question of the questionnaire that can defer a donor
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) enum ScreeningCheck {
+    MinWeightKg(u32),
+    MinHaemoglobin(u32),
+    RecentTravel,
+    RecentTattooOrPiercing,
+    RecentIllness,
+    Medication(String),
+}
+
+// A failed check defers the donor for `deferral_days`, or permanently when None
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ScreeningRule {
+    check: ScreeningCheck,
+    deferral_days: Option<u32>,
+    reason: String,
+}
+
+// A version of the screening rules, every screening records the version it was scored with
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct ScreeningRules {
+    version: u32,
+    rules: Vec<ScreeningRule>,
+    set_by: Option<Principal>,
+    set_at: u64,
+}
+
+impl Storable for ScreeningRules {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ScreeningRules {
+    // the most rules with the longest reasons and medication names
+    const MAX_SIZE: u32 = 12288;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) enum ScreeningOutcome {
+    Cleared,
+    Deferred { days: Option<u32>, reason: String },
+}
+
+// The questionnaire answered for a pledge, kept with the donation it was taken for.
+// `hospital_id` is the hospital of the staff who submitted it, None when the donor did.
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct Screening {
+    pledge_id: PledgeId,
+    donor_id: DonorId,
+    rules_version: u32,
+    answers: ScreeningAnswers,
+    outcome: ScreeningOutcome,
+    submitted_by: Principal,
+    hospital_id: Option<HospitalId>,
+    submitted_at: u64,
+}
+
+impl Storable for Screening {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Screening {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+pub(crate) struct ScreeningPayload {
+    pub(crate) pledge_id: PledgeId,
+    pub(crate) answers: ScreeningAnswers,
+}
+
+fn rule(check: ScreeningCheck, deferral_days: Option<u32>, reason: &str) -> ScreeningRule {
+    ScreeningRule {
+        check,
+        deferral_days,
+        reason: reason.to_string(),
+    }
+}
+
+// Rules in force until an administrator sets the first version of their own
+fn default_rules() -> ScreeningRules {
+    ScreeningRules {
+        version: 1,
+        rules: vec![
+            rule(
+                ScreeningCheck::MinWeightKg(50),
+                Some(180),
+                "weight under 50 kg",
+            ),
+            rule(
+                ScreeningCheck::MinHaemoglobin(125),
+                Some(90),
+                "haemoglobin under 125 g/L",
+            ),
+            rule(ScreeningCheck::RecentTravel, Some(28), "recent travel"),
+            rule(
+                ScreeningCheck::RecentTattooOrPiercing,
+                Some(120),
+                "recent tattoo or piercing",
+            ),
+            rule(ScreeningCheck::RecentIllness, Some(14), "recent illness"),
+            rule(
+                ScreeningCheck::Medication("isotretinoin".to_string()),
+                Some(30),
+                "taking isotretinoin",
+            ),
+            rule(
+                ScreeningCheck::Medication("acitretin".to_string()),
+                None,
+                "taken acitretin",
+            ),
+        ],
+        set_by: None,
+        set_at: 0,
+    }
+}
+
+fn current_rules() -> ScreeningRules {
+    SCREENING_RULE_STORAGE
+        .with(|s| s.borrow().last_key_value().map(|(_, rules)| rules))
+        .unwrap_or_else(default_rules)
+}
+
+fn fails(check: &ScreeningCheck, answers: &ScreeningAnswers) -> bool {
+    match check {
+        ScreeningCheck::MinWeightKg(min) => answers.weight_kg < *min,
+        // haemoglobin is only judged once it has been measured
+        ScreeningCheck::MinHaemoglobin(min) => answers
+            .haemoglobin_g_per_l
+            .is_some_and(|reading| reading < *min),
+        ScreeningCheck::RecentTravel => answers.travelled_recently,
+        ScreeningCheck::RecentTattooOrPiercing => answers.tattoo_or_piercing_recently,
+        ScreeningCheck::RecentIllness => answers.illness_recently,
+        ScreeningCheck::Medication(name) => answers
+            .medications
+            .iter()
+            .any(|medication| medication.trim().eq_ignore_ascii_case(name)),
+    }
+}
+
+// Defer for the longest deferral among the failed rules, a permanent one wins
+fn evaluate(rules: &ScreeningRules, answers: &ScreeningAnswers) -> ScreeningOutcome {
+    let failed: Vec<&ScreeningRule> = rules
+        .rules
+        .iter()
+        .filter(|rule| fails(&rule.check, answers))
+        .collect();
+    if failed.is_empty() {
+        return ScreeningOutcome::Cleared;
+    }
+    let days = match failed.iter().any(|rule| rule.deferral_days.is_none()) {
+        true => None,
+        false => failed.iter().filter_map(|rule| rule.deferral_days).max(),
+    };
+    let mut reason = failed
+        .iter()
+        .map(|rule| rule.reason.as_str())
+        .collect::<Vec<&str>>()
+        .join(", ");
+    // cut to fit a deferral, the rules stay readable from the version the screening records
+    if reason.len() > MAX_REASON_LENGTH {
+        let end = (0..=MAX_REASON_LENGTH - 3)
+            .rev()
+            .find(|end| reason.is_char_boundary(*end))
+            .unwrap_or(0);
+        reason.truncate(end);
+        reason.push_str("...");
+    }
+    ScreeningOutcome::Deferred { days, reason }
+}
+
+// Score the answers for a pledge and defer the donor when the screening fails
+pub(crate) fn submit(caller: Principal, payload: ScreeningPayload) -> Result<Screening, Error> {
+    if let Err(e) = payload.answers.validate() {
+        return Err(Error::InvalidPayload { msg: e.to_string() });
+    }
+    if let Some(medication) = payload
+        .answers
+        .medications
+        .iter()
+        .find(|medication| medication.len() > MAX_MEDICATION_LENGTH)
+    {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "medication name {:?} is longer than {} characters",
+                medication, MAX_MEDICATION_LENGTH
+            ),
+        });
+    }
+    let (donor_id, hospital_id) = pledges::screening_submitter(&caller, payload.pledge_id)?;
+    if hospital_id.is_none() && payload.answers.haemoglobin_g_per_l.is_some() {
+        return Err(Error::InvalidPayload {
+            msg: "haemoglobin is measured by staff at the collection site, leave it out"
+                .to_string(),
+        });
+    }
+
+    let rules = current_rules();
+    let outcome = evaluate(&rules, &payload.answers);
+    let screening = Screening {
+        pledge_id: payload.pledge_id,
+        donor_id,
+        rules_version: rules.version,
+        answers: payload.answers,
+        outcome: outcome.clone(),
+        submitted_by: caller,
+        hospital_id,
+        submitted_at: now(),
+    };
+    SCREENING_STORAGE.with(|s| {
+        s.borrow_mut()
+            .insert(screening.pledge_id, screening.clone())
+    });
+    if let ScreeningOutcome::Deferred { days, reason } = outcome {
+        eligibility::record_deferral(donor_id, days, reason, hospital_id, caller);
+    }
+    Ok(screening)
+}
+
+// Blood is only collected for a pledge whose donor was cleared with a haemoglobin reading taken
+// by staff
+pub(crate) fn check_cleared(pledge_id: PledgeId) -> Result<(), Error> {
+    let screening = SCREENING_STORAGE
+        .with(|s| s.borrow().get(&pledge_id))
+        .ok_or(Error::InvalidPayload {
+            msg: format!(
+                "pledge of id: {} has not been screened, submit a screening first",
+                pledge_id
+            ),
+        })?;
+    if let ScreeningOutcome::Deferred { reason, .. } = screening.outcome {
+        return Err(Error::IneligibleDonor {
+            msg: format!(
+                "screening for pledge of id: {} deferred the donor: {}",
+                pledge_id, reason
+            ),
+        });
+    }
+    if screening.answers.haemoglobin_g_per_l.is_none() || screening.hospital_id.is_none() {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "screening for pledge of id: {} has no haemoglobin reading taken by staff",
+                pledge_id
+            ),
+        });
+    }
+    Ok(())
+}
+
+// answer the screening questionnaire for an open pledge, as its donor or as collecting staff
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn submit_screening(payload: ScreeningPayload) -> Result<Screening, Error> {
+    let caller = caller_principal()?;
+    submit(caller, payload)
+}
+
+fn check_rules(rules: &[ScreeningRule]) -> Result<(), Error> {
+    if rules.is_empty() || rules.len() > MAX_SCREENING_RULES {
+        return Err(Error::InvalidPayload {
+            msg: format!("expected 1 to {} screening rules", MAX_SCREENING_RULES),
+        });
+    }
+    if rules.iter().any(|rule| rule.reason.trim().is_empty()) {
+        return Err(Error::InvalidPayload {
+            msg: "every screening rule needs a reason".to_string(),
+        });
+    }
+    if rules.iter().any(|rule| {
+        rule.deferral_days
+            .is_some_and(|days| days == 0 || days > eligibility::MAX_DEFERRAL_DAYS)
+    }) {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "deferrals must last 1 to {} days",
+                eligibility::MAX_DEFERRAL_DAYS
+            ),
+        });
+    }
+    for rule in rules {
+        if rule.reason.len() > MAX_REASON_LENGTH {
+            return Err(Error::InvalidPayload {
+                msg: format!(
+                    "reason {:?} is longer than {} characters",
+                    rule.reason, MAX_REASON_LENGTH
+                ),
+            });
+        }
+        match &rule.check {
+            ScreeningCheck::Medication(name) if name.len() > MAX_MEDICATION_LENGTH => {
+                return Err(Error::InvalidPayload {
+                    msg: format!(
+                        "medication name {:?} is longer than {} characters",
+                        name, MAX_MEDICATION_LENGTH
+                    ),
+                })
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// publish a new version of the screening rules, only for canister administrators
+#[ic_cdk::update(guard = "caller_is_super_admin")]
+fn set_screening_rules(rules: Vec<ScreeningRule>) -> Result<ScreeningRules, Error> {
+    check_rules(&rules)?;
+    let rules = ScreeningRules {
+        version: current_rules().version + 1,
+        rules,
+        set_by: Some(ic_cdk::caller()),
+        set_at: now(),
+    };
+    SCREENING_RULE_STORAGE.with(|s| s.borrow_mut().insert(rules.version, rules.clone()));
+    Ok(rules)
+}
+
+// get the screening rules in force
+#[ic_cdk::query]
+fn get_screening_rules() -> ScreeningRules {
+    current_rules()
+}
+
+// get the screening taken for a pledge, visible to whoever can see the pledge
+#[ic_cdk::query]
+fn get_screening(pledge_id: PledgeId) -> Result<Screening, Error> {
+    pledges::authorize_pledge_viewer(&ic_cdk::caller(), pledge_id)?;
+    SCREENING_STORAGE
+        .with(|s| s.borrow().get(&pledge_id))
+        .ok_or(Error::NotFound {
+            msg: format!("screening for pledge of id: {} not found", pledge_id),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn healthy() -> ScreeningAnswers {
+        ScreeningAnswers {
+            weight_kg: 70,
+            haemoglobin_g_per_l: Some(140),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn healthy_donor_is_cleared() {
+        assert_eq!(
+            evaluate(&default_rules(), &healthy()),
+            ScreeningOutcome::Cleared
+        );
+        // an unmeasured haemoglobin does not defer
+        let unmeasured = ScreeningAnswers {
+            haemoglobin_g_per_l: None,
+            ..healthy()
+        };
+        assert_eq!(
+            evaluate(&default_rules(), &unmeasured),
+            ScreeningOutcome::Cleared
+        );
+    }
+
+    #[test]
+    fn longest_deferral_wins() {
+        let answers = ScreeningAnswers {
+            travelled_recently: true,
+            tattoo_or_piercing_recently: true,
+            ..healthy()
+        };
+        assert_eq!(
+            evaluate(&default_rules(), &answers),
+            ScreeningOutcome::Deferred {
+                days: Some(120),
+                reason: "recent travel, recent tattoo or piercing".to_string(),
+            }
+        );
+
+        let answers = ScreeningAnswers {
+            medications: vec![" Acitretin ".to_string()],
+            weight_kg: 45,
+            ..healthy()
+        };
+        assert!(matches!(
+            evaluate(&default_rules(), &answers),
+            ScreeningOutcome::Deferred { days: None, .. }
+        ));
+    }
+
+    fn longest_rule(n: usize) -> ScreeningRule {
+        rule(
+            ScreeningCheck::Medication(format!("{:0>1$}", n, MAX_MEDICATION_LENGTH)),
+            Some(eligibility::MAX_DEFERRAL_DAYS),
+            &"r".repeat(MAX_REASON_LENGTH),
+        )
+    }
+
+    #[test]
+    fn rules_and_joined_reasons_are_bounded() {
+        let mut rules: Vec<ScreeningRule> = (0..MAX_SCREENING_RULES).map(longest_rule).collect();
+        assert!(check_rules(&rules).is_ok());
+        let stored = ScreeningRules {
+            version: u32::MAX,
+            rules: rules.clone(),
+            set_by: Some(Principal::from_slice(&[1; 29])),
+            set_at: u64::MAX,
+        };
+        assert!(stored.to_bytes().len() <= ScreeningRules::MAX_SIZE as usize);
+
+        // every rule fails, the joined reason is cut to fit a deferral
+        let answers = ScreeningAnswers {
+            medications: (0..MAX_MEDICATIONS as usize)
+                .map(|n| format!("{:0>1$}", n, MAX_MEDICATION_LENGTH))
+                .collect(),
+            ..healthy()
+        };
+        match evaluate(&stored, &answers) {
+            ScreeningOutcome::Deferred { reason, .. } => {
+                assert_eq!(reason.len(), MAX_REASON_LENGTH);
+                assert!(reason.ends_with("..."));
+            }
+            outcome => panic!("expected a deferral, got {:?}", outcome),
+        }
+
+        rules[0].reason.push('r');
+        assert!(check_rules(&rules).is_err());
+        rules[0] = longest_rule(0);
+        rules[0].check = ScreeningCheck::Medication("m".repeat(MAX_MEDICATION_LENGTH + 1));
+        assert!(check_rules(&rules).is_err());
+    }
+
+    #[test]
+    fn only_staff_readings_clear_a_donor() {
+        let screening = |hospital_id| Screening {
+            pledge_id: PledgeId(1),
+            donor_id: DonorId(2),
+            rules_version: 1,
+            answers: healthy(),
+            outcome: ScreeningOutcome::Cleared,
+            submitted_by: Principal::anonymous(),
+            hospital_id,
+            submitted_at: 0,
+        };
+        SCREENING_STORAGE.with(|s| s.borrow_mut().insert(PledgeId(1), screening(None)));
+        assert!(matches!(
+            check_cleared(PledgeId(1)),
+            Err(Error::InvalidPayload { .. })
+        ));
+        SCREENING_STORAGE.with(|s| {
+            s.borrow_mut()
+                .insert(PledgeId(1), screening(Some(HospitalId(3))))
+        });
+        assert!(check_cleared(PledgeId(1)).is_ok());
+    }
+}
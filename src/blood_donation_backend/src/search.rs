@@ -0,0 +1,461 @@
+use crate::ids::{HospitalId, PatientId};
+use crate::indexes::IndexText;
+use crate::schema;
+use crate::{Error, Hospital, Patient, HOSPITAL_STORAGE, PATIENT_STORAGE, SEARCH_INDEX};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+// Shorter words match too many records to be worth indexing or searching
+const MIN_TOKEN_LENGTH: usize = 2;
+// Shorter words of a search match whole words only, as the words they start are too many
+const MIN_PREFIX_LENGTH: usize = 3;
+// Most index entries read for a word of a search, whole words first as they sort before the
+// words they start
+const MAX_TERM_POSTINGS: usize = 5000;
+// Words of a search beyond these are ignored
+const MAX_QUERY_TOKENS: usize = 8;
+// Most hits returned by a single search
+const MAX_SEARCH_HITS: u32 = 100;
+const DEFAULT_SEARCH_HITS: u32 = 20;
+
+// Texts of hospitals and patients that can be searched
+#[derive(
+    candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Debug,
+)]
+pub(crate) enum SearchField {
+    HospitalName,
+    HospitalCity,
+    HospitalAddress,
+    PatientDescription,
+}
+
+const FIELDS: [SearchField; 4] = [
+    SearchField::HospitalName,
+    SearchField::HospitalCity,
+    SearchField::HospitalAddress,
+    SearchField::PatientDescription,
+];
+
+impl SearchField {
+    // How much a word found in the field counts towards the relevance of a hit
+    fn weight(self) -> u32 {
+        match self {
+            SearchField::HospitalName => 3,
+            SearchField::HospitalCity => 2,
+            SearchField::HospitalAddress | SearchField::PatientDescription => 1,
+        }
+    }
+
+    fn is_hospital(self) -> bool {
+        self != SearchField::PatientDescription
+    }
+}
+
+// A field of a record holding a word, the value of a search index entry
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub(crate) struct Posting {
+    field: u8,
+    id: u64,
+}
+
+impl Posting {
+    fn new(field: SearchField, id: u64) -> Self {
+        let field = FIELDS.iter().position(|f| *f == field).unwrap_or(0) as u8;
+        Posting { field, id }
+    }
+
+    fn field(self) -> SearchField {
+        FIELDS[usize::from(self.field) % FIELDS.len()]
+    }
+}
+
+impl Storable for Posting {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = vec![self.field];
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let mut id = [0; 8];
+        id.copy_from_slice(&bytes[1..9]);
+        Posting {
+            field: bytes[0],
+            id: u64::from_be_bytes(id),
+        }
+    }
+}
+
+impl BoundedStorable for Posting {
+    const MAX_SIZE: u32 = 9;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+// A search over the words of hospitals and patients. Every word must match, as a whole word or
+// as the start of one, in one of `fields` (all of them when omitted).
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SearchQuery {
+    text: String,
+    fields: Option<Vec<SearchField>>,
+    limit: Option<u32>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) enum SearchHit {
+    Hospital {
+        hospital: Hospital,
+        score: u32,
+        fields: Vec<SearchField>,
+    },
+    Patient {
+        patient: Patient,
+        score: u32,
+        fields: Vec<SearchField>,
+    },
+}
+
+// The best hits of a search, most relevant first, and the number of records matching it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct SearchResults {
+    hits: Vec<SearchHit>,
+    total: u64,
+}
+
+// A hospital or patient matching a search
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub(crate) enum Match {
+    Hospital(HospitalId),
+    Patient(PatientId),
+}
+
+#[derive(Default)]
+struct Relevance {
+    score: u32,
+    fields: BTreeSet<SearchField>,
+}
+
+// Lowercase words of a text with accents removed, e.g. "Hôpital Saint-Éloi" gives "hopital",
+// "saint" and "eloi"
+pub(crate) fn tokenize(text: &str) -> Vec<String> {
+    let folded: String = text
+        .nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .collect();
+    folded
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= MIN_TOKEN_LENGTH)
+        .map(|word| IndexText::cut(word.to_string()).as_str().to_string())
+        .collect()
+}
+
+type Postings = BTreeMap<(IndexText, Posting), u32>;
+
+// Index entries of the fields of a record, valued by how often the word appears in the field
+fn postings(id: u64, fields: &[(SearchField, &str)]) -> Postings {
+    let mut postings = Postings::new();
+    for (field, text) in fields {
+        for token in tokenize(text) {
+            *postings
+                .entry((IndexText::cut(token), Posting::new(*field, id)))
+                .or_default() += 1;
+        }
+    }
+    postings
+}
+
+pub(crate) fn hospital_postings(hospital: &Hospital) -> Postings {
+    postings(
+        hospital.id.0,
+        &[
+            (SearchField::HospitalName, &hospital.name),
+            (SearchField::HospitalCity, &hospital.city),
+            (SearchField::HospitalAddress, &hospital.address),
+        ],
+    )
+}
+
+pub(crate) fn patient_postings(patient: &Patient) -> Postings {
+    postings(
+        patient.id.0,
+        &[(SearchField::PatientDescription, &patient.description)],
+    )
+}
+
+// Replace the index entries of a record, only writing those that changed
+pub(crate) fn update_postings(previous: Postings, current: Postings) {
+    SEARCH_INDEX.with(|s| {
+        let mut index = s.borrow_mut();
+        for key in previous.keys() {
+            if !current.contains_key(key) {
+                index.remove(key);
+            }
+        }
+        for (key, count) in current {
+            if previous.get(&key) != Some(&count) {
+                index.insert(key, count);
+            }
+        }
+    });
+}
+
+// Index the words of a batch of stored hospitals
+pub(crate) fn index_hospital_words(after: Option<u64>, limit: usize) -> Option<u64> {
+    let (hospitals, next) = schema::batch(&HOSPITAL_STORAGE, after, limit);
+    for hospital in hospitals {
+        update_postings(Postings::new(), hospital_postings(&hospital));
+    }
+    next
+}
+
+// Index the words of a batch of stored patients
+pub(crate) fn index_patient_words(after: Option<u64>, limit: usize) -> Option<u64> {
+    let (patients, next) = schema::batch(&PATIENT_STORAGE, after, limit);
+    for patient in patients {
+        update_postings(Postings::new(), patient_postings(&patient));
+    }
+    next
+}
+
+// Records matching every word of `text` in one of `fields`, most relevant first. A word found
+// whole counts twice as much as a word it starts, and a name more than a city or an address.
+pub(crate) fn search_records(
+    text: &str,
+    fields: &[SearchField],
+) -> Result<Vec<(Match, u32, Vec<SearchField>)>, Error> {
+    let mut terms = tokenize(text);
+    terms.sort();
+    terms.dedup();
+    terms.truncate(MAX_QUERY_TOKENS);
+    if terms.is_empty() {
+        return Err(Error::InvalidPayload {
+            msg: format!(
+                "search for words of at least {} letters or digits",
+                MIN_TOKEN_LENGTH
+            ),
+        });
+    }
+
+    let mut matches: Option<BTreeMap<Match, Relevance>> = None;
+    for term in terms {
+        let mut found: BTreeMap<Match, Relevance> = BTreeMap::new();
+        let prefix = term.chars().count() >= MIN_PREFIX_LENGTH;
+        SEARCH_INDEX.with(|s| {
+            let start = (IndexText::cut(term.clone()), Posting::default());
+            for ((token, posting), count) in s
+                .borrow()
+                .range(start..)
+                .take_while(|((token, _), _)| match prefix {
+                    true => token.as_str().starts_with(&term),
+                    false => token.as_str() == term,
+                })
+                .take(MAX_TERM_POSTINGS)
+            {
+                let field = posting.field();
+                if !fields.contains(&field) {
+                    continue;
+                }
+                let exact = if token.as_str() == term { 2 } else { 1 };
+                let record = match field.is_hospital() {
+                    true => Match::Hospital(HospitalId(posting.id)),
+                    false => Match::Patient(PatientId(posting.id)),
+                };
+                let relevance = found.entry(record).or_default();
+                relevance.score += field.weight() * exact * count;
+                relevance.fields.insert(field);
+            }
+        });
+        // every word must match
+        matches = Some(match matches {
+            None => found,
+            Some(previous) => previous
+                .into_iter()
+                .filter_map(|(record, mut relevance)| {
+                    let more = found.remove(&record)?;
+                    relevance.score += more.score;
+                    relevance.fields.extend(more.fields);
+                    Some((record, relevance))
+                })
+                .collect(),
+        });
+    }
+
+    let mut ranked: Vec<(Match, u32, Vec<SearchField>)> = matches
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(record, relevance)| {
+            (
+                record,
+                relevance.score,
+                relevance.fields.into_iter().collect(),
+            )
+        })
+        .collect();
+    ranked.sort_by(|(a, a_score, _), (b, b_score, _)| b_score.cmp(a_score).then(a.cmp(b)));
+    Ok(ranked)
+}
+
+// search hospitals by name, city and address and patients by description
+#[ic_cdk::query]
+fn search(query: SearchQuery) -> Result<SearchResults, Error> {
+    schema::check_migrated()?;
+    let fields = match query.fields {
+        Some(fields) if !fields.is_empty() => fields,
+        _ => FIELDS.to_vec(),
+    };
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_HITS)
+        .clamp(1, MAX_SEARCH_HITS) as usize;
+    let ranked = search_records(&query.text, &fields)?;
+    let hits = ranked
+        .iter()
+        .filter_map(|(record, score, fields)| match record {
+            Match::Hospital(id) => HOSPITAL_STORAGE
+                .with(|s| s.borrow().get(id))
+                .map(|hospital| SearchHit::Hospital {
+                    hospital: Hospital {
+                        password: "******".to_string(),
+                        ..hospital
+                    },
+                    score: *score,
+                    fields: fields.clone(),
+                }),
+            Match::Patient(id) => {
+                PATIENT_STORAGE
+                    .with(|s| s.borrow().get(id))
+                    .map(|patient| SearchHit::Patient {
+                        patient: Patient {
+                            password: "******".to_string(),
+                            ..patient
+                        },
+                        score: *score,
+                        fields: fields.clone(),
+                    })
+            }
+        })
+        .take(limit)
+        .collect();
+    Ok(SearchResults {
+        hits,
+        total: ranked.len() as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexes;
+
+    #[test]
+    fn words_are_folded_and_split() {
+        assert_eq!(
+            tokenize("Hôpital Saint-Éloi, 3 rue Müller"),
+            ["hopital", "saint", "eloi", "rue", "muller"]
+        );
+    }
+
+    #[test]
+    fn hits_match_every_word_by_prefix_ranked_by_field() {
+        for (id, name, city) in [
+            (1, "Kenyatta National Hospital", "Nairobi"),
+            (2, "Nakuru Level 5", "Nakuru"),
+            (3, "Coast General", "Mombasa"),
+        ] {
+            indexes::store_hospital(Hospital {
+                id: HospitalId(id),
+                name: name.to_string(),
+                city: city.to_string(),
+                address: "Hospital Road".to_string(),
+                ..Default::default()
+            });
+        }
+        indexes::store_patient(Patient {
+            id: PatientId(1),
+            description: "Needs blood after surgery at the national hospital".to_string(),
+            ..Default::default()
+        });
+        let all = FIELDS.to_vec();
+        let records = |text: &str, fields: &[SearchField]| -> Vec<Match> {
+            search_records(text, fields)
+                .unwrap()
+                .into_iter()
+                .map(|(record, _, _)| record)
+                .collect()
+        };
+
+        // the hospital naming the word ranks above those with it in their address
+        let hits = records("hosp", &all);
+        assert_eq!(hits.len(), 4);
+        assert_eq!(hits[0], Match::Hospital(HospitalId(1)));
+        assert_eq!(
+            records("NATIONAL hospital", &all),
+            [Match::Hospital(HospitalId(1)), Match::Patient(PatientId(1))]
+        );
+        assert_eq!(
+            records("nak", &[SearchField::HospitalCity]),
+            [Match::Hospital(HospitalId(2))]
+        );
+        assert!(records("hospital", &[SearchField::HospitalName])
+            .iter()
+            .all(|record| *record == Match::Hospital(HospitalId(1))));
+
+        // renamed records are found by their new words only
+        indexes::store_hospital(Hospital {
+            id: HospitalId(3),
+            name: "Coast Provincial".to_string(),
+            city: "Mombasa".to_string(),
+            ..Default::default()
+        });
+        assert!(records("general", &all).is_empty());
+        assert_eq!(
+            records("provincial", &all),
+            [Match::Hospital(HospitalId(3))]
+        );
+        assert!(matches!(
+            search_records("a -", &all),
+            Err(Error::InvalidPayload { .. })
+        ));
+    }
+
+    #[test]
+    fn short_words_match_whole_and_reads_are_capped() {
+        for id in 1..=3 {
+            indexes::store_patient(Patient {
+                id: PatientId(id),
+                description: "an ab abc".to_string(),
+                ..Default::default()
+            });
+        }
+        indexes::store_patient(Patient {
+            id: PatientId(4),
+            description: "abcd".to_string(),
+            ..Default::default()
+        });
+        let records = |text: &str| -> Vec<Match> {
+            search_records(text, &FIELDS)
+                .unwrap()
+                .into_iter()
+                .map(|(record, _, _)| record)
+                .collect()
+        };
+        // a two letter word does not read every word it starts
+        assert_eq!(records("ab").len(), 3);
+        assert_eq!(records("abc").len(), 4);
+
+        for id in 5..=MAX_TERM_POSTINGS as u64 + 5 {
+            indexes::store_patient(Patient {
+                id: PatientId(id),
+                description: "abcde".to_string(),
+                ..Default::default()
+            });
+        }
+        // whole words are read before the words they start
+        let hits = records("abcd");
+        assert_eq!(hits.len(), MAX_TERM_POSTINGS);
+        assert_eq!(hits[0], Match::Patient(PatientId(4)));
+    }
+}
@@ -0,0 +1,611 @@
+use crate::ids::{DonorId, HospitalId, PatientId, PledgeId};
+use crate::inventory::UnitStatus;
+use crate::pagination::{self, ListOptions, Listed, Page};
+use crate::roles::{self, caller_is_authenticated, Role, RoleGrant, StorablePrincipal};
+use crate::{
+    caller_principal, Error, ACTIVITY_COUNTER, ACTIVITY_STORAGE, HOSPITAL_STORAGE, STAFF_STORAGE,
+};
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::{BoundedStorable, Storable};
+use std::borrow::Cow;
+
+// Actions a staff member can be allowed to perform on behalf of a hospital
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) enum StaffPermission {
+    RegisterPatients,
+    ConfirmDonations,
+    EditHospitalProfile,
+    ManageInventory,
+}
+
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) enum StaffStatus {
+    Invited,
+    Active,
+}
+
+// Membership of a principal in the staff of a hospital
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct StaffMember {
+    hospital_id: HospitalId,
+    principal: Principal,
+    status: StaffStatus,
+    permissions: Vec<StaffPermission>,
+    invited_by: Principal,
+    invited_at: u64,
+    joined_at: Option<u64>,
+}
+
+// Hospital-side mutations attributed to the staff member that performed them
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub(crate) enum StaffAction {
+    EditedProfile,
+    RegisteredPatient { patient_id: PatientId },
+    RecordedPledge { donor_id: DonorId },
+    InvitedStaff { principal: Principal },
+    JoinedStaff,
+    RemovedStaff { principal: Principal },
+    ChangedPermissions { principal: Principal },
+    ConfirmedDonation { pledge_id: PledgeId },
+    DeferredDonor { donor_id: DonorId },
+    ChangedUnit { unit_id: u64, status: UnitStatus },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct StaffActivity {
+    id: u64,
+    hospital_id: HospitalId,
+    actor: Principal,
+    action: StaffAction,
+    at: u64,
+}
+
+impl Listed for StaffActivity {
+    type Cursor = u64;
+    const NAME: &'static str = "activities";
+
+    fn cursor(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Storable for StaffMember {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for StaffActivity {
+    // Conversion to bytes
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    // Conversion from bytes
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for StaffMember {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl BoundedStorable for StaffActivity {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct StaffPayload {
+    hospital_id: HospitalId,
+    principal: Principal,
+    permissions: Vec<StaffPermission>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+pub(crate) struct RemoveStaffPayload {
+    hospital_id: HospitalId,
+    principal: Principal,
+}
+
+fn get_member(hospital_id: HospitalId, principal: Principal) -> Option<StaffMember> {
+    STAFF_STORAGE.with(|s| s.borrow().get(&(hospital_id, StorablePrincipal(principal))))
+}
+
+fn save_member(member: &StaffMember) {
+    STAFF_STORAGE.with(|s| {
+        s.borrow_mut().insert(
+            (member.hospital_id, StorablePrincipal(member.principal)),
+            member.clone(),
+        )
+    });
+}
+
+fn staff_of(hospital_id: HospitalId) -> Vec<StaffMember> {
+    // the management canister principal is empty and sorts before every other principal
+    let start = (
+        hospital_id,
+        StorablePrincipal(Principal::management_canister()),
+    );
+    STAFF_STORAGE.with(|s| {
+        s.borrow()
+            .range(start..)
+            .take_while(|((id, _), _)| *id == hospital_id)
+            .map(|(_, member)| member)
+            .collect()
+    })
+}
+
+// Check that the caller may act for a hospital with a permission,
+// hospital admins and super-admins hold every permission
+pub(crate) fn require_permission(
+    caller: &Principal,
+    hospital_id: HospitalId,
+    permission: StaffPermission,
+) -> Result<(), Error> {
+    if roles::require_role(caller, &[Role::HospitalAdmin], Some(hospital_id.0)).is_ok() {
+        return Ok(());
+    }
+    match get_member(hospital_id, *caller) {
+        Some(member)
+            if member.status == StaffStatus::Active && member.permissions.contains(&permission) =>
+        {
+            Ok(())
+        }
+        _ => Err(Error::Unauthorized {
+            msg: format!(
+                "Unauthorized, caller lacks the {:?} permission at hospital id: {}",
+                permission, hospital_id
+            ),
+        }),
+    }
+}
+
+// Permissions without repeats, in the order first given
+fn distinct(permissions: Vec<StaffPermission>) -> Vec<StaffPermission> {
+    let mut distinct = Vec::new();
+    for permission in permissions {
+        if !distinct.contains(&permission) {
+            distinct.push(permission);
+        }
+    }
+    distinct
+}
+
+// Attribute a hospital-side mutation to the principal that performed it
+pub(crate) fn record_activity(hospital_id: HospitalId, actor: Principal, action: StaffAction) {
+    let id = ACTIVITY_COUNTER
+        .with(|counter| {
+            let current_id = *counter.borrow().get();
+            counter.borrow_mut().set(current_id + 1)
+        })
+        .expect("Cannot increment Ids");
+    let activity = StaffActivity {
+        id,
+        hospital_id,
+        actor,
+        action,
+        at: crate::now(),
+    };
+    ACTIVITY_STORAGE.with(|s| s.borrow_mut().insert((hospital_id, id), activity));
+}
+
+// Create active memberships for staff roles granted before the membership table existed
+pub(crate) fn backfill_memberships() {
+    let staff: Vec<(Principal, HospitalId)> = crate::ROLE_STORAGE.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|((_, grant), _)| grant.role == Role::HospitalStaff)
+            .filter_map(|((principal, grant), _)| {
+                grant.scope_id.map(|id| (principal.0, HospitalId(id)))
+            })
+            .collect()
+    });
+    for (principal, hospital_id) in staff {
+        if get_member(hospital_id, principal).is_none() {
+            save_member(&StaffMember {
+                hospital_id,
+                principal,
+                status: StaffStatus::Active,
+                permissions: vec![
+                    StaffPermission::RegisterPatients,
+                    StaffPermission::ConfirmDonations,
+                    StaffPermission::EditHospitalProfile,
+                    StaffPermission::ManageInventory,
+                ],
+                invited_by: principal,
+                invited_at: crate::now(),
+                joined_at: Some(crate::now()),
+            });
+        }
+    }
+}
+
+// invite a principal to join the staff of a hospital with the given permissions
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn invite_staff(payload: StaffPayload) -> Result<StaffMember, Error> {
+    let caller = caller_principal()?;
+    invite(caller, payload)
+}
+
+fn invite(caller: Principal, payload: StaffPayload) -> Result<StaffMember, Error> {
+    if !HOSPITAL_STORAGE.with(|s| s.borrow().contains_key(&payload.hospital_id)) {
+        return Err(Error::NotFound {
+            msg: format!("hospital of id: {} not found", payload.hospital_id),
+        });
+    }
+    roles::require_role(&caller, &[Role::HospitalAdmin], Some(payload.hospital_id.0))?;
+    if payload.principal == Principal::anonymous() {
+        return Err(Error::InvalidPayload {
+            msg: "the anonymous principal cannot join a hospital staff".to_string(),
+        });
+    }
+    if let Some(member) = get_member(payload.hospital_id, payload.principal) {
+        if member.status == StaffStatus::Active {
+            return Err(Error::AlreadyInit {
+                msg: format!(
+                    "{} is already staff of hospital id: {}",
+                    payload.principal, payload.hospital_id
+                ),
+            });
+        }
+    }
+
+    let member = StaffMember {
+        hospital_id: payload.hospital_id,
+        principal: payload.principal,
+        status: StaffStatus::Invited,
+        permissions: distinct(payload.permissions),
+        invited_by: caller,
+        invited_at: crate::now(),
+        joined_at: None,
+    };
+    save_member(&member);
+    record_activity(
+        payload.hospital_id,
+        caller,
+        StaffAction::InvitedStaff {
+            principal: payload.principal,
+        },
+    );
+    Ok(member)
+}
+
+// accept a pending invitation to the staff of a hospital
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn accept_staff_invite(hospital_id: HospitalId) -> Result<StaffMember, Error> {
+    let caller = caller_principal()?;
+    accept_invite(caller, hospital_id)
+}
+
+fn accept_invite(caller: Principal, hospital_id: HospitalId) -> Result<StaffMember, Error> {
+    match get_member(hospital_id, caller) {
+        Some(member) if member.status == StaffStatus::Invited => {
+            let member = StaffMember {
+                status: StaffStatus::Active,
+                joined_at: Some(crate::now()),
+                ..member
+            };
+            save_member(&member);
+            roles::grant(
+                caller,
+                RoleGrant {
+                    role: Role::HospitalStaff,
+                    scope_id: Some(hospital_id.0),
+                },
+            );
+            record_activity(hospital_id, caller, StaffAction::JoinedStaff);
+            Ok(member)
+        }
+        Some(_) => Err(Error::AlreadyInit {
+            msg: format!("caller is already staff of hospital id: {}", hospital_id),
+        }),
+        None => Err(Error::NotFound {
+            msg: format!("no invitation for hospital id: {}", hospital_id),
+        }),
+    }
+}
+
+// change the permissions of a staff member
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn set_staff_permissions(payload: StaffPayload) -> Result<StaffMember, Error> {
+    let caller = caller_principal()?;
+    set_permissions(caller, payload)
+}
+
+fn set_permissions(caller: Principal, payload: StaffPayload) -> Result<StaffMember, Error> {
+    roles::require_role(&caller, &[Role::HospitalAdmin], Some(payload.hospital_id.0))?;
+    match get_member(payload.hospital_id, payload.principal) {
+        Some(member) => {
+            let member = StaffMember {
+                permissions: distinct(payload.permissions),
+                ..member
+            };
+            save_member(&member);
+            record_activity(
+                payload.hospital_id,
+                caller,
+                StaffAction::ChangedPermissions {
+                    principal: payload.principal,
+                },
+            );
+            Ok(member)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "{} is not staff of hospital id: {}",
+                payload.principal, payload.hospital_id
+            ),
+        }),
+    }
+}
+
+// remove a staff member or cancel an invitation, staff members can also remove themselves
+#[ic_cdk::update(guard = "caller_is_authenticated")]
+fn remove_staff(payload: RemoveStaffPayload) -> Result<StaffMember, Error> {
+    let caller = caller_principal()?;
+    remove(caller, payload)
+}
+
+fn remove(caller: Principal, payload: RemoveStaffPayload) -> Result<StaffMember, Error> {
+    if payload.principal != caller {
+        roles::require_role(&caller, &[Role::HospitalAdmin], Some(payload.hospital_id.0))?;
+    }
+    let key = (payload.hospital_id, StorablePrincipal(payload.principal));
+    match STAFF_STORAGE.with(|s| s.borrow_mut().remove(&key)) {
+        Some(member) => {
+            roles::revoke(
+                payload.principal,
+                RoleGrant {
+                    role: Role::HospitalStaff,
+                    scope_id: Some(payload.hospital_id.0),
+                },
+            );
+            record_activity(
+                payload.hospital_id,
+                caller,
+                StaffAction::RemovedStaff {
+                    principal: payload.principal,
+                },
+            );
+            Ok(member)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "{} is not staff of hospital id: {}",
+                payload.principal, payload.hospital_id
+            ),
+        }),
+    }
+}
+
+// list the staff and pending invitations of a hospital
+#[ic_cdk::query]
+fn list_hospital_staff(hospital_id: HospitalId) -> Result<Vec<StaffMember>, Error> {
+    let caller = ic_cdk::caller();
+    roles::require_role(
+        &caller,
+        &[Role::HospitalAdmin, Role::HospitalStaff],
+        Some(hospital_id.0),
+    )?;
+    Ok(staff_of(hospital_id))
+}
+
+// list a page of the mutations performed on behalf of a hospital and who performed them
+#[ic_cdk::query]
+fn get_hospital_activity(
+    hospital_id: HospitalId,
+    options: ListOptions<u64>,
+) -> Result<Page<StaffActivity, u64>, Error> {
+    activity_page(ic_cdk::caller(), hospital_id, &options)
+}
+
+fn activity_page(
+    caller: Principal,
+    hospital_id: HospitalId,
+    options: &ListOptions<u64>,
+) -> Result<Page<StaffActivity, u64>, Error> {
+    roles::require_role(
+        &caller,
+        &[Role::HospitalAdmin, Role::HospitalStaff],
+        Some(hospital_id.0),
+    )?;
+    ACTIVITY_STORAGE.with(|s| {
+        let activities = s.borrow();
+        pagination::paginate(
+            activities
+                .range((hospital_id, 0)..)
+                .take_while(|((id, _), _)| *id == hospital_id)
+                .map(|(_, activity)| activity),
+            options,
+            |id| activities.get(&(hospital_id, id)),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Hospital;
+
+    fn principal(n: u8) -> Principal {
+        Principal::from_slice(&[n; 10])
+    }
+
+    // hospital 1 with principal(1) as its admin
+    fn setup() -> (HospitalId, Principal) {
+        let (hospital_id, admin) = (HospitalId(1), principal(1));
+        HOSPITAL_STORAGE.with(|s| {
+            s.borrow_mut().insert(
+                hospital_id,
+                Hospital {
+                    id: hospital_id,
+                    verified_by: Some(principal(9)),
+                    ..Default::default()
+                },
+            )
+        });
+        roles::grant(
+            admin,
+            RoleGrant {
+                role: Role::HospitalAdmin,
+                scope_id: Some(hospital_id.0),
+            },
+        );
+        (hospital_id, admin)
+    }
+
+    fn staff(hospital_id: HospitalId, permissions: Vec<StaffPermission>) -> StaffPayload {
+        StaffPayload {
+            hospital_id,
+            principal: principal(2),
+            permissions,
+        }
+    }
+
+    #[test]
+    fn invited_staff_act_once_they_accept() {
+        let (hospital_id, admin) = setup();
+        let member = principal(2);
+        // only admins invite
+        assert!(invite(
+            principal(3),
+            staff(hospital_id, vec![StaffPermission::ConfirmDonations])
+        )
+        .is_err());
+        invite(
+            admin,
+            staff(hospital_id, vec![StaffPermission::ConfirmDonations]),
+        )
+        .unwrap();
+        assert!(
+            require_permission(&member, hospital_id, StaffPermission::ConfirmDonations).is_err()
+        );
+
+        accept_invite(member, hospital_id).unwrap();
+        assert!(
+            require_permission(&member, hospital_id, StaffPermission::ConfirmDonations).is_ok()
+        );
+        assert!(
+            require_permission(&member, hospital_id, StaffPermission::ManageInventory).is_err()
+        );
+        assert!(matches!(
+            accept_invite(member, hospital_id),
+            Err(Error::AlreadyInit { .. })
+        ));
+        assert!(matches!(
+            accept_invite(principal(3), hospital_id),
+            Err(Error::NotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn permission_changes_and_removal_take_effect() {
+        let (hospital_id, admin) = setup();
+        let member = principal(2);
+        invite(
+            admin,
+            staff(hospital_id, vec![StaffPermission::ConfirmDonations]),
+        )
+        .unwrap();
+        accept_invite(member, hospital_id).unwrap();
+
+        // staff cannot change their own permissions
+        assert!(set_permissions(
+            member,
+            staff(hospital_id, vec![StaffPermission::ManageInventory])
+        )
+        .is_err());
+        set_permissions(
+            admin,
+            staff(hospital_id, vec![StaffPermission::ManageInventory]),
+        )
+        .unwrap();
+        assert!(
+            require_permission(&member, hospital_id, StaffPermission::ConfirmDonations).is_err()
+        );
+        assert!(require_permission(&member, hospital_id, StaffPermission::ManageInventory).is_ok());
+
+        let removal = RemoveStaffPayload {
+            hospital_id,
+            principal: member,
+        };
+        assert!(remove(principal(3), removal.clone()).is_err());
+        remove(admin, removal.clone()).unwrap();
+        assert!(
+            require_permission(&member, hospital_id, StaffPermission::ManageInventory).is_err()
+        );
+        assert!(roles::require_role(&member, &[Role::HospitalStaff], Some(hospital_id.0)).is_err());
+        assert!(matches!(
+            remove(admin, removal),
+            Err(Error::NotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn activity_log_attributes_staff_changes() {
+        let (hospital_id, admin) = setup();
+        let member = principal(2);
+        invite(
+            admin,
+            staff(hospital_id, vec![StaffPermission::ConfirmDonations]),
+        )
+        .unwrap();
+        accept_invite(member, hospital_id).unwrap();
+        set_permissions(
+            admin,
+            staff(hospital_id, vec![StaffPermission::ManageInventory]),
+        )
+        .unwrap();
+        // staff members can leave on their own
+        remove(
+            member,
+            RemoveStaffPayload {
+                hospital_id,
+                principal: member,
+            },
+        )
+        .unwrap();
+
+        let page = activity_page(admin, hospital_id, &ListOptions::default()).unwrap();
+        let log: Vec<(Principal, StaffAction)> = page
+            .items
+            .into_iter()
+            .map(|activity| (activity.actor, activity.action))
+            .collect();
+        assert_eq!(
+            log,
+            [
+                (admin, StaffAction::InvitedStaff { principal: member }),
+                (member, StaffAction::JoinedStaff),
+                (admin, StaffAction::ChangedPermissions { principal: member }),
+                (member, StaffAction::RemovedStaff { principal: member }),
+            ]
+        );
+        // former staff no longer read the log
+        assert!(activity_page(member, hospital_id, &ListOptions::default()).is_err());
+    }
+
+    #[test]
+    fn repeated_permissions_are_kept_once() {
+        let permissions = distinct(vec![
+            StaffPermission::ManageInventory,
+            StaffPermission::RegisterPatients,
+            StaffPermission::ManageInventory,
+            StaffPermission::RegisterPatients,
+        ]);
+        assert_eq!(
+            permissions,
+            [
+                StaffPermission::ManageInventory,
+                StaffPermission::RegisterPatients
+            ]
+        );
+    }
+}